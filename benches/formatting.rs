@@ -0,0 +1,83 @@
+use std::net::UdpSocket;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use statsd::client::Pipeline;
+use statsd::Client;
+
+/// Formatting as done before metrics were written into reusable buffers,
+/// kept as a baseline for comparison.
+fn legacy_line(prefix: &str, metric: &str, value: f64) -> String {
+    let data = format!("{}:{}|c", metric, value);
+    if prefix.is_empty() {
+        data
+    } else {
+        format!("{}.{}", prefix, data)
+    }
+}
+
+fn bench_client(c: &mut Criterion) {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+    let tagged = client.with_tag("region", "eu").with_tag("shard", "3");
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let mut group = c.benchmark_group("client");
+    group.bench_function("legacy format", |b| {
+        b.iter(|| {
+            let data = legacy_line("myapp", black_box("some.counter"), black_box(12.5));
+            let _ = socket.send_to(data.as_bytes(), addr);
+        })
+    });
+    group.bench_function("count", |b| {
+        b.iter(|| client.count(black_box("some.counter"), black_box(12.5)))
+    });
+    group.bench_function("count with tags", |b| {
+        b.iter(|| tagged.count(black_box("some.counter"), black_box(12.5)))
+    });
+    group.bench_function("sampled count", |b| {
+        b.iter(|| client.sampled_count(black_box("some.counter"), black_box(12.5), 1.0))
+    });
+    group.finish();
+}
+
+fn bench_pipeline(c: &mut Criterion) {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let mut group = c.benchmark_group("pipeline");
+    group.bench_function("legacy format 100 metrics", |b| {
+        b.iter(|| {
+            let stats: Vec<String> = (0..100)
+                .map(|i| format!("{}:{}|g", black_box("some.gauge"), f64::from(i)))
+                .collect();
+            let mut data = String::new();
+            for stat in stats {
+                let stat = format!("myapp.{}", stat);
+                if data.len() + stat.len() + 1 > 512 {
+                    let _ = socket.send_to(data.clone().as_bytes(), addr);
+                    data.clear();
+                } else if !data.is_empty() {
+                    data += "\n";
+                }
+                data += &stat;
+            }
+            let _ = socket.send_to(data.as_bytes(), addr);
+        })
+    });
+    let mut pipeline = Pipeline::new();
+    group.bench_function("send 100 metrics", |b| {
+        b.iter(|| {
+            for i in 0..100 {
+                pipeline.gauge(black_box("some.gauge"), f64::from(i));
+            }
+            pipeline.send(&client);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_client, bench_pipeline);
+criterion_main!(benches);
@@ -0,0 +1,28 @@
+use std::net::UdpSocket;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use statsd::Client;
+
+fn bench_pipeline_send(c: &mut Criterion) {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+
+    let mut group = c.benchmark_group("pipeline send 1000 metrics");
+    for &batch_send in &[false, true] {
+        let name = if batch_send { "sendmmsg" } else { "send_to" };
+        let mut pipeline = client.pipeline();
+        pipeline.set_batch_send(batch_send);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for i in 0..1000 {
+                    pipeline.gauge(black_box("some.gauge"), f64::from(i));
+                }
+                pipeline.send(&client);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_pipeline_send);
+criterion_main!(benches);
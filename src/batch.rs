@@ -0,0 +1,144 @@
+use std::net::{SocketAddr, UdpSocket};
+
+/// Buffers used to send packets in batches, reused so sending doesn't
+/// allocate.
+#[derive(Default)]
+pub(crate) struct Batch {
+    #[cfg(target_os = "linux")]
+    iovecs: Vec<libc::iovec>,
+    #[cfg(target_os = "linux")]
+    headers: Vec<libc::mmsghdr>,
+}
+
+// SAFETY: the buffers hold pointers only while sending, and are emptied
+// before `send()` returns.
+unsafe impl Send for Batch {}
+unsafe impl Sync for Batch {}
+
+impl Batch {
+    /// Send packets stored back to back in `buf`, where `ends` holds the
+    /// end offset of each packet.
+    ///
+    /// On Linux all packets are submitted with a single `sendmmsg` call,
+    /// and any packets it could not send are sent one at a time. UDP GSO
+    /// is not used as it requires every packet but the last to have the
+    /// same size, which would mean padding packets.
+    pub fn send(&mut self, socket: &UdpSocket, addr: SocketAddr, buf: &[u8], ends: &[usize]) {
+        #[cfg(target_os = "linux")]
+        let sent = linux::sendmmsg(self, socket, addr, buf, ends);
+        #[cfg(not(target_os = "linux"))]
+        let sent = 0;
+
+        send_each(socket, addr, buf, ends, sent);
+    }
+}
+
+/// Send packets stored back to back in `buf` with one `send_to` call
+/// each, skipping the first `skip` packets.
+pub(crate) fn send_each(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    buf: &[u8],
+    ends: &[usize],
+    skip: usize,
+) {
+    let mut start = if skip == 0 { 0 } else { ends[skip - 1] };
+    for &end in &ends[skip..] {
+        let _ = socket.send_to(&buf[start..end], addr);
+        start = end;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::mem;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    use super::Batch;
+
+    /// Send packets with `sendmmsg`, returning how many were sent.
+    pub fn sendmmsg(
+        batch: &mut Batch,
+        socket: &UdpSocket,
+        addr: SocketAddr,
+        buf: &[u8],
+        ends: &[usize],
+    ) -> usize {
+        let (mut name, name_len) = sockaddr(addr);
+        let mut start = 0;
+        let iovecs = &mut batch.iovecs;
+        iovecs.extend(ends.iter().map(|&end| {
+            let iovec = libc::iovec {
+                iov_base: buf[start..].as_ptr() as *mut libc::c_void,
+                iov_len: end - start,
+            };
+            start = end;
+            iovec
+        }));
+        // The iovecs aren't moved once the headers point to them.
+        let headers = &mut batch.headers;
+        headers.extend(iovecs.iter_mut().map(|iovec| {
+            // SAFETY: both structs are plain C structs for which all
+            // zeroes is a valid value.
+            let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+            header.msg_hdr.msg_name = &mut name as *mut _ as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = name_len;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+            header
+        }));
+
+        let mut sent = 0;
+        while sent < headers.len() {
+            let remaining = &mut headers[sent..];
+            // SAFETY: the headers point to `name` and `iovecs`, which point
+            // into `buf`, all of which outlive this call.
+            let result = unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    remaining.as_mut_ptr(),
+                    remaining.len() as libc::c_uint,
+                    0,
+                )
+            };
+            if result <= 0 {
+                break;
+            }
+            sent += result as usize;
+        }
+        // Don't keep pointers to `name` and `buf` once they are gone.
+        headers.clear();
+        iovecs.clear();
+        sent
+    }
+
+    fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // SAFETY: `sockaddr_storage` is a plain C struct for which all
+        // zeroes is a valid value, and is large enough to hold any socket
+        // address.
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_scope_id = addr.scope_id();
+                unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+}
@@ -0,0 +1,77 @@
+//! Helpers shared by the `statsd-server` and `statsd-proxy` binaries.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Set by signal handlers to stop the process.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Parse a duration in seconds, or with a `ms`, `s` or `m` unit.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, scale) = if let Some(number) = value.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = value.strip_suffix('s') {
+        (number, 1.0)
+    } else if let Some(number) = value.strip_suffix('m') {
+        (number, 60.0)
+    } else {
+        (value, 1.0)
+    };
+    // Durations too large for a `Duration` or rounding down to zero are
+    // rejected, as a zero interval would flush in a busy loop.
+    let duration = number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|number| Duration::try_from_secs_f64(number * scale).ok());
+    match duration {
+        Some(duration) if !duration.is_zero() => Ok(duration),
+        _ => Err(format!("Invalid duration `{}`", value)),
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn stop(_: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+/// Stop on SIGINT and SIGTERM.
+#[cfg(unix)]
+pub fn handle_signals() {
+    let handler = stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is
+    // async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn handle_signals() {}
+
+/// Block until a signal handled by `handle_signals()` is received.
+pub fn wait_for_stop() {
+    while !STOP.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parsing_durations() {
+        assert_eq!(Ok(Duration::from_secs(10)), parse_duration("10"));
+        assert_eq!(Ok(Duration::from_secs(10)), parse_duration("10s"));
+        assert_eq!(Ok(Duration::from_millis(250)), parse_duration("250ms"));
+        assert_eq!(Ok(Duration::from_secs(90)), parse_duration("1.5m"));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("-1").is_err());
+        assert!(parse_duration("fast").is_err());
+        assert!(parse_duration("1e300").is_err());
+        assert!(parse_duration("1e-12").is_err());
+        assert!(parse_duration("inf").is_err());
+    }
+}
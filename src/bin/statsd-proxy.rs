@@ -0,0 +1,223 @@
+//! A statsd proxy relaying metrics to upstream servers.
+//!
+//! Run `statsd-proxy --help` for the options.
+mod common;
+
+use std::env;
+use std::process;
+use std::time::Duration;
+
+use common::{handle_signals, parse_duration, wait_for_stop};
+use statsd::server::{Proxy, Routing};
+use statsd::Client;
+
+const USAGE: &str = "\
+Usage: statsd-proxy --upstream <ADDRESS> [OPTIONS]
+
+Receive statsd metrics, and relay them to upstream servers.
+
+Options:
+      --upstream <ADDRESS>     Relay metrics to a statsd server
+      --udp <ADDRESS>          Listen on a UDP address [default: 127.0.0.1:8125]
+      --tcp <ADDRESS>          Listen on a TCP address
+      --unix <PATH>            Listen on a Unix datagram socket
+      --routing <ROUTING>      Relay each metric to every upstream with `broadcast`,
+                               or to one upstream with `hash` [default: broadcast]
+      --batch-size <BYTES>     Re-batch lines into packets of up to this size
+      --batch-interval <TIME>  Send re-batched lines at least this often, e.g.
+                               `100ms` [default: 100ms]
+      --rewrite <FROM=TO>      Replace the prefix of metric names
+      --tag <KEY:VALUE>        Add a tag to every metric
+      --allow <PATTERN>        Only relay metrics matching a pattern, where `*`
+                               matches any characters
+      --deny <PATTERN>         Drop metrics matching a pattern
+  -h, --help                   Print this help
+  -V, --version                Print the version
+
+Every option but `--routing`, `--batch-size` and `--batch-interval` can be
+repeated.";
+
+/// Parse command line arguments, without the program name, into a proxy.
+///
+/// `Ok(None)` is returned when the help or version was printed.
+fn proxy<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Proxy>, String> {
+    let mut proxy = Proxy::new();
+    let mut listening = false;
+    let mut batch_size = None;
+    let mut batch_interval = Duration::from_millis(100);
+    let bind_error = |address: &str, e| format!("Can't listen on {}: {}", address, e);
+    while let Some(arg) = args.next() {
+        let (name, value) = match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(None);
+            }
+            "-V" | "--version" => {
+                println!("statsd-proxy {}", env!("CARGO_PKG_VERSION"));
+                return Ok(None);
+            }
+            _ => match arg.strip_prefix("--") {
+                Some(option) => match option.split_once('=') {
+                    Some((name, value)) => (name, value.to_string()),
+                    None => {
+                        let value = args
+                            .next()
+                            .ok_or_else(|| format!("Missing value for `{}`", arg))?;
+                        (option, value)
+                    }
+                },
+                None => return Err(format!("Unexpected argument `{}`", arg)),
+            },
+        };
+        let value = value.as_str();
+        match name {
+            "upstream" => proxy.add_upstream(
+                Client::new(value, "").map_err(|e| format!("Invalid upstream {}: {}", value, e))?,
+            ),
+            "udp" => {
+                let bound = proxy.bind_udp(value).map_err(|e| bind_error(value, e))?;
+                eprintln!("Listening on udp://{}", bound);
+                listening = true;
+            }
+            "tcp" => {
+                let bound = proxy.bind_tcp(value).map_err(|e| bind_error(value, e))?;
+                eprintln!("Listening on tcp://{}", bound);
+                listening = true;
+            }
+            "unix" => {
+                bind_unix(&mut proxy, value).map_err(|e| bind_error(value, e))?;
+                eprintln!("Listening on unix://{}", value);
+                listening = true;
+            }
+            "routing" => proxy.set_routing(match value {
+                "broadcast" => Routing::Broadcast,
+                "hash" => Routing::ConsistentHash,
+                _ => return Err(format!("Invalid routing `{}`", value)),
+            }),
+            "batch-size" => match value.parse::<usize>() {
+                Ok(size) if size > 0 => batch_size = Some(size),
+                _ => return Err(format!("Invalid batch size `{}`", value)),
+            },
+            "batch-interval" => batch_interval = parse_duration(value)?,
+            "rewrite" => {
+                let (from, to) = value
+                    .split_once('=')
+                    .ok_or_else(|| format!("Invalid rewrite `{}`", value))?;
+                proxy
+                    .add_prefix_rewrite(from, to)
+                    .map_err(|e| format!("Invalid rewrite `{}`: {}", value, e))?;
+            }
+            "tag" => {
+                let (key, value) = value
+                    .split_once(':')
+                    .ok_or_else(|| format!("Invalid tag `{}`", value))?;
+                proxy
+                    .add_tag(key, value)
+                    .map_err(|e| format!("Invalid tag `{}:{}`: {}", key, value, e))?;
+            }
+            "allow" => proxy.allow(value),
+            "deny" => proxy.deny(value),
+            _ => return Err(format!("Unknown option `{}`", name)),
+        }
+    }
+    if !listening {
+        let bound = proxy
+            .bind_udp("127.0.0.1:8125")
+            .map_err(|e| bind_error("127.0.0.1:8125", e))?;
+        eprintln!("Listening on udp://{}", bound);
+    }
+    if let Some(batch_size) = batch_size {
+        proxy.set_batching(batch_size, batch_interval);
+    }
+    Ok(Some(proxy))
+}
+
+#[cfg(unix)]
+fn bind_unix(proxy: &mut Proxy, path: &str) -> std::io::Result<()> {
+    proxy.bind_unix(path)
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_: &mut Proxy, _: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix sockets are not supported on this platform",
+    ))
+}
+
+fn run() -> Result<(), String> {
+    let proxy = match proxy(env::args().skip(1))? {
+        Some(proxy) => proxy,
+        None => return Ok(()),
+    };
+    handle_signals();
+    let running = proxy
+        .start()
+        .map_err(|e| format!("Can't start the proxy: {}", e))?;
+    wait_for_stop();
+    eprintln!("Shutting down");
+    // Re-batched lines are sent before exiting.
+    running.shutdown();
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("statsd-proxy: {}", e);
+        process::exit(2);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Option<Proxy>, String> {
+        proxy(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parsing_args() {
+        let proxy = args(&[
+            "--udp=127.0.0.1:0",
+            "--upstream",
+            "127.0.0.1:8125",
+            "--routing=hash",
+            "--batch-size=1432",
+            "--rewrite=edge.=",
+            "--tag=dc:eu",
+            "--allow=myapp.*",
+        ])
+        .unwrap()
+        .unwrap();
+        assert!(proxy.start().is_ok());
+
+        assert!(args(&["--help"]).unwrap().is_none());
+        assert_eq!(
+            Some("Invalid routing `random`".to_string()),
+            args(&["--routing", "random"]).err()
+        );
+        assert_eq!(
+            Some("Invalid tag `dc`".to_string()),
+            args(&["--tag", "dc"]).err()
+        );
+        assert_eq!(
+            Some("Invalid tag `dc:eu|west`: Invalid characters in \"eu|west\"".to_string()),
+            args(&["--tag", "dc:eu|west"]).err()
+        );
+        assert_eq!(
+            Some("Invalid rewrite `edge.=a:1|c`: Invalid characters in \"a:1|c\"".to_string()),
+            args(&["--rewrite", "edge.=a:1|c"]).err()
+        );
+        assert_eq!(
+            Some("Missing value for `--upstream`".to_string()),
+            args(&["--upstream"]).err()
+        );
+    }
+
+    #[test]
+    fn test_requiring_upstreams() {
+        let proxy = args(&["--udp=127.0.0.1:0"]).unwrap().unwrap();
+        assert!(proxy.start().is_err());
+    }
+}
@@ -0,0 +1,645 @@
+//! A statsd daemon printing aggregated metrics.
+//!
+//! Run `statsd-server --help` for the options.
+mod common;
+
+use std::env;
+use std::fs;
+use std::process;
+use std::time::Duration;
+
+use common::{handle_signals, parse_duration, wait_for_stop};
+use statsd::server::{ConsoleBackend, GraphiteBackend, Retention, Server};
+#[cfg(feature = "prometheus")]
+use statsd::server::{Mapping, PrometheusBackend, TimerType};
+
+const USAGE: &str = "\
+Usage: statsd-server [OPTIONS]
+
+Receive statsd metrics, and print the aggregated metrics at every flush.
+
+Options:
+  -c, --config <FILE>          Read options from a file of `option = value` lines
+      --udp <ADDRESS>          Listen on a UDP address [default: 127.0.0.1:8125]
+      --tcp <ADDRESS>          Listen on a TCP address
+      --unix <PATH>            Listen on a Unix datagram socket
+      --admin <ADDRESS>        Serve the admin interface on a TCP address, e.g.
+                               `127.0.0.1:8126`
+      --flush-interval <TIME>  Flush interval, e.g. `10s` or `500ms` [default: 10s]
+      --percentiles <LIST>     Timer percentiles, e.g. `90,99`, or `-10` for the top
+                               10% [default: 90]
+      --histogram <[METRIC=]BINS>
+                               Count the values of timers whose name contains
+                               METRIC in bins, e.g. `api.=10,100,inf`
+      --sketch <COUNT>         Summarize timers with more values than this per
+                               flush in a sketch accurate to 1%
+      --counter-retention <RETENTION>
+                               How long idle counters are reported as zeros:
+                               `delete` them at once, `keep` them forever, or a
+                               number of flushes [default: delete]
+      --gauge-retention <RETENTION>
+                               How long idle gauges are reported [default: keep]
+      --set-retention <RETENTION>
+                               How long idle sets are reported [default: delete]
+      --timer-retention <RETENTION>
+                               How long idle timers are reported [default: delete]
+      --max-metrics <COUNT>    Drop new metrics beyond this many, counting them in
+                               `statsd.cardinality_overflow`
+      --internal-metrics <BOOL>
+                               Report the server's own metrics under `statsd.`
+                               [default: true]
+      --backend <NAME>         Backend to flush to, `stdout`, `graphite`, `prometheus`
+                               or `none` [default: stdout]
+      --graphite <ADDRESS>     Carbon address of the `graphite` backend
+                               [default: 127.0.0.1:2003]
+      --graphite-legacy-namespace <BOOL>
+                               Send counters under `stats.` and `stats_counts.`
+                               rather than `stats.counters.` [default: true]
+      --prometheus <ADDRESS>   Address serving `/metrics` for the `prometheus` backend
+                               [default: 127.0.0.1:9102]
+      --prometheus-mapping <RULE>
+                               Map names matching a glob, or a regex between
+                               slashes, to a metric name and labels, with a
+                               `PATTERN NAME [LABEL=VALUE...]` rule, e.g.
+                               `myapp.*.hits hits route=$1`
+      --prometheus-timer-type <TYPE>
+                               Expose timers as a `summary` or `histogram`
+                               [default: summary]
+      --prometheus-buckets <LIST>
+                               Histogram buckets
+                               [default: 5,10,25,50,100,250,500,1000,2500,5000,10000]
+  -h, --help                   Print this help
+  -V, --version                Print the version
+
+Options taking an address, path, name, rule or histogram can be repeated. Options given on the
+command line replace the same options from the configuration file.";
+
+/// Default histogram buckets, in milliseconds.
+#[cfg(feature = "prometheus")]
+const DEFAULT_BUCKETS: &[f64] = &[
+    5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+];
+
+/// Options, from the command line or a configuration file.
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    config: Option<String>,
+    udp: Option<Vec<String>>,
+    tcp: Option<Vec<String>>,
+    unix: Option<Vec<String>>,
+    admin: Option<Vec<String>>,
+    flush_interval: Option<Duration>,
+    percentiles: Option<Vec<f64>>,
+    histograms: Option<Vec<(String, Vec<f64>)>>,
+    sketch: Option<usize>,
+    counter_retention: Option<Retention>,
+    gauge_retention: Option<Retention>,
+    set_retention: Option<Retention>,
+    timer_retention: Option<Retention>,
+    max_metrics: Option<usize>,
+    internal_metrics: Option<bool>,
+    backends: Option<Vec<String>>,
+    graphite: Option<String>,
+    graphite_legacy_namespace: Option<bool>,
+    prometheus: Option<String>,
+    prometheus_mappings: Option<Vec<String>>,
+    prometheus_timer_type: Option<String>,
+    prometheus_buckets: Option<Vec<f64>>,
+}
+
+impl Options {
+    /// Set an option by its long name, without the leading `--`.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let push = |list: &mut Option<Vec<String>>| {
+            list.get_or_insert_with(Vec::new).push(value.to_string())
+        };
+        match name {
+            "config" => self.config = Some(value.to_string()),
+            "udp" => push(&mut self.udp),
+            "tcp" => push(&mut self.tcp),
+            "unix" => push(&mut self.unix),
+            "admin" => push(&mut self.admin),
+            "backend" => push(&mut self.backends),
+            "flush-interval" => self.flush_interval = Some(parse_duration(value)?),
+            "percentiles" => self.percentiles = Some(parse_percentiles(value)?),
+            "histogram" => {
+                let (metric, bins) = value.rsplit_once('=').unwrap_or(("", value));
+                self.histograms
+                    .get_or_insert_with(Vec::new)
+                    .push((metric.to_string(), parse_bins(bins)?));
+            }
+            "sketch" => match value.parse::<usize>() {
+                Ok(count) => self.sketch = Some(count),
+                _ => return Err(format!("Invalid value count `{}`", value)),
+            },
+            "counter-retention" => self.counter_retention = Some(parse_retention(value)?),
+            "gauge-retention" => self.gauge_retention = Some(parse_retention(value)?),
+            "set-retention" => self.set_retention = Some(parse_retention(value)?),
+            "timer-retention" => self.timer_retention = Some(parse_retention(value)?),
+            "max-metrics" => match value.parse::<usize>() {
+                Ok(count) => self.max_metrics = Some(count),
+                _ => return Err(format!("Invalid metric count `{}`", value)),
+            },
+            "internal-metrics" => self.internal_metrics = Some(parse_bool(value)?),
+            "graphite" => self.graphite = Some(value.to_string()),
+            "graphite-legacy-namespace" => {
+                self.graphite_legacy_namespace = Some(parse_bool(value)?)
+            }
+            "prometheus" => self.prometheus = Some(value.to_string()),
+            "prometheus-mapping" => push(&mut self.prometheus_mappings),
+            "prometheus-timer-type" => match value {
+                "summary" | "histogram" => self.prometheus_timer_type = Some(value.to_string()),
+                _ => return Err(format!("Invalid timer type `{}`", value)),
+            },
+            "prometheus-buckets" => self.prometheus_buckets = Some(parse_buckets(value)?),
+            _ => return Err(format!("Unknown option `{}`", name)),
+        }
+        Ok(())
+    }
+
+    /// Parse command line arguments, without the program name.
+    ///
+    /// `Ok(None)` is returned when the help or version was printed.
+    fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+        let mut options = Options::default();
+        while let Some(arg) = args.next() {
+            let name = match arg.as_str() {
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    return Ok(None);
+                }
+                "-V" | "--version" => {
+                    println!("statsd-server {}", env!("CARGO_PKG_VERSION"));
+                    return Ok(None);
+                }
+                "-c" => "config",
+                _ => match arg.strip_prefix("--") {
+                    Some(name) => name,
+                    None => return Err(format!("Unexpected argument `{}`", arg)),
+                },
+            };
+            match name.split_once('=') {
+                Some((name, value)) => options.set(name, value)?,
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("Missing value for `{}`", arg))?;
+                    options.set(name, &value)?;
+                }
+            }
+        }
+        Ok(Some(options))
+    }
+
+    /// Parse a configuration file of `option = value` lines. Empty lines
+    /// and lines starting with `#` are ignored.
+    fn from_config(config: &str) -> Result<Options, String> {
+        let mut options = Options::default();
+        for (number, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Line {}: expected `option = value`", number + 1))?;
+            let name = name.trim();
+            if name == "config" {
+                return Err(format!("Line {}: `config` can't be nested", number + 1));
+            }
+            options
+                .set(name, value.trim())
+                .map_err(|e| format!("Line {}: {}", number + 1, e))?;
+        }
+        Ok(options)
+    }
+
+    /// Replace options with those set in `overrides`.
+    fn merge(self, overrides: Options) -> Options {
+        Options {
+            config: overrides.config.or(self.config),
+            udp: overrides.udp.or(self.udp),
+            tcp: overrides.tcp.or(self.tcp),
+            unix: overrides.unix.or(self.unix),
+            admin: overrides.admin.or(self.admin),
+            flush_interval: overrides.flush_interval.or(self.flush_interval),
+            percentiles: overrides.percentiles.or(self.percentiles),
+            histograms: overrides.histograms.or(self.histograms),
+            sketch: overrides.sketch.or(self.sketch),
+            counter_retention: overrides.counter_retention.or(self.counter_retention),
+            gauge_retention: overrides.gauge_retention.or(self.gauge_retention),
+            set_retention: overrides.set_retention.or(self.set_retention),
+            timer_retention: overrides.timer_retention.or(self.timer_retention),
+            max_metrics: overrides.max_metrics.or(self.max_metrics),
+            internal_metrics: overrides.internal_metrics.or(self.internal_metrics),
+            backends: overrides.backends.or(self.backends),
+            graphite: overrides.graphite.or(self.graphite),
+            graphite_legacy_namespace: overrides
+                .graphite_legacy_namespace
+                .or(self.graphite_legacy_namespace),
+            prometheus: overrides.prometheus.or(self.prometheus),
+            prometheus_mappings: overrides.prometheus_mappings.or(self.prometheus_mappings),
+            prometheus_timer_type: overrides
+                .prometheus_timer_type
+                .or(self.prometheus_timer_type),
+            prometheus_buckets: overrides.prometheus_buckets.or(self.prometheus_buckets),
+        }
+    }
+
+    /// Create a server from the options.
+    fn server(mut self) -> Result<Server, String> {
+        let mut server = Server::new();
+        let bind_error = |address: &str, e| format!("Can't listen on {}: {}", address, e);
+        let (mut udp, tcp, unix) = (
+            self.udp.take().unwrap_or_default(),
+            self.tcp.take().unwrap_or_default(),
+            self.unix.take().unwrap_or_default(),
+        );
+        if udp.is_empty() && tcp.is_empty() && unix.is_empty() {
+            udp.push("127.0.0.1:8125".to_string());
+        }
+        for address in &udp {
+            let bound = server
+                .bind_udp(address.as_str())
+                .map_err(|e| bind_error(address, e))?;
+            eprintln!("Listening on udp://{}", bound);
+        }
+        for address in &tcp {
+            let bound = server
+                .bind_tcp(address.as_str())
+                .map_err(|e| bind_error(address, e))?;
+            eprintln!("Listening on tcp://{}", bound);
+        }
+        for path in &unix {
+            bind_unix(&mut server, path).map_err(|e| bind_error(path, e))?;
+            eprintln!("Listening on unix://{}", path);
+        }
+        for address in self.admin.take().unwrap_or_default() {
+            let bound = server
+                .bind_admin(address.as_str())
+                .map_err(|e| bind_error(&address, e))?;
+            eprintln!("Admin interface on tcp://{}", bound);
+        }
+        if let Some(flush_interval) = self.flush_interval {
+            server.set_flush_interval(flush_interval);
+        }
+        if let Some(ref percentiles) = self.percentiles {
+            server.set_percentiles(percentiles);
+        }
+        for (metric, bins) in self.histograms.take().unwrap_or_default() {
+            server.add_histogram(&metric, &bins);
+        }
+        if let Some(count) = self.sketch {
+            server.set_sketching(count, 0.01);
+        }
+        if let Some(retention) = self.counter_retention {
+            server.set_counter_retention(retention);
+        }
+        if let Some(retention) = self.gauge_retention {
+            server.set_gauge_retention(retention);
+        }
+        if let Some(retention) = self.set_retention {
+            server.set_set_retention(retention);
+        }
+        if let Some(retention) = self.timer_retention {
+            server.set_timer_retention(retention);
+        }
+        if let Some(max_metrics) = self.max_metrics {
+            server.set_max_metrics(max_metrics);
+        }
+        if let Some(internal_metrics) = self.internal_metrics {
+            server.set_internal_metrics(internal_metrics);
+        }
+        let backends = self.backends.take();
+        for backend in backends.unwrap_or_else(|| vec!["stdout".to_string()]) {
+            match backend.as_str() {
+                "stdout" => server.add_backend(ConsoleBackend::new()),
+                "graphite" => {
+                    let address = self.graphite.as_deref().unwrap_or("127.0.0.1:2003");
+                    let mut graphite = GraphiteBackend::new(address)
+                        .map_err(|e| format!("Invalid Graphite address {}: {}", address, e))?;
+                    if let Some(legacy_namespace) = self.graphite_legacy_namespace {
+                        graphite.set_legacy_namespace(legacy_namespace);
+                    }
+                    server.add_backend(graphite);
+                }
+                "prometheus" => self.add_prometheus(&mut server)?,
+                "none" => {}
+                _ => return Err(format!("Unknown backend `{}`", backend)),
+            }
+        }
+        Ok(server)
+    }
+
+    #[cfg(feature = "prometheus")]
+    fn add_prometheus(&self, server: &mut Server) -> Result<(), String> {
+        let mut prometheus = PrometheusBackend::new();
+        for rule in self.prometheus_mappings.iter().flatten() {
+            prometheus.add_mapping(parse_mapping(rule)?);
+        }
+        if self.prometheus_timer_type.as_deref() == Some("histogram") {
+            let buckets = self.prometheus_buckets.clone();
+            prometheus.set_timer_type(TimerType::Histogram(
+                buckets.unwrap_or_else(|| DEFAULT_BUCKETS.to_vec()),
+            ));
+        }
+        let address = self.prometheus.as_deref().unwrap_or("127.0.0.1:9102");
+        let bound = prometheus
+            .listen(address)
+            .map_err(|e| format!("Can't listen on {}: {}", address, e))?;
+        eprintln!("Serving Prometheus metrics on http://{}/metrics", bound);
+        server.add_backend(prometheus);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "prometheus"))]
+    fn add_prometheus(&self, _: &mut Server) -> Result<(), String> {
+        Err("The `prometheus` backend needs the `prometheus` feature".to_string())
+    }
+}
+
+/// Parse a `PATTERN NAME [LABEL=VALUE...]` mapping rule, where patterns
+/// between slashes are regexes.
+#[cfg(feature = "prometheus")]
+fn parse_mapping(rule: &str) -> Result<Mapping, String> {
+    let invalid = || format!("Invalid mapping `{}`", rule);
+    let mut parts = rule.split_whitespace();
+    let (pattern, name) = match (parts.next(), parts.next()) {
+        (Some(pattern), Some(name)) => (pattern, name),
+        _ => return Err(invalid()),
+    };
+    let regex = pattern
+        .strip_prefix('/')
+        .and_then(|pattern| pattern.strip_suffix('/'));
+    let mut mapping = match regex {
+        Some(regex) => Mapping::regex(regex, name).map_err(|e| format!("{}: {}", invalid(), e))?,
+        None => Mapping::glob(pattern, name),
+    };
+    for label in parts {
+        let (label, value) = label.split_once('=').ok_or_else(invalid)?;
+        mapping.add_label(label, value);
+    }
+    Ok(mapping)
+}
+
+#[cfg(unix)]
+fn bind_unix(server: &mut Server, path: &str) -> std::io::Result<()> {
+    server.bind_unix(path)
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_: &mut Server, _: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix sockets are not supported on this platform",
+    ))
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("Invalid boolean `{}`", value)),
+    }
+}
+
+/// Parse a comma separated list of histogram bucket bounds.
+fn parse_buckets(value: &str) -> Result<Vec<f64>, String> {
+    value
+        .split(',')
+        .map(|bound| match bound.trim().parse::<f64>() {
+            Ok(bound) if bound.is_finite() => Ok(bound),
+            _ => Err(format!("Invalid bucket `{}`", bound.trim())),
+        })
+        .collect()
+}
+
+/// Parse a retention, `delete`, `keep` or a number of flushes.
+fn parse_retention(value: &str) -> Result<Retention, String> {
+    match value {
+        "delete" => Ok(Retention::Delete),
+        "keep" => Ok(Retention::Keep),
+        _ => match value.parse::<u32>() {
+            Ok(flushes) => Ok(Retention::Flushes(flushes)),
+            Err(_) => Err(format!("Invalid retention `{}`", value)),
+        },
+    }
+}
+
+/// Parse a comma separated list of histogram bins, where `inf` is a bin of
+/// the values above the others.
+fn parse_bins(value: &str) -> Result<Vec<f64>, String> {
+    value
+        .split(',')
+        .map(|bin| match bin.trim().parse::<f64>() {
+            Ok(bin) if !bin.is_nan() && bin != f64::NEG_INFINITY => Ok(bin),
+            _ => Err(format!("Invalid bin `{}`", bin.trim())),
+        })
+        .collect()
+}
+
+/// Parse a comma separated list of percentiles in `[-100, 0)` or
+/// `(0, 100]`.
+fn parse_percentiles(value: &str) -> Result<Vec<f64>, String> {
+    value
+        .split(',')
+        .map(|percentile| match percentile.trim().parse::<f64>() {
+            Ok(percentile) if percentile != 0.0 && percentile.abs() <= 100.0 => Ok(percentile),
+            _ => Err(format!("Invalid percentile `{}`", percentile.trim())),
+        })
+        .collect()
+}
+
+fn run() -> Result<(), String> {
+    let cli = match Options::from_args(env::args().skip(1))? {
+        Some(options) => options,
+        None => return Ok(()),
+    };
+    let options = match cli.config {
+        Some(ref path) => {
+            let config = fs::read_to_string(path)
+                .map_err(|e| format!("Can't read the configuration file {}: {}", path, e))?;
+            Options::from_config(&config)?.merge(cli)
+        }
+        None => cli,
+    };
+    handle_signals();
+    let running = options
+        .server()?
+        .start()
+        .map_err(|e| format!("Can't start the server: {}", e))?;
+    wait_for_stop();
+    eprintln!("Shutting down");
+    // Metrics received since the last flush are flushed before exiting.
+    running.shutdown();
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("statsd-server: {}", e);
+        process::exit(2);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Option<Options>, String> {
+        Options::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parsing_args() {
+        let options = args(&[
+            "--udp",
+            "0.0.0.0:8125",
+            "--udp=[::1]:8125",
+            "--tcp",
+            "127.0.0.1:8126",
+            "--flush-interval",
+            "500ms",
+            "--percentiles=90,99.9,-10",
+            "--histogram=api.=10,100,inf",
+            "--histogram",
+            "5,50",
+            "--sketch=10000",
+            "--counter-retention=keep",
+            "--timer-retention=5",
+            "--max-metrics=100000",
+            "--internal-metrics=no",
+            "-c",
+            "statsd.conf",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            Options {
+                config: Some("statsd.conf".to_string()),
+                udp: Some(vec!["0.0.0.0:8125".to_string(), "[::1]:8125".to_string()]),
+                tcp: Some(vec!["127.0.0.1:8126".to_string()]),
+                flush_interval: Some(Duration::from_millis(500)),
+                percentiles: Some(vec![90.0, 99.9, -10.0]),
+                histograms: Some(vec![
+                    ("api.".to_string(), vec![10.0, 100.0, f64::INFINITY]),
+                    (String::new(), vec![5.0, 50.0]),
+                ]),
+                sketch: Some(10000),
+                counter_retention: Some(Retention::Keep),
+                timer_retention: Some(Retention::Flushes(5)),
+                max_metrics: Some(100000),
+                internal_metrics: Some(false),
+                ..Options::default()
+            },
+            options
+        );
+
+        assert_eq!(None, args(&["--help"]).unwrap());
+        assert_eq!(
+            Err("Unknown option `port`".to_string()),
+            args(&["--port", "1"])
+        );
+        assert_eq!(
+            Err("Missing value for `--udp`".to_string()),
+            args(&["--udp"])
+        );
+        assert_eq!(Err("Unexpected argument `udp`".to_string()), args(&["udp"]));
+        assert_eq!(
+            Err("Invalid bin `nan`".to_string()),
+            args(&["--histogram", "t=1,nan"])
+        );
+        assert_eq!(
+            Err("Invalid retention `forever`".to_string()),
+            args(&["--gauge-retention", "forever"])
+        );
+        assert_eq!(
+            Err("Invalid percentile `0`".to_string()),
+            args(&["--percentiles", "0"])
+        );
+    }
+
+    #[test]
+    fn test_parsing_config() {
+        let config = "\
+            # Local daemon\n\
+            udp = 127.0.0.1:8125\n\
+            \n\
+            flush-interval = 1m\n\
+            backend = none\n";
+        let file = Options::from_config(config).unwrap();
+        assert_eq!(Some(Duration::from_secs(60)), file.flush_interval);
+
+        let cli = args(&["--udp", "127.0.0.1:9125"]).unwrap().unwrap();
+        let options = file.merge(cli);
+        assert_eq!(Some(vec!["127.0.0.1:9125".to_string()]), options.udp);
+        assert_eq!(Some(Duration::from_secs(60)), options.flush_interval);
+        assert_eq!(Some(vec!["none".to_string()]), options.backends);
+
+        assert_eq!(
+            Err("Line 2: expected `option = value`".to_string()),
+            Options::from_config("udp = :8125\nudp")
+        );
+        assert_eq!(
+            Err("Line 1: Invalid percentile `101`".to_string()),
+            Options::from_config("percentiles = 90, 101")
+        );
+    }
+
+    #[test]
+    fn test_creating_servers() {
+        let options = args(&[
+            "--udp",
+            "127.0.0.1:0",
+            "--admin=127.0.0.1:0",
+            "--backend",
+            "none",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(Some(vec!["127.0.0.1:0".to_string()]), options.admin);
+        assert!(options.server().unwrap().start().is_ok());
+
+        let options = args(&[
+            "--udp=127.0.0.1:0",
+            "--backend=graphite",
+            "--graphite-legacy-namespace=no",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(Some(false), options.graphite_legacy_namespace);
+        assert!(options.server().is_ok());
+
+        let options = args(&["--udp=127.0.0.1:0", "--backend=carbon"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            Some("Unknown backend `carbon`".to_string()),
+            options.server().err()
+        );
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_prometheus_backend() {
+        let options = args(&[
+            "--udp=127.0.0.1:0",
+            "--backend=prometheus",
+            "--prometheus=127.0.0.1:0",
+            "--prometheus-mapping=myapp.*.hits hits route=$1",
+            "--prometheus-mapping=/^db\\.(.+)$/ db table=$1",
+            "--prometheus-timer-type=histogram",
+        ])
+        .unwrap()
+        .unwrap();
+        assert!(options.server().is_ok());
+
+        assert!(parse_mapping("myapp.*.hits").is_err());
+        assert!(parse_mapping("myapp.*.hits hits route").is_err());
+        assert!(parse_mapping("/(/ hits").is_err());
+        assert_eq!(
+            Err("Invalid timer type `gauge`".to_string()),
+            args(&["--prometheus-timer-type", "gauge"])
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Shortest interval between flushes, so a zero interval doesn't keep the
+/// flusher thread busy.
+const MIN_FLUSH_INTERVAL: Duration = Duration::from_millis(1);
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Shard tried first by the current thread, so threads spread over
+    /// the shards instead of competing for the same one.
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Buffer shared by the threads using a buffered `Client`.
+///
+/// The buffer is split in shards, each holding the metrics of a packet
+/// being built. A thread takes ownership of a shard by swapping its
+/// pointer out, appends to it, and puts it back. Threads never wait on
+/// each other: when a shard is in use, the next one is tried.
+pub(crate) struct SharedBuffer {
+    shards: Box<[Shard]>,
+    max_udp_size: usize,
+    socket: Arc<UdpSocket>,
+    server_address: SocketAddr,
+    /// Thread flushing the buffer periodically, woken up to stop.
+    flusher: OnceLock<JoinHandle<()>>,
+}
+
+impl SharedBuffer {
+    /// Create a buffer, and a background thread flushing it every
+    /// `interval`, or every millisecond for shorter intervals.
+    ///
+    /// The thread stops as soon as the buffer is dropped.
+    pub fn start(
+        socket: Arc<UdpSocket>,
+        server_address: SocketAddr,
+        max_udp_size: usize,
+        interval: Duration,
+    ) -> Arc<SharedBuffer> {
+        let shards = thread::available_parallelism().map_or(4, |n| n.get() * 2);
+        let buffer = Arc::new(SharedBuffer {
+            shards: (0..shards).map(|_| Shard::new()).collect(),
+            max_udp_size,
+            socket,
+            server_address,
+            flusher: OnceLock::new(),
+        });
+        let interval = interval.max(MIN_FLUSH_INTERVAL);
+        let weak: Weak<SharedBuffer> = Arc::downgrade(&buffer);
+        let flusher = thread::Builder::new()
+            .name("statsd-flusher".to_string())
+            .spawn(move || {
+                let mut next = Instant::now() + interval;
+                loop {
+                    // Unparked early when the buffer is dropped.
+                    thread::park_timeout(next.saturating_duration_since(Instant::now()));
+                    let buffer = match weak.upgrade() {
+                        Some(buffer) => buffer,
+                        None => break,
+                    };
+                    let now = Instant::now();
+                    if now >= next {
+                        buffer.flush();
+                        next += interval;
+                        if next < now {
+                            // Skip the flushes missed while suspended.
+                            next = now + interval;
+                        }
+                    }
+                }
+            });
+        if let Ok(flusher) = flusher {
+            let _ = buffer.flusher.set(flusher);
+        }
+        buffer
+    }
+
+    /// Append a metric line, sending the packet it was added to when full.
+    pub fn push(&self, line: &[u8]) {
+        let first = SHARD.with(|shard| *shard);
+        for i in 0..self.shards.len() {
+            let shard = &self.shards[(first + i) % self.shards.len()];
+            if let Some(mut packet) = shard.take() {
+                let data = &mut packet.data;
+                if !data.is_empty() {
+                    if data.len() + line.len() + 1 > self.max_udp_size {
+                        self.send(data);
+                        data.clear();
+                    } else {
+                        data.push(b'\n');
+                    }
+                }
+                data.extend_from_slice(line);
+                shard.put(packet);
+                return;
+            }
+        }
+        // Every shard is in use, send the line right away instead of waiting.
+        self.send(line);
+    }
+
+    /// Send the metrics buffered in every shard not currently in use.
+    pub fn flush(&self) {
+        for shard in self.shards.iter() {
+            if let Some(mut packet) = shard.take() {
+                if !packet.data.is_empty() {
+                    self.send(&packet.data);
+                    packet.data.clear();
+                }
+                shard.put(packet);
+            }
+        }
+    }
+
+    fn send(&self, data: &[u8]) {
+        let _ = self.socket.send_to(data, self.server_address);
+    }
+}
+
+impl Drop for SharedBuffer {
+    fn drop(&mut self) {
+        self.flush();
+        if let Some(flusher) = self.flusher.take() {
+            flusher.thread().unpark();
+            // The flusher drops the buffer itself when it holds the last
+            // reference while flushing.
+            if flusher.thread().id() != thread::current().id() {
+                let _ = flusher.join();
+            }
+        }
+    }
+}
+
+/// A packet being built, owned by whichever thread swapped it out.
+struct Shard {
+    packet: AtomicPtr<Packet>,
+}
+
+#[derive(Default)]
+struct Packet {
+    data: Vec<u8>,
+}
+
+impl Shard {
+    fn new() -> Shard {
+        Shard {
+            packet: AtomicPtr::new(Box::into_raw(Box::default())),
+        }
+    }
+
+    /// Take the packet, unless another thread has it.
+    fn take(&self) -> Option<Box<Packet>> {
+        let packet = self.packet.swap(ptr::null_mut(), Ordering::Acquire);
+        if packet.is_null() {
+            None
+        } else {
+            // SAFETY: the pointer was created by `Box::into_raw()`, and
+            // swapping it out gives this thread sole ownership.
+            Some(unsafe { Box::from_raw(packet) })
+        }
+    }
+
+    /// Put back a packet returned by `take()`.
+    fn put(&self, packet: Box<Packet>) {
+        self.packet.store(Box::into_raw(packet), Ordering::Release);
+    }
+}
+
+impl Drop for Shard {
+    fn drop(&mut self) {
+        drop(self.take());
+    }
+}
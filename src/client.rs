@@ -1,15 +1,33 @@
-use std::collections::VecDeque;
+use std::cell::RefCell;
 use std::error;
 use std::fmt;
 use std::io::Error;
+use std::mem;
 use std::net::AddrParseError;
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time;
 
+use crate::batch;
+use crate::buffer::SharedBuffer;
+use crate::format::{self, Delta, Float, Scope};
+use crate::testing::Sink;
+use crate::validation::{NamePolicy, NonFinitePolicy};
+
+thread_local! {
+    /// Buffer used to format metrics sent without a pipeline.
+    static BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(512));
+}
+
 #[derive(Debug)]
 pub enum StatsdError {
     IoError(Error),
     AddrParseError(String),
+    InvalidMetric(String),
+    InvalidValue(String),
+    GlobalClientSet,
 }
 
 impl From<AddrParseError> for StatsdError {
@@ -29,6 +47,9 @@ impl fmt::Display for StatsdError {
         match *self {
             StatsdError::IoError(ref e) => write!(f, "{}", e),
             StatsdError::AddrParseError(ref e) => write!(f, "{}", e),
+            StatsdError::InvalidMetric(ref e) => write!(f, "{}", e),
+            StatsdError::InvalidValue(ref e) => write!(f, "{}", e),
+            StatsdError::GlobalClientSet => write!(f, "Global client is already set"),
         }
     }
 }
@@ -50,10 +71,26 @@ impl error::Error for StatsdError {}
 /// let client = Client::new("127.0.0.1:8125", "myapp");
 /// client.incr("some.metric.completed");
 /// ```
+///
+/// Cloning a client is cheap, as clones share the same socket.
+#[derive(Clone)]
 pub struct Client {
-    socket: UdpSocket,
-    server_address: SocketAddr,
+    destination: Destination,
     prefix: String,
+    tags: Vec<(String, String)>,
+    scope: Option<Scope>,
+    policy: NamePolicy,
+    value_policy: NonFinitePolicy,
+    dropped: Arc<AtomicU64>,
+    buffer: Option<Arc<SharedBuffer>>,
+}
+
+/// Where a client sends its metrics.
+#[derive(Clone)]
+enum Destination {
+    Udp(Arc<UdpSocket>, SocketAddr),
+    /// Metrics recorded in memory by a `testing::Recording`.
+    Sink(Arc<Sink>),
 }
 
 impl Client {
@@ -71,11 +108,123 @@ impl Client {
         } else {
             UdpSocket::bind("[::]:0")?
         };
-        Ok(Client {
-            socket,
+        let destination = Destination::Udp(Arc::new(socket), server_address);
+        Ok(Client::with_destination(destination, prefix))
+    }
+
+    /// Construct a client recording metrics in `sink` instead of sending them.
+    pub(crate) fn recording(prefix: &str, sink: Arc<Sink>) -> Client {
+        Client::with_destination(Destination::Sink(sink), prefix)
+    }
+
+    fn with_destination(destination: Destination, prefix: &str) -> Client {
+        let policy = NamePolicy::default();
+        Client {
+            destination,
             prefix: prefix.to_string(),
-            server_address,
-        })
+            tags: Vec::new(),
+            scope: Scope::render(prefix, &[], policy).ok(),
+            policy,
+            value_policy: NonFinitePolicy::default(),
+            dropped: Arc::new(AtomicU64::new(0)),
+            buffer: None,
+        }
+    }
+
+    /// Get a child client with `scope` appended to the prefix.
+    ///
+    /// The child client shares the socket of its parent, so scoping
+    /// is cheap and can be done for each subsystem of an application.
+    ///
+    /// ```ignore
+    /// let client = Client::new("127.0.0.1:8125", "myapp").unwrap();
+    /// let db = client.scoped("db");
+    /// // Sends `myapp.db.query:1|c`
+    /// db.incr("query");
+    /// ```
+    pub fn scoped(&self, scope: &str) -> Client {
+        let prefix = if self.prefix.is_empty() {
+            scope.to_string()
+        } else {
+            format!("{}.{}", self.prefix, scope)
+        };
+        self.child(prefix, self.tags.clone())
+    }
+
+    /// Get a child client that adds a constant tag to every metric.
+    ///
+    /// Tags are sent in the DogStatsD `|#key:value` format. The child
+    /// client shares the socket of its parent.
+    ///
+    /// ```ignore
+    /// let client = Client::new("127.0.0.1:8125", "myapp").unwrap();
+    /// let tagged = client.scoped("db").with_tag("shard", "3");
+    /// // Sends `myapp.db.query:1|c|#shard:3`
+    /// tagged.incr("query");
+    /// ```
+    pub fn with_tag(&self, key: &str, value: &str) -> Client {
+        let mut tags = self.tags.clone();
+        tags.push((key.to_string(), value.to_string()));
+        self.child(self.prefix.clone(), tags)
+    }
+
+    fn child(&self, prefix: String, tags: Vec<(String, String)>) -> Client {
+        Client {
+            destination: self.destination.clone(),
+            scope: Scope::render(&prefix, &tags, self.policy).ok(),
+            prefix,
+            tags,
+            policy: self.policy,
+            value_policy: self.value_policy,
+            dropped: Arc::clone(&self.dropped),
+            buffer: self.buffer.clone(),
+        }
+    }
+
+    /// Get a client that batches metrics into packets before sending them.
+    ///
+    /// Metrics recorded from any thread are added to a shared buffer, and
+    /// sent once a packet reaches `max_udp_size` bytes. A background thread
+    /// sends partially filled packets every `flush_interval`, or every
+    /// millisecond for shorter intervals. Threads don't
+    /// wait on each other while recording metrics, so a buffered client
+    /// can be shared by a thread pool instead of using a `Pipeline` behind
+    /// a `Mutex`.
+    ///
+    /// Remaining metrics are sent when the buffered client, and every
+    /// child client created from it, has been dropped.
+    ///
+    /// ```ignore
+    /// use std::time::Duration;
+    ///
+    /// let client = Client::new("127.0.0.1:8125", "myapp").unwrap();
+    /// let buffered = Arc::new(client.buffered(512, Duration::from_millis(100)));
+    /// for _ in 0..4 {
+    ///     let buffered = Arc::clone(&buffered);
+    ///     thread::spawn(move || buffered.incr("jobs.completed"));
+    /// }
+    /// ```
+    pub fn buffered(&self, max_udp_size: usize, flush_interval: time::Duration) -> Client {
+        let mut client = self.child(self.prefix.clone(), self.tags.clone());
+        // Recording clients record metrics right away.
+        if let Destination::Udp(ref socket, server_address) = self.destination {
+            client.buffer = Some(SharedBuffer::start(
+                Arc::clone(socket),
+                server_address,
+                max_udp_size,
+                flush_interval,
+            ));
+        }
+        client
+    }
+
+    /// Send metrics buffered by a client created with `buffered()`.
+    ///
+    /// This does nothing for clients without a buffer.
+    pub fn flush(&self) {
+        if let Some(ref buffer) = self.buffer {
+            buffer.flush();
+        }
     }
 
     /// Increment a metric by 1
@@ -114,8 +263,7 @@ impl Client {
     /// client.count("metric.completed", 12.0);
     /// ```
     pub fn count(&self, metric: &str, value: f64) {
-        let data = self.prepare(format!("{}:{}|c", metric, value));
-        self.send(data);
+        self.send_float(metric, value, "c", None);
     }
 
     /// Modify a counter by `value` only x% of the time.
@@ -132,8 +280,7 @@ impl Client {
         if rand::random::<f64>() >= rate {
             return;
         }
-        let data = self.prepare(format!("{}:{}|c|@{}", metric, value, rate));
-        self.send(data);
+        self.send_float(metric, value, "c", Some(rate));
     }
 
     /// Set a gauge value.
@@ -143,8 +290,43 @@ impl Client {
     /// client.gauge("power_level.observed", 9001.0);
     /// ```
     pub fn gauge(&self, metric: &str, value: f64) {
-        let data = self.prepare(format!("{}:{}|g", metric, value));
-        self.send(data);
+        self.send_float(metric, value, "g", None);
+    }
+
+    /// Change a gauge by `delta`, sent as `+delta` or `-delta`.
+    ///
+    /// Servers add changes to the current value of the gauge, so changes
+    /// sent from several threads don't override each other.
+    ///
+    /// ```ignore
+    /// client.gauge_delta("queue.length", -2.0);
+    /// ```
+    pub fn gauge_delta(&self, metric: &str, delta: f64) {
+        match self.value_policy.apply(delta) {
+            Ok(delta) => {
+                let _ = self.try_send_metric(metric, Delta(delta), "g", None);
+            }
+            Err(_) => self.record_dropped(1),
+        }
+    }
+
+    /// Modify a counter by an integer `value`.
+    ///
+    /// ```ignore
+    /// // Decrement by 3
+    /// client.count_i64("metric.completed", -3);
+    /// ```
+    pub fn count_i64(&self, metric: &str, value: i64) {
+        self.send_metric(metric, value, "c", None);
+    }
+
+    /// Set a gauge to an integer value.
+    ///
+    /// ```ignore
+    /// client.gauge_u64("queue.length", 42);
+    /// ```
+    pub fn gauge_u64(&self, metric: &str, value: u64) {
+        self.send_metric(metric, value, "g", None);
     }
 
     /// Send a timer value.
@@ -156,8 +338,7 @@ impl Client {
     /// client.timer("response.duration", 10.123);
     /// ```
     pub fn timer(&self, metric: &str, value: f64) {
-        let data = self.prepare(format!("{}:{}|ms", metric, value));
-        self.send(data);
+        self.send_float(metric, value, "ms", None);
     }
 
     /// Time a block of code.
@@ -177,23 +358,236 @@ impl Client {
     {
         let start = time::Instant::now();
         let return_val = callable();
-        let used = start.elapsed();
-        let data = self.prepare(format!("{}:{}|ms", metric, used.as_millis()));
-        self.send(data);
+        self.timer_duration(metric, start.elapsed());
         return_val
     }
 
-    fn prepare<T: AsRef<str>>(&self, data: T) -> String {
-        if self.prefix.is_empty() {
-            data.as_ref().to_string()
-        } else {
-            format!("{}.{}", self.prefix, data.as_ref())
+    /// Send a timer value from a `Duration`.
+    ///
+    /// The duration is sent in whole ms.
+    ///
+    /// ```ignore
+    /// let started = std::time::Instant::now();
+    /// // Your code here.
+    /// client.timer_duration("response.duration", started.elapsed());
+    /// ```
+    pub fn timer_duration(&self, metric: &str, duration: time::Duration) {
+        self.send_metric(metric, duration.as_millis(), "ms", None);
+    }
+
+    /// Start a timer that is sent when it goes out of scope.
+    ///
+    /// ```ignore
+    /// {
+    ///   let _timer = client.start_timer("response.duration");
+    ///   // Your code here.
+    /// } // The timer is sent here.
+    /// ```
+    pub fn start_timer(&self, metric: &str) -> ClientTimer<'_> {
+        ClientTimer {
+            client: self,
+            metric: metric.to_string(),
+            start: time::Instant::now(),
         }
     }
 
-    /// Send data along the UDP socket.
-    fn send(&self, data: String) {
-        let _ = self.socket.send_to(data.as_bytes(), self.server_address);
+    /// Time a block of code that returns a `Result`.
+    ///
+    /// The passed closure will be timed and executed. The block's
+    /// duration will be sent as `<metric>.ok` when the closure
+    /// succeeds, and `<metric>.err` when it fails.
+    ///
+    /// ```ignore
+    /// let rows = client.time_result("db.query", || {
+    ///   db.query("SELECT 1")
+    /// });
+    /// ```
+    pub fn time_result<F, T, E>(&self, metric: &str, callable: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        let start = time::Instant::now();
+        let return_val = callable();
+        let metric = format!("{}.{}", metric, result_suffix(&return_val));
+        self.timer_duration(&metric, start.elapsed());
+        return_val
+    }
+
+    /// Time a block of code that returns a `Result` and count its failures.
+    ///
+    /// Behaves like `time_result()`, and additionally increments the
+    /// `<metric>.errors` counter when the closure fails.
+    ///
+    /// ```ignore
+    /// let rows = client.time_result_counted("db.query", || {
+    ///   db.query("SELECT 1")
+    /// });
+    /// ```
+    pub fn time_result_counted<F, T, E>(&self, metric: &str, callable: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        let return_val = self.time_result(metric, callable);
+        if return_val.is_err() {
+            self.incr(&format!("{}.errors", metric));
+        }
+        return_val
+    }
+
+    /// Set the policy applied to the prefix, metric names and tags.
+    ///
+    /// The default policy sends names unchanged. Child clients inherit
+    /// the policy of their parent.
+    ///
+    /// ```ignore
+    /// use statsd::validation::NamePolicy;
+    ///
+    /// client.set_name_policy(NamePolicy::Reject);
+    /// // Not sent, as `:` is not allowed in a name.
+    /// client.incr("bad:name");
+    /// ```
+    pub fn set_name_policy(&mut self, policy: NamePolicy) {
+        self.policy = policy;
+        self.scope = Scope::render(&self.prefix, &self.tags, policy).ok();
+    }
+
+    /// Set the policy applied to `NaN` and infinite values.
+    ///
+    /// The default policy drops these values. Child clients inherit
+    /// the policy of their parent.
+    ///
+    /// ```ignore
+    /// use statsd::validation::NonFinitePolicy;
+    ///
+    /// client.set_non_finite_policy(NonFinitePolicy::Clamp);
+    /// // Sent as `f64::MAX`
+    /// client.gauge("metric", f64::INFINITY);
+    /// ```
+    pub fn set_non_finite_policy(&mut self, policy: NonFinitePolicy) {
+        self.value_policy = policy;
+    }
+
+    /// Get the number of metrics dropped because of an invalid name or value.
+    ///
+    /// The number is shared with child clients, and includes metrics
+    /// dropped by pipelines sent through this client.
+    pub fn dropped_metrics(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Format and send a float metric, unless its value is rejected.
+    fn send_float(&self, metric: &str, value: f64, kind: &str, rate: Option<f64>) {
+        let _ = self.try_send_float(metric, value, kind, rate);
+    }
+
+    fn try_send_float(
+        &self,
+        metric: &str,
+        value: f64,
+        kind: &str,
+        rate: Option<f64>,
+    ) -> Result<(), StatsdError> {
+        match self.value_policy.apply(value) {
+            Ok(value) => self.try_send_metric(metric, Float(value), kind, rate),
+            Err(e) => {
+                self.record_dropped(1);
+                Err(e)
+            }
+        }
+    }
+
+    /// Format and send a metric, unless its name is rejected.
+    fn send_metric<V: fmt::Display>(&self, metric: &str, value: V, kind: &str, rate: Option<f64>) {
+        let _ = self.try_send_metric(metric, value, kind, rate);
+    }
+
+    /// Format and send a metric, returning why it was dropped if it was.
+    fn try_send_metric<V: fmt::Display>(
+        &self,
+        metric: &str,
+        value: V,
+        kind: &str,
+        rate: Option<f64>,
+    ) -> Result<(), StatsdError> {
+        self.try_send_tagged(metric, value, kind, rate, &[])
+    }
+
+    /// Send a float metric with tags added to those of the client, for the
+    /// `statsd_*!` macros and the integrations of this crate.
+    #[doc(hidden)]
+    pub fn send_tagged(&self, metric: &str, value: f64, kind: &str, tags: &[(&str, &str)]) {
+        match self.value_policy.apply(value) {
+            Ok(value) => {
+                let _ = self.try_send_tagged(metric, Float(value), kind, None, tags);
+            }
+            Err(_) => self.record_dropped(1),
+        }
+    }
+
+    /// Format and send a metric with tags added to those of the client.
+    ///
+    /// The metric is formatted into a buffer that is reused by every
+    /// client on the current thread, so no allocation is needed.
+    fn try_send_tagged<V: fmt::Display>(
+        &self,
+        metric: &str,
+        value: V,
+        kind: &str,
+        rate: Option<f64>,
+        tags: &[(&str, &str)],
+    ) -> Result<(), StatsdError> {
+        let scope = match self.scope {
+            Some(ref scope) => scope,
+            None => {
+                self.record_dropped(1);
+                return Err(StatsdError::InvalidMetric(
+                    "Invalid characters in the prefix or tags".to_string(),
+                ));
+            }
+        };
+        BUFFER.with(|buf| {
+            let mut buf = buf.borrow_mut();
+            buf.clear();
+            buf.extend_from_slice(&scope.prefix);
+            let written = format::write_metric(&mut buf, self.policy, metric, value, kind, rate)
+                .and_then(|()| {
+                    buf.extend_from_slice(&scope.tags);
+                    format::write_tags(&mut buf, self.policy, !scope.tags.is_empty(), tags)
+                });
+            match written {
+                Ok(()) => {
+                    self.send(&buf);
+                    Ok(())
+                }
+                Err(e) => {
+                    self.record_dropped(1);
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    fn record_dropped(&self, count: u64) {
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Address metrics are sent to, or `None` for recording clients.
+    pub(crate) fn server_address(&self) -> Option<SocketAddr> {
+        match self.destination {
+            Destination::Udp(_, server_address) => Some(server_address),
+            Destination::Sink(_) => None,
+        }
+    }
+
+    /// Send data along the UDP socket, or add it to the buffer.
+    pub(crate) fn send(&self, data: &[u8]) {
+        match (&self.destination, &self.buffer) {
+            (Destination::Sink(sink), _) => sink.push(data),
+            (_, Some(buffer)) => buffer.push(data),
+            (Destination::Udp(socket, server_address), None) => {
+                let _ = socket.send_to(data, *server_address);
+            }
+        }
     }
 
     /// Get a pipeline struct that allows optimizes the number of UDP
@@ -206,7 +600,10 @@ impl Client {
     /// pipeline.send(&mut client);
     /// ```
     pub fn pipeline(&self) -> Pipeline {
-        Pipeline::new()
+        let mut pipeline = Pipeline::new();
+        pipeline.set_name_policy(self.policy);
+        pipeline.set_non_finite_policy(self.value_policy);
+        pipeline
     }
 
     /// Send a histogram value.
@@ -216,8 +613,20 @@ impl Client {
     /// client.histogram("response.size", 128.0);
     /// ```
     pub fn histogram(&self, metric: &str, value: f64) {
-        let data = self.prepare(format!("{}:{}|h", metric, value));
-        self.send(data);
+        self.send_float(metric, value, "h", None);
+    }
+
+    /// Send a distribution value.
+    ///
+    /// Distributions are a DogStatsD extension, aggregated by the
+    /// server across every host sending them.
+    ///
+    /// ```ignore
+    /// // pass response size value
+    /// client.distribution("response.size", 128.0);
+    /// ```
+    pub fn distribution(&self, metric: &str, value: f64) {
+        self.send_float(metric, value, "d", None);
     }
 
     /// Send a key/value
@@ -226,21 +635,136 @@ impl Client {
     /// client.kv("key", 1.);
     /// ```
     pub fn kv(&self, metric: &str, value: f64) {
-        let data = self.prepare(format!("{}:{}|kv", metric, value));
-        self.send(data);
+        self.send_float(metric, value, "kv", None);
+    }
+
+    /// Modify a counter by `value`, returning an error when the metric is
+    /// dropped because of its name or value.
+    ///
+    /// Names are only rejected with `NamePolicy::Reject`, and values with
+    /// `NonFinitePolicy::Drop` or `NonFinitePolicy::Clamp`. Like every
+    /// dropped metric, the metric is counted by `dropped_metrics()`.
+    ///
+    /// ```ignore
+    /// use statsd::validation::NamePolicy;
+    ///
+    /// client.set_name_policy(NamePolicy::Reject);
+    /// assert!(client.try_count("bad:name", 1.0).is_err());
+    /// ```
+    pub fn try_count(&self, metric: &str, value: f64) -> Result<(), StatsdError> {
+        self.try_send_float(metric, value, "c", None)
+    }
+
+    /// Set a gauge value, returning an error when the metric is dropped.
+    /// See `try_count()`.
+    pub fn try_gauge(&self, metric: &str, value: f64) -> Result<(), StatsdError> {
+        self.try_send_float(metric, value, "g", None)
+    }
+
+    /// Send a timer value, returning an error when the metric is dropped.
+    /// See `try_count()`.
+    pub fn try_timer(&self, metric: &str, value: f64) -> Result<(), StatsdError> {
+        self.try_send_float(metric, value, "ms", None)
+    }
+
+    /// Send a histogram value, returning an error when the metric is
+    /// dropped. See `try_count()`.
+    pub fn try_histogram(&self, metric: &str, value: f64) -> Result<(), StatsdError> {
+        self.try_send_float(metric, value, "h", None)
+    }
+
+    /// Send a distribution value, returning an error when the metric is
+    /// dropped. See `try_count()`.
+    pub fn try_distribution(&self, metric: &str, value: f64) -> Result<(), StatsdError> {
+        self.try_send_float(metric, value, "d", None)
+    }
+
+    /// Send a key/value, returning an error when the metric is dropped.
+    /// See `try_count()`.
+    pub fn try_kv(&self, metric: &str, value: f64) -> Result<(), StatsdError> {
+        self.try_send_float(metric, value, "kv", None)
     }
 }
 
 pub struct Pipeline {
-    stats: VecDeque<String>,
+    /// Metric lines without prefix and tags, stored back to back.
+    stats: Vec<u8>,
+    /// End offset of each line in `stats`.
+    lines: Vec<usize>,
+    /// Packets being built when sending, stored back to back.
+    packets: Vec<u8>,
+    /// End offset of each packet in `packets`.
+    packet_ends: Vec<usize>,
     max_udp_size: usize,
+    batch_send: bool,
+    /// Buffers reused to send packets with a single system call.
+    batch: batch::Batch,
+    policy: NamePolicy,
+    value_policy: NonFinitePolicy,
+    dropped: u64,
 }
 
 impl Pipeline {
     pub fn new() -> Pipeline {
         Pipeline {
-            stats: VecDeque::new(),
+            stats: Vec::new(),
+            lines: Vec::new(),
+            packets: Vec::new(),
+            packet_ends: Vec::new(),
             max_udp_size: 512,
+            batch_send: false,
+            batch: batch::Batch::default(),
+            policy: NamePolicy::default(),
+            value_policy: NonFinitePolicy::default(),
+            dropped: 0,
+        }
+    }
+
+    /// Set the policy applied to metric names
+    ///
+    /// ```
+    /// use statsd::client::Pipeline;
+    /// use statsd::validation::NamePolicy;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// pipe.set_name_policy(NamePolicy::Reject);
+    /// ```
+    pub fn set_name_policy(&mut self, policy: NamePolicy) {
+        self.policy = policy;
+    }
+
+    /// Set the policy applied to `NaN` and infinite values
+    ///
+    /// ```
+    /// use statsd::client::Pipeline;
+    /// use statsd::validation::NonFinitePolicy;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// pipe.set_non_finite_policy(NonFinitePolicy::Clamp);
+    /// ```
+    pub fn set_non_finite_policy(&mut self, policy: NonFinitePolicy) {
+        self.value_policy = policy;
+    }
+
+    /// Format and queue a float metric, unless its value is rejected.
+    fn push_float(&mut self, metric: &str, value: f64, kind: &str, rate: Option<f64>) {
+        match self.value_policy.apply(value) {
+            Ok(value) => self.push_metric(metric, Float(value), kind, rate),
+            Err(_) => self.dropped += 1,
+        }
+    }
+
+    /// Format and queue a metric, unless its name is rejected.
+    fn push_metric<V: fmt::Display>(
+        &mut self,
+        metric: &str,
+        value: V,
+        kind: &str,
+        rate: Option<f64>,
+    ) {
+        match format::write_metric(&mut self.stats, self.policy, metric, value, kind, rate) {
+            Ok(()) => self.lines.push(self.stats.len()),
+            Err(_) => self.dropped += 1,
         }
     }
 
@@ -256,6 +780,21 @@ impl Pipeline {
         self.max_udp_size = max_udp_size;
     }
 
+    /// Send all packets of the pipeline with a single system call
+    ///
+    /// On Linux this uses `sendmmsg`, elsewhere packets are
+    /// still sent one at a time.
+    ///
+    /// ```
+    /// use statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// pipe.set_batch_send(true);
+    /// ```
+    pub fn set_batch_send(&mut self, batch_send: bool) {
+        self.batch_send = batch_send;
+    }
+
     /// Increment a metric by 1
     ///
     /// ```
@@ -301,8 +840,7 @@ impl Pipeline {
     /// pipe.count("metric.completed", 12.0);
     /// ```
     pub fn count(&mut self, metric: &str, value: f64) {
-        let data = format!("{}:{}|c", metric, value);
-        self.stats.push_back(data);
+        self.push_float(metric, value, "c", None);
     }
 
     /// Modify a counter by `value` only x% of the time.
@@ -321,8 +859,7 @@ impl Pipeline {
         if rand::random::<f64>() >= rate {
             return;
         }
-        let data = format!("{}:{}|c|@{}", metric, value, rate);
-        self.stats.push_back(data);
+        self.push_float(metric, value, "c", Some(rate));
     }
 
     /// Set a gauge value.
@@ -335,8 +872,32 @@ impl Pipeline {
     /// pipe.gauge("power_level.observed", 9001.0);
     /// ```
     pub fn gauge(&mut self, metric: &str, value: f64) {
-        let data = format!("{}:{}|g", metric, value);
-        self.stats.push_back(data);
+        self.push_float(metric, value, "g", None);
+    }
+
+    /// Modify a counter by an integer `value`.
+    ///
+    /// ```
+    /// use statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // Decrement by 3
+    /// pipe.count_i64("metric.completed", -3);
+    /// ```
+    pub fn count_i64(&mut self, metric: &str, value: i64) {
+        self.push_metric(metric, value, "c", None);
+    }
+
+    /// Set a gauge to an integer value.
+    ///
+    /// ```
+    /// use statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// pipe.gauge_u64("queue.length", 42);
+    /// ```
+    pub fn gauge_u64(&mut self, metric: &str, value: u64) {
+        self.push_metric(metric, value, "g", None);
     }
 
     /// Send a timer value.
@@ -351,8 +912,7 @@ impl Pipeline {
     /// pipe.timer("response.duration", 10.123);
     /// ```
     pub fn timer(&mut self, metric: &str, value: f64) {
-        let data = format!("{}:{}|ms", metric, value);
-        self.stats.push_back(data);
+        self.push_float(metric, value, "ms", None);
     }
 
     /// Time a block of code.
@@ -365,81 +925,311 @@ impl Pipeline {
     ///
     /// let mut pipe = Pipeline::new();
     /// // pass a duration value
-    /// pipe.time("response.duration", || {
+    /// let answer = pipe.time("response.duration", || {
     ///   // Your code here.
+    ///   42
     /// });
+    /// assert_eq!(answer, 42);
     /// ```
-    pub fn time<F>(&mut self, metric: &str, callable: F)
+    pub fn time<F, R>(&mut self, metric: &str, callable: F) -> R
     where
-        F: FnOnce(),
+        F: FnOnce() -> R,
     {
         let start = time::Instant::now();
-        callable();
-        let used = start.elapsed();
-        let data = format!("{}:{}|ms", metric, used.as_millis());
-        self.stats.push_back(data);
+        let return_val = callable();
+        self.timer_duration(metric, start.elapsed());
+        return_val
     }
 
-    /// Send a histogram value.
+    /// Send a timer value from a `Duration`.
+    ///
+    /// The duration is sent in whole ms.
     ///
     /// ```
     /// use statsd::client::Pipeline;
+    /// use std::time::Duration;
     ///
     /// let mut pipe = Pipeline::new();
-    /// // pass response size value
-    /// pipe.histogram("response.size", 128.0);
+    /// pipe.timer_duration("response.duration", Duration::from_millis(12));
     /// ```
-    pub fn histogram(&mut self, metric: &str, value: f64) {
-        let data = format!("{}:{}|h", metric, value);
-        self.stats.push_back(data);
+    pub fn timer_duration(&mut self, metric: &str, duration: time::Duration) {
+        self.push_metric(metric, duration.as_millis(), "ms", None);
     }
 
-    /// Send a key/value.
+    /// Start a timer that is recorded when it goes out of scope.
+    ///
+    /// The returned guard dereferences to the pipeline, so metrics
+    /// can still be recorded while the timer is running.
     ///
     /// ```
     /// use statsd::client::Pipeline;
     ///
     /// let mut pipe = Pipeline::new();
-    /// // pass response size value
-    /// pipe.kv("response.size", 256.);
+    /// {
+    ///     let mut timer = pipe.start_timer("response.duration");
+    ///     timer.incr("response.started");
+    /// } // The timer is recorded here.
     /// ```
-    pub fn kv(&mut self, metric: &str, value: f64) {
-        let data = format!("{}:{}|kv", metric, value);
-        self.stats.push_back(data);
-    }
-
-    /// Send data along the UDP socket.
-    pub fn send(&mut self, client: &Client) {
-        let mut _data = String::new();
-        if let Some(data) = self.stats.pop_front() {
-            _data += client.prepare(&data).as_ref();
-            while !self.stats.is_empty() {
-                let stat = client.prepare(self.stats.pop_front().unwrap());
-                if _data.len() + stat.len() + 1 > self.max_udp_size {
-                    client.send(_data.clone());
-                    _data.clear();
-                    _data += &stat;
-                } else {
-                    _data += "\n";
-                    _data += &stat;
-                }
-            }
-        }
-        if !_data.is_empty() {
-            client.send(_data);
+    pub fn start_timer(&mut self, metric: &str) -> PipelineTimer<'_> {
+        PipelineTimer {
+            pipeline: self,
+            metric: metric.to_string(),
+            start: time::Instant::now(),
         }
     }
-}
 
-impl Default for Pipeline {
-    fn default() -> Self {
-        Self::new()
+    /// Time a block of code that returns a `Result`.
+    ///
+    /// The block's duration will be recorded as `<metric>.ok` when the
+    /// closure succeeds, and `<metric>.err` when it fails.
+    ///
+    /// ```
+    /// use statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// let parsed = pipe.time_result("parse.duration", || "42".parse::<u32>());
+    /// assert_eq!(parsed, Ok(42));
+    /// ```
+    pub fn time_result<F, T, E>(&mut self, metric: &str, callable: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        let start = time::Instant::now();
+        let return_val = callable();
+        let metric = format!("{}.{}", metric, result_suffix(&return_val));
+        self.timer_duration(&metric, start.elapsed());
+        return_val
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
+
+    /// Time a block of code that returns a `Result` and count its failures.
+    ///
+    /// Behaves like `time_result()`, and additionally increments the
+    /// `<metric>.errors` counter when the closure fails.
+    ///
+    /// ```
+    /// use statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// let parsed = pipe.time_result_counted("parse.duration", || "nope".parse::<u32>());
+    /// assert!(parsed.is_err());
+    /// ```
+    pub fn time_result_counted<F, T, E>(&mut self, metric: &str, callable: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        let return_val = self.time_result(metric, callable);
+        if return_val.is_err() {
+            self.incr(&format!("{}.errors", metric));
+        }
+        return_val
+    }
+
+    /// Send a histogram value.
+    ///
+    /// ```
+    /// use statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // pass response size value
+    /// pipe.histogram("response.size", 128.0);
+    /// ```
+    pub fn histogram(&mut self, metric: &str, value: f64) {
+        self.push_float(metric, value, "h", None);
+    }
+
+    /// Send a distribution value.
+    ///
+    /// ```
+    /// use statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // pass response size value
+    /// pipe.distribution("response.size", 128.0);
+    /// ```
+    pub fn distribution(&mut self, metric: &str, value: f64) {
+        self.push_float(metric, value, "d", None);
+    }
+
+    /// Send a key/value.
+    ///
+    /// ```
+    /// use statsd::client::Pipeline;
+    ///
+    /// let mut pipe = Pipeline::new();
+    /// // pass response size value
+    /// pipe.kv("response.size", 256.);
+    /// ```
+    pub fn kv(&mut self, metric: &str, value: f64) {
+        self.push_float(metric, value, "kv", None);
+    }
+
+    /// Send data along the UDP socket.
+    ///
+    /// With a client created by `Client::buffered()`, the metrics are
+    /// added to the buffer of the client instead, and packed into packets
+    /// of the size of the buffer.
+    pub fn send(&mut self, client: &Client) {
+        client.record_dropped(mem::take(&mut self.dropped));
+        let scope = match client.scope {
+            Some(ref scope) => scope,
+            None => {
+                client.record_dropped(self.lines.len() as u64);
+                self.clear();
+                return;
+            }
+        };
+        if let Some(ref buffer) = client.buffer {
+            let mut start = 0;
+            for &end in &self.lines {
+                // The packets buffer holds one line at a time.
+                self.packets.clear();
+                scope.write_line(&self.stats[start..end], &mut self.packets);
+                buffer.push(&self.packets);
+                start = end;
+            }
+            self.clear();
+            return;
+        }
+        let mut start = 0;
+        let mut packet_start = 0;
+        for &end in &self.lines {
+            let line = &self.stats[start..end];
+            start = end;
+            let packet_len = self.packets.len() - packet_start;
+            if packet_len > 0 {
+                if packet_len + scope.line_len(line) + 1 > self.max_udp_size {
+                    packet_start = self.packets.len();
+                    self.packet_ends.push(packet_start);
+                } else {
+                    self.packets.push(b'\n');
+                }
+            }
+            scope.write_line(line, &mut self.packets);
+        }
+        if self.packets.len() > packet_start {
+            self.packet_ends.push(self.packets.len());
+        }
+        match client.destination {
+            Destination::Sink(ref sink) => {
+                let mut start = 0;
+                for &end in &self.packet_ends {
+                    sink.push(&self.packets[start..end]);
+                    start = end;
+                }
+            }
+            Destination::Udp(ref socket, addr) if self.batch_send => {
+                self.batch
+                    .send(socket, addr, &self.packets, &self.packet_ends);
+            }
+            Destination::Udp(ref socket, addr) => {
+                batch::send_each(socket, addr, &self.packets, &self.packet_ends, 0);
+            }
+        }
+        self.clear();
+    }
+
+    /// Remove queued metrics, keeping buffers for reuse.
+    fn clear(&mut self) {
+        self.stats.clear();
+        self.lines.clear();
+        self.packets.clear();
+        self.packet_ends.clear();
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Timer started by `Client::start_timer()`.
+///
+/// The elapsed time is sent to the client when the timer is dropped.
+pub struct ClientTimer<'a> {
+    client: &'a Client,
+    metric: String,
+    start: time::Instant,
+}
+
+impl<'a> Drop for ClientTimer<'a> {
+    fn drop(&mut self) {
+        self.client
+            .timer_duration(&self.metric, self.start.elapsed());
+    }
+}
+
+/// Timer started by the `statsd_time!` macro, sent with tags when dropped.
+#[doc(hidden)]
+pub struct TaggedTimer<'a> {
+    client: &'a Client,
+    metric: &'a str,
+    tags: &'a [(&'a str, &'a str)],
+    start: time::Instant,
+}
+
+impl<'a> TaggedTimer<'a> {
+    pub fn start(client: &'a Client, metric: &'a str, tags: &'a [(&'a str, &'a str)]) -> Self {
+        TaggedTimer {
+            client,
+            metric,
+            tags,
+            start: time::Instant::now(),
+        }
+    }
+}
+
+impl<'a> Drop for TaggedTimer<'a> {
+    fn drop(&mut self) {
+        let millis = self.start.elapsed().as_millis();
+        let _ = self
+            .client
+            .try_send_tagged(self.metric, millis, "ms", None, self.tags);
+    }
+}
+
+/// Timer started by `Pipeline::start_timer()`.
+///
+/// The elapsed time is recorded in the pipeline when the timer is dropped.
+pub struct PipelineTimer<'a> {
+    pipeline: &'a mut Pipeline,
+    metric: String,
+    start: time::Instant,
+}
+
+impl<'a> Deref for PipelineTimer<'a> {
+    type Target = Pipeline;
+
+    fn deref(&self) -> &Pipeline {
+        self.pipeline
+    }
+}
+
+impl<'a> DerefMut for PipelineTimer<'a> {
+    fn deref_mut(&mut self) -> &mut Pipeline {
+        self.pipeline
+    }
+}
+
+impl<'a> Drop for PipelineTimer<'a> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        self.pipeline.timer_duration(&self.metric, elapsed);
+    }
+}
+
+/// Metric name suffix used to split timings by outcome.
+fn result_suffix<T, E>(result: &Result<T, E>) -> &'static str {
+    if result.is_ok() {
+        "ok"
+    } else {
+        "err"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
     use std::net::{SocketAddr, UdpSocket};
     use std::sync::{
@@ -529,6 +1319,20 @@ mod test {
         assert_eq!("metric:9.1|g", response);
     }
 
+    #[test]
+    fn test_sending_gauge_delta() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving_all(|| {
+            client.gauge_delta("metric", 2.5);
+            client.gauge_delta("metric", -1e-7);
+        });
+        assert_eq!(
+            vec!["myapp.metric:+2.5|g", "myapp.metric:-1e-7|g"],
+            response
+        );
+    }
+
     #[test]
     fn test_sending_incr() {
         let server = Server::new();
@@ -582,6 +1386,47 @@ mod test {
         assert!(response.contains("|ms"));
     }
 
+    #[test]
+    fn test_sending_timed_result_ok() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            let output: Result<u8, ()> = client.time_result_counted("query", || Ok(7));
+            assert_eq!(output, Ok(7));
+        });
+        assert_eq!("myapp.query.ok:0|ms", response);
+    }
+
+    #[test]
+    fn test_sending_timed_result_err() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            let output: Result<(), &str> = client.time_result_counted("query", || Err("boom"));
+            assert_eq!(output, Err("boom"));
+        });
+        assert_eq!("myapp.query.err:0|ms\nmyapp.query.errors:1|c", response);
+    }
+
+    #[test]
+    fn test_sending_timer_duration() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server
+            .run_while_receiving(|| client.timer_duration("metric", Duration::from_micros(21_390)));
+        assert_eq!("myapp.metric:21|ms", response);
+    }
+
+    #[test]
+    fn test_sending_scoped_timer() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            let _timer = client.start_timer("scope");
+        });
+        assert_eq!("myapp.scope:0|ms", response);
+    }
+
     #[test]
     fn test_sending_histogram() {
         let server = Server::new();
@@ -590,6 +1435,14 @@ mod test {
         assert_eq!("myapp.metric:9.1|h", response);
     }
 
+    #[test]
+    fn test_sending_distribution() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| client.distribution("metric", 9.1));
+        assert_eq!("myapp.metric:9.1|d", response);
+    }
+
     #[test]
     fn test_sending_kv() {
         let server = Server::new();
@@ -616,6 +1469,311 @@ mod test {
         assert_eq!("myapp.metric:9.1|g\nmyapp.time_block:0|ms", response);
     }
 
+    #[test]
+    fn test_scoped_client() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            let db = client.scoped("db");
+            db.incr("query");
+            db.scoped("pool").gauge("size", 4.0);
+        });
+        assert_eq!("myapp.db.query:1|c\nmyapp.db.pool.size:4|g", response);
+    }
+
+    #[test]
+    fn test_scoped_client_without_prefix() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "").unwrap();
+        let response = server.run_while_receiving(|| client.scoped("db").incr("query"));
+        assert_eq!("db.query:1|c", response);
+    }
+
+    #[test]
+    fn test_tagged_client() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            let tagged = client.with_tag("region", "eu").scoped("db");
+            tagged
+                .with_tag("shard", "3")
+                .sampled_count("query", 1.0, 1.0);
+            tagged.timer("latency", 2.5);
+        });
+        assert_eq!(
+            "myapp.db.query:1|c|@1|#region:eu,shard:3\nmyapp.db.latency:2.5|ms|#region:eu",
+            response
+        );
+    }
+
+    #[test]
+    fn test_pipeline_sending_to_tagged_client() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            let tagged = client.scoped("cache").with_tag("tier", "l1");
+            let mut pipeline = tagged.pipeline();
+            pipeline.incr("hit");
+            pipeline.incr("miss");
+            pipeline.send(&tagged);
+        });
+        assert_eq!(
+            "myapp.cache.hit:1|c|#tier:l1\nmyapp.cache.miss:1|c|#tier:l1",
+            response
+        );
+    }
+
+    #[test]
+    fn test_sanitizing_names() {
+        let server = Server::new();
+        let mut client = Client::new(server.addr(), "my app").unwrap();
+        client.set_name_policy(NamePolicy::Sanitize);
+        let response = server.run_while_receiving(|| {
+            client
+                .scoped("d|b")
+                .with_tag("ho st", "a,b")
+                .gauge("metric:1|c\nother", 1.0);
+        });
+        assert_eq!("my_app.d_b.metric_1_c_other:1|g|#ho_st:a_b", response);
+    }
+
+    #[test]
+    fn test_rejecting_names() {
+        let server = Server::new();
+        let mut client = Client::new(server.addr(), "myapp").unwrap();
+        client.set_name_policy(NamePolicy::Reject);
+        let response = server.run_while_receiving_all(|| {
+            client.incr("bad:name");
+            client.with_tag("bad tag", "x").incr("good");
+            client.incr("good");
+        });
+        assert_eq!(vec!["myapp.good:1|c"], response);
+    }
+
+    #[test]
+    fn test_returning_rejections() {
+        let server = Server::new();
+        let mut client = Client::new(server.addr(), "myapp").unwrap();
+        client.set_name_policy(NamePolicy::Reject);
+        let response = server.run_while_receiving_all(|| {
+            let err = client.try_count("bad:name", 1.0).unwrap_err();
+            assert!(matches!(err, StatsdError::InvalidMetric(_)));
+            let err = client.with_tag("bad tag", "x").try_gauge("good", 1.0);
+            assert!(matches!(err, Err(StatsdError::InvalidMetric(_))));
+            let err = client.try_timer("good", f64::NAN).unwrap_err();
+            assert!(matches!(err, StatsdError::InvalidValue(_)));
+            client.try_kv("good", 2.0).unwrap();
+        });
+        assert_eq!(vec!["myapp.good:2|kv"], response);
+        assert_eq!(3, client.dropped_metrics());
+    }
+
+    #[test]
+    fn test_passing_through_names() {
+        let server = Server::new();
+        let mut client = Client::new(server.addr(), "myapp").unwrap();
+        client.set_name_policy(NamePolicy::PassThrough);
+        let response = server.run_while_receiving(|| client.incr("a b"));
+        assert_eq!("myapp.a b:1|c", response);
+    }
+
+    #[test]
+    fn test_pipeline_rejecting_names() {
+        let server = Server::new();
+        let mut client = Client::new(server.addr(), "myapp").unwrap();
+        client.set_name_policy(NamePolicy::Reject);
+        let response = server.run_while_receiving(|| {
+            let mut pipeline = client.pipeline();
+            pipeline.gauge("bad\nname", 1.0);
+            pipeline.gauge("good", 1.0);
+            pipeline.send(&client);
+        });
+        assert_eq!("myapp.good:1|g", response);
+    }
+
+    #[test]
+    fn test_dropping_non_finite_values() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving_all(|| {
+            client.gauge("metric", f64::NAN);
+            client.scoped("child").timer("metric", f64::INFINITY);
+            client.count("metric", 1e300);
+        });
+        assert_eq!(vec!["myapp.metric:1e300|c"], response);
+        assert_eq!(2, client.dropped_metrics());
+    }
+
+    #[test]
+    fn test_clamping_non_finite_values() {
+        let server = Server::new();
+        let mut client = Client::new(server.addr(), "myapp").unwrap();
+        client.set_non_finite_policy(NonFinitePolicy::Clamp);
+        let response = server.run_while_receiving(|| client.gauge("metric", f64::INFINITY));
+        assert_eq!("myapp.metric:1.7976931348623157e308|g", response);
+    }
+
+    #[test]
+    fn test_sending_integer_values() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            client.count_i64("counter", -9_007_199_254_740_993);
+            client.gauge_u64("gauge", u64::MAX);
+        });
+        assert_eq!(
+            "myapp.counter:-9007199254740993|c\nmyapp.gauge:18446744073709551615|g",
+            response
+        );
+    }
+
+    #[test]
+    fn test_pipeline_dropping_non_finite_values() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            let mut pipeline = client.pipeline();
+            pipeline.gauge("metric", f64::NAN);
+            pipeline.histogram("metric", f64::NEG_INFINITY);
+            pipeline.count_i64("counter", 3);
+            pipeline.gauge_u64("gauge", 4);
+            pipeline.send(&client);
+        });
+        assert_eq!("myapp.counter:3|c\nmyapp.gauge:4|g", response);
+        assert_eq!(2, client.dropped_metrics());
+    }
+
+    #[test]
+    fn test_buffered_client() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving_all(|| {
+            let buffered = client.buffered(512, Duration::from_secs(60));
+            buffered.incr("metric");
+            buffered.scoped("db").gauge("size", 2.0);
+            buffered.flush();
+            buffered.incr("after_flush");
+        });
+        assert_eq!(
+            vec![
+                "myapp.metric:1|c\nmyapp.db.size:2|g",
+                "myapp.after_flush:1|c"
+            ],
+            response
+        );
+    }
+
+    #[test]
+    fn test_buffered_pipeline() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving_all(|| {
+            let buffered = client.buffered(512, Duration::from_secs(60));
+            buffered.incr("metric");
+            let mut pipeline = buffered.pipeline();
+            pipeline.set_max_udp_size(16);
+            pipeline.incr("first");
+            pipeline.incr("second");
+            pipeline.send(&buffered);
+            buffered.flush();
+        });
+        assert_eq!(
+            vec!["myapp.metric:1|c\nmyapp.first:1|c\nmyapp.second:1|c"],
+            response
+        );
+    }
+
+    #[test]
+    fn test_dropping_buffered_client_stops_flusher() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let started = time::Instant::now();
+        // Dropping the client waits for the flusher thread to stop.
+        drop(client.buffered(512, Duration::from_secs(60)));
+        assert!(started.elapsed() < Duration::from_secs(30));
+        // A zero interval is raised rather than spinning.
+        let buffered = client.buffered(512, Duration::ZERO);
+        let response = server.run_while_receiving(|| buffered.incr("metric"));
+        assert_eq!("myapp.metric:1|c", response);
+    }
+
+    #[test]
+    fn test_buffered_client_flushes_periodically() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let buffered = client.buffered(512, Duration::from_millis(20));
+        let response = server.run_while_receiving(|| buffered.incr("metric"));
+        assert_eq!("myapp.metric:1|c", response);
+    }
+
+    #[test]
+    fn test_buffered_client_from_threads() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving_all(|| {
+            let buffered = Arc::new(client.buffered(64, Duration::from_secs(60)));
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    let buffered = Arc::clone(&buffered);
+                    thread::spawn(move || {
+                        for _ in 0..50 {
+                            buffered.incr("metric");
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        });
+        assert!(response.iter().all(|packet| packet.len() <= 64));
+        let lines: Vec<&str> = response.iter().flat_map(|p| p.lines()).collect();
+        assert_eq!(400, lines.len());
+        assert!(lines.iter().all(|line| *line == "myapp.metric:1|c"));
+    }
+
+    #[test]
+    fn test_pipeline_time_returns_value() {
+        let mut pipeline = Pipeline::new();
+        let output = pipeline.time("time_block", || "a string");
+        assert_eq!(output, "a string");
+    }
+
+    #[test]
+    fn test_pipeline_sending_scoped_timer() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            let mut pipeline = client.pipeline();
+            {
+                let mut timer = pipeline.start_timer("scope");
+                timer.incr("started");
+            }
+            pipeline.timer_duration("metric", Duration::from_millis(5));
+            pipeline.send(&client);
+        });
+        assert_eq!(
+            "myapp.started:1|c\nmyapp.scope:0|ms\nmyapp.metric:5|ms",
+            response
+        );
+    }
+
+    #[test]
+    fn test_pipeline_sending_timed_result() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            let mut pipeline = client.pipeline();
+            let _ = pipeline.time_result::<_, (), ()>("query", || Ok(()));
+            let _ = pipeline.time_result_counted::<_, (), ()>("query", || Err(()));
+            pipeline.send(&client);
+        });
+        assert_eq!(
+            "myapp.query.ok:0|ms\nmyapp.query.err:0|ms\nmyapp.query.errors:1|c",
+            response
+        );
+    }
+
     #[test]
     fn test_pipeline_sending_gauge() {
         let server = Server::new();
@@ -640,6 +1798,18 @@ mod test {
         assert_eq!("myapp.metric:9.1|h", response);
     }
 
+    #[test]
+    fn test_pipeline_sending_distribution() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            let mut pipeline = client.pipeline();
+            pipeline.distribution("metric", 9.1);
+            pipeline.send(&client);
+        });
+        assert_eq!("myapp.metric:9.1|d", response);
+    }
+
     #[test]
     fn test_pipeline_sending_kv() {
         let server = Server::new();
@@ -696,6 +1866,51 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_pipeline_batch_send() {
+        let server = Server::new();
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving_all(|| {
+            let mut pipeline = client.pipeline();
+            pipeline.set_batch_send(true);
+            pipeline.set_max_udp_size(40);
+            pipeline.gauge("metric", 9.1);
+            pipeline.count("metric", 12.2);
+            pipeline.count("other_metric", 1.0);
+            pipeline.incr("metric_with_a_long_name_over_max_size");
+            pipeline.send(&client);
+        });
+        assert_eq!(
+            vec![
+                "myapp.metric:9.1|g\nmyapp.metric:12.2|c",
+                "myapp.other_metric:1|c",
+                "myapp.metric_with_a_long_name_over_max_size:1|c",
+            ],
+            response
+        );
+    }
+
+    #[test]
+    fn test_pipeline_batch_send_ipv6() {
+        let sock = match UdpSocket::bind("[::1]:0") {
+            Ok(sock) => sock,
+            // IPv6 is not available everywhere tests are run.
+            Err(_) => return,
+        };
+        sock.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let local_addr = sock.local_addr().unwrap();
+        let server = Server { local_addr, sock };
+        let client = Client::new(server.addr(), "myapp").unwrap();
+        let response = server.run_while_receiving(|| {
+            let mut pipeline = client.pipeline();
+            pipeline.set_batch_send(true);
+            pipeline.incr("metric");
+            pipeline.send(&client);
+        });
+        assert_eq!("myapp.metric:1|c", response);
+    }
+
     #[test]
     fn test_pipeline_send_metric_after_pipeline() {
         let server = Server::new();
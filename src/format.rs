@@ -0,0 +1,192 @@
+use std::fmt;
+use std::io::Write;
+
+use crate::client::StatsdError;
+use crate::validation::NamePolicy;
+
+/// Display wrapper writing floats in a compact, round-trippable form.
+///
+/// Values are written in plain notation when that is reasonably short,
+/// and in exponent notation otherwise, so `1e300` is not sent as a
+/// 301 digit string. Both forms are the shortest representation that
+/// parses back to the same `f64`.
+pub(crate) struct Float(pub f64);
+
+impl fmt::Display for Float {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = self.0;
+        let abs = value.abs();
+        if value == 0.0 {
+            // Avoid sending `-0`, which gauges would read as a delta.
+            f.write_str("0")
+        } else if (1e-5..1e16).contains(&abs) || !value.is_finite() {
+            write!(f, "{}", value)
+        } else {
+            write!(f, "{:e}", value)
+        }
+    }
+}
+
+/// Change of a gauge, always written with a sign so that it isn't read as
+/// a new value.
+pub(crate) struct Delta(pub f64);
+
+impl fmt::Display for Delta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 >= 0.0 {
+            f.write_str("+")?;
+        }
+        Float(self.0).fmt(f)
+    }
+}
+
+/// Prefix and tags of a client, rendered once so they can be copied
+/// into every metric line.
+#[derive(Clone)]
+pub(crate) struct Scope {
+    /// The prefix followed by a `.`, or nothing.
+    pub prefix: Vec<u8>,
+    /// The tags in `|#key:value,key:value` form, or nothing.
+    pub tags: Vec<u8>,
+}
+
+impl Scope {
+    pub fn render(
+        prefix: &str,
+        tags: &[(String, String)],
+        policy: NamePolicy,
+    ) -> Result<Scope, StatsdError> {
+        let mut scope = Scope {
+            prefix: Vec::new(),
+            tags: Vec::new(),
+        };
+        if !prefix.is_empty() {
+            policy.write(prefix, &mut scope.prefix)?;
+            scope.prefix.push(b'.');
+        }
+        for (i, (key, value)) in tags.iter().enumerate() {
+            scope
+                .tags
+                .extend_from_slice(if i == 0 { b"|#" } else { b"," });
+            policy.write(key, &mut scope.tags)?;
+            scope.tags.push(b':');
+            policy.write_tag_value(value, &mut scope.tags)?;
+        }
+        Ok(scope)
+    }
+
+    /// Length of a line once the prefix and tags are added.
+    pub fn line_len(&self, line: &[u8]) -> usize {
+        self.prefix.len() + line.len() + self.tags.len()
+    }
+
+    /// Append a line with the prefix and tags to `buf`.
+    pub fn write_line(&self, line: &[u8], buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.prefix);
+        buf.extend_from_slice(line);
+        buf.extend_from_slice(&self.tags);
+    }
+}
+
+/// Append a `name:value|kind[|@rate]` line to `buf`.
+///
+/// Nothing is written when the name is rejected by `policy`.
+pub(crate) fn write_metric<V: fmt::Display>(
+    buf: &mut Vec<u8>,
+    policy: NamePolicy,
+    metric: &str,
+    value: V,
+    kind: &str,
+    rate: Option<f64>,
+) -> Result<(), StatsdError> {
+    let start = buf.len();
+    if let Err(e) = policy.write(metric, buf) {
+        buf.truncate(start);
+        return Err(e);
+    }
+    // Writing to a `Vec` can't fail.
+    let _ = write!(buf, ":{}|{}", value, kind);
+    if let Some(rate) = rate {
+        let _ = write!(buf, "|@{}", Float(rate));
+    }
+    Ok(())
+}
+
+/// Append `key:value` tags to a line, after tags already written when
+/// `continued` is set.
+pub(crate) fn write_tags(
+    buf: &mut Vec<u8>,
+    policy: NamePolicy,
+    continued: bool,
+    tags: &[(&str, &str)],
+) -> Result<(), StatsdError> {
+    for (i, (key, value)) in tags.iter().enumerate() {
+        buf.extend_from_slice(if i == 0 && !continued { b"|#" } else { b"," });
+        policy.write(key, buf)?;
+        buf.push(b':');
+        policy.write_tag_value(value, buf)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plain_notation() {
+        assert_eq!("12", Float(12.0).to_string());
+        assert_eq!("-1", Float(-1.0).to_string());
+        assert_eq!("9.1", Float(9.1).to_string());
+        assert_eq!("0.00001", Float(0.00001).to_string());
+        assert_eq!("1000000000000000", Float(1e15).to_string());
+        assert_eq!("0", Float(-0.0).to_string());
+    }
+
+    #[test]
+    fn test_exponent_notation() {
+        assert_eq!("1e16", Float(1e16).to_string());
+        assert_eq!("-1.5e300", Float(-1.5e300).to_string());
+        assert_eq!("1.2e-7", Float(1.2e-7).to_string());
+        assert_eq!(
+            "5e-324",
+            Float(f64::MIN_POSITIVE * f64::EPSILON).to_string()
+        );
+    }
+
+    #[test]
+    fn test_write_metric() {
+        let mut buf = b"prefix.".to_vec();
+        write_metric(&mut buf, NamePolicy::Sanitize, "a b", 1, "c", Some(0.5)).unwrap();
+        assert_eq!(b"prefix.a_b:1|c|@0.5".to_vec(), buf);
+
+        let err = write_metric(&mut buf, NamePolicy::Reject, "a b", 1, "c", None);
+        assert!(err.is_err());
+        assert_eq!(b"prefix.a_b:1|c|@0.5".to_vec(), buf);
+    }
+
+    #[test]
+    fn test_scope() {
+        let tags = vec![
+            ("a".to_string(), "b".to_string()),
+            ("c d".to_string(), "e".to_string()),
+        ];
+        let scope = Scope::render("my app", &tags, NamePolicy::Sanitize).unwrap();
+        let mut buf = Vec::new();
+        scope.write_line(b"metric:1|c", &mut buf);
+        assert_eq!(b"my_app.metric:1|c|#a:b,c_d:e".to_vec(), buf);
+        assert_eq!(buf.len(), scope.line_len(b"metric:1|c"));
+
+        assert!(Scope::render("my app", &tags, NamePolicy::Reject).is_err());
+        let scope = Scope::render("", &[], NamePolicy::Reject).unwrap();
+        assert!(scope.prefix.is_empty() && scope.tags.is_empty());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for value in &[0.1, 1.0 / 3.0, 123456.789, 1e-300, f64::MAX, f64::MIN] {
+            let formatted = Float(*value).to_string();
+            assert_eq!(*value, formatted.parse::<f64>().unwrap(), "{}", formatted);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::sync::OnceLock;
+
+use crate::client::{Client, StatsdError};
+
+static GLOBAL_CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Install the client used by the `statsd_*!` macros.
+///
+/// The global client can only be installed once. When a client is
+/// already installed, `StatsdError::GlobalClientSet` is returned.
+///
+/// ```
+/// use statsd::Client;
+///
+/// let client = Client::new("127.0.0.1:8125", "myapp").unwrap();
+/// statsd::set_global_client(client).ok();
+/// statsd::statsd_incr!("some.counter");
+/// ```
+pub fn set_global_client(client: Client) -> Result<(), StatsdError> {
+    GLOBAL_CLIENT
+        .set(client)
+        .map_err(|_| StatsdError::GlobalClientSet)
+}
+
+/// Get the global client, if one has been installed.
+///
+/// ```
+/// if let Some(client) = statsd::global() {
+///     client.incr("some.counter");
+/// }
+/// ```
+pub fn global() -> Option<&'static Client> {
+    GLOBAL_CLIENT.get()
+}
+
+/// Send a metric with `key => value` tags to the global client, when one
+/// is installed.
+///
+/// The tags are written after those of the client, without creating a
+/// child client for them.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __statsd_global {
+    ($kind:expr, $metric:expr, $value:expr $(, $key:expr => $tag_value:expr)*) => {
+        if let Some(client) = $crate::global() {
+            client.send_tagged($metric, $value, $kind, &[$(($key, $tag_value)),*]);
+        }
+    };
+}
+
+/// Increment a counter of the global client by 1.
+///
+/// ```
+/// statsd::statsd_incr!("some.counter");
+/// statsd::statsd_incr!("some.counter", "region" => "eu");
+/// ```
+#[macro_export]
+macro_rules! statsd_incr {
+    ($metric:expr $(, $key:expr => $value:expr)* $(,)?) => {
+        $crate::__statsd_global!("c", $metric, 1.0 $(, $key => $value)*)
+    };
+}
+
+/// Decrement a counter of the global client by 1.
+///
+/// ```
+/// statsd::statsd_decr!("some.counter");
+/// ```
+#[macro_export]
+macro_rules! statsd_decr {
+    ($metric:expr $(, $key:expr => $value:expr)* $(,)?) => {
+        $crate::__statsd_global!("c", $metric, -1.0 $(, $key => $value)*)
+    };
+}
+
+/// Modify a counter of the global client by a value.
+///
+/// ```
+/// statsd::statsd_count!("some.counter", 12.0);
+/// ```
+#[macro_export]
+macro_rules! statsd_count {
+    ($metric:expr, $count:expr $(, $key:expr => $value:expr)* $(,)?) => {
+        $crate::__statsd_global!("c", $metric, $count $(, $key => $value)*)
+    };
+}
+
+/// Set a gauge of the global client.
+///
+/// ```
+/// statsd::statsd_gauge!("some.gauge", 9001.0, "region" => "eu");
+/// ```
+#[macro_export]
+macro_rules! statsd_gauge {
+    ($metric:expr, $gauge:expr $(, $key:expr => $value:expr)* $(,)?) => {
+        $crate::__statsd_global!("g", $metric, $gauge $(, $key => $value)*)
+    };
+}
+
+/// Send a timer value in ms to the global client.
+///
+/// ```
+/// statsd::statsd_timer!("some.timer", 10.1);
+/// ```
+#[macro_export]
+macro_rules! statsd_timer {
+    ($metric:expr, $timer:expr $(, $key:expr => $value:expr)* $(,)?) => {
+        $crate::__statsd_global!("ms", $metric, $timer $(, $key => $value)*)
+    };
+}
+
+/// Send a histogram value to the global client.
+///
+/// ```
+/// statsd::statsd_histogram!("some.histogram", 128.0);
+/// ```
+#[macro_export]
+macro_rules! statsd_histogram {
+    ($metric:expr, $histogram:expr $(, $key:expr => $value:expr)* $(,)?) => {
+        $crate::__statsd_global!("h", $metric, $histogram $(, $key => $value)*)
+    };
+}
+
+/// Time a block of code with the global client.
+///
+/// The block is run in place, so `return` and `?` behave as they do
+/// outside of the macro. The block's value is returned. Tags are
+/// passed after the block.
+///
+/// ```
+/// let answer = statsd::statsd_time!("some.timer", {
+///     // Your code here.
+///     42
+/// }, "region" => "eu");
+/// assert_eq!(answer, 42);
+/// ```
+#[macro_export]
+macro_rules! statsd_time {
+    ($metric:expr, $body:block $(, $key:expr => $value:expr)* $(,)?) => {{
+        let _metric: &str = $metric;
+        let _tags: &[(&str, &str)] = &[$(($key, $value)),*];
+        let _timer = $crate::global()
+            .map(|client| $crate::client::TaggedTimer::start(client, _metric, _tags));
+        $body
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use crate::client::Client;
+
+    fn receive(server: &UdpSocket) -> String {
+        let mut buf = [0; 1500];
+        let len = server.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    // Every test of the global client has to live in this function, as the
+    // client can only be installed once per process.
+    #[test]
+    fn test_global_client() {
+        statsd_incr!("metric");
+        assert_eq!(2, statsd_time!("metric", { 1 + 1 }));
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+        assert!(super::set_global_client(client).is_ok());
+
+        let other = Client::new(server.local_addr().unwrap(), "other").unwrap();
+        assert!(super::set_global_client(other).is_err());
+
+        statsd_incr!("counter");
+        assert_eq!("myapp.counter:1|c", receive(&server));
+        statsd_decr!("counter", "a" => "b");
+        assert_eq!("myapp.counter:-1|c|#a:b", receive(&server));
+        statsd_count!("counter", 3.0, "a" => "b", "c" => "d");
+        assert_eq!("myapp.counter:3|c|#a:b,c:d", receive(&server));
+        statsd_gauge!("gauge", 1.5);
+        assert_eq!("myapp.gauge:1.5|g", receive(&server));
+        statsd_timer!("timer", 2.5,);
+        assert_eq!("myapp.timer:2.5|ms", receive(&server));
+        statsd_histogram!("histogram", 7.0);
+        assert_eq!("myapp.histogram:7|h", receive(&server));
+        let region = String::from("eu");
+        statsd_gauge!("gauge", f64::NAN, "a" => "b");
+        statsd_gauge!(&format!("gauge.{}", 2), 3.0, "route" => "/users/:id", "region" => &region);
+        assert_eq!(
+            "myapp.gauge.2:3|g|#route:/users/:id,region:eu",
+            receive(&server)
+        );
+
+        let output = statsd_time!("time", { "a string" }, "a" => "b");
+        assert_eq!("a string", output);
+        assert_eq!("myapp.time:0|ms|#a:b", receive(&server));
+    }
+}
@@ -0,0 +1,304 @@
+//! A `tracing-subscriber` layer sending span timings and event counts
+//! through a `Client`.
+//!
+//! ```ignore
+//! use statsd::layer::StatsdLayer;
+//! use tracing_subscriber::prelude::*;
+//!
+//! let client = statsd::Client::new("127.0.0.1:8125", "myapp").unwrap();
+//! let mut layer = StatsdLayer::new(&client);
+//! layer.time_span("db.query");
+//! layer.allow_tag_field("table");
+//! tracing_subscriber::registry().with(layer).init();
+//!
+//! // Sends `myapp.db.query.busy:<ms>|ms|#table:users` and
+//! // `myapp.db.query.idle:<ms>|ms|#table:users` when the span closes.
+//! let span = tracing::info_span!("db.query", table = "users");
+//! ```
+//!
+//! This module is only available with the `tracing` feature.
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::client::Client;
+
+/// Layer recording span timings as timers and events as counters.
+///
+/// Spans are only timed when their name was passed to `time_span()`.
+/// When such a span closes, the time spent inside the span is sent as
+/// `<span name>.busy` and the time spent outside of it as
+/// `<span name>.idle`, in ms. Span fields passed to `allow_tag_field()`
+/// are sent as tags of these timers.
+///
+/// Every event increments the `events` counter, tagged with the level
+/// and target of the event.
+pub struct StatsdLayer {
+    client: Client,
+    /// Names of the timed spans, with their `busy` and `idle` timers.
+    spans: HashMap<String, (String, String)>,
+    tag_fields: HashSet<String>,
+    event_metric: Option<String>,
+}
+
+impl StatsdLayer {
+    /// Create a layer sending metrics with `client`.
+    pub fn new(client: &Client) -> StatsdLayer {
+        StatsdLayer {
+            client: client.clone(),
+            spans: HashMap::new(),
+            tag_fields: HashSet::new(),
+            event_metric: Some("events".to_string()),
+        }
+    }
+
+    /// Time spans with the given name.
+    pub fn time_span(&mut self, name: &str) {
+        let timers = (format!("{}.busy", name), format!("{}.idle", name));
+        self.spans.insert(name.to_string(), timers);
+    }
+
+    /// Send the span field with the given name as a tag.
+    ///
+    /// Only allow fields with a small number of distinct values, as each
+    /// value creates a new time series on most servers.
+    pub fn allow_tag_field(&mut self, name: &str) {
+        self.tag_fields.insert(name.to_string());
+    }
+
+    /// Set the counter incremented for each event, or `None` to not count
+    /// events.
+    pub fn set_event_metric(&mut self, metric: Option<&str>) {
+        self.event_metric = metric.map(str::to_string);
+    }
+}
+
+/// Timings and tags of a timed span, stored in the span's extensions.
+struct Timing {
+    busy: Duration,
+    idle: Duration,
+    last: Instant,
+    tags: Vec<(String, String)>,
+}
+
+/// Collects the allowed fields of a span as tags.
+struct TagVisitor<'a> {
+    allowed: &'a HashSet<String>,
+    tags: &'a mut Vec<(String, String)>,
+}
+
+impl<'a> TagVisitor<'a> {
+    fn push(&mut self, field: &Field, value: String) {
+        if self.allowed.contains(field.name()) {
+            self.tags.retain(|(key, _)| key != field.name());
+            self.tags.push((field.name().to_string(), value));
+        }
+    }
+}
+
+impl<'a> Visit for TagVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, format!("{:?}", value));
+    }
+}
+
+impl<S> Layer<S> for StatsdLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !self.spans.contains_key(attrs.metadata().name()) {
+            return;
+        }
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut tags = Vec::new();
+        attrs.record(&mut TagVisitor {
+            allowed: &self.tag_fields,
+            tags: &mut tags,
+        });
+        span.extensions_mut().insert(Timing {
+            busy: Duration::ZERO,
+            idle: Duration::ZERO,
+            last: Instant::now(),
+            tags,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                values.record(&mut TagVisitor {
+                    allowed: &self.tag_fields,
+                    tags: &mut timing.tags,
+                });
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                let now = Instant::now();
+                timing.idle += now - timing.last;
+                timing.last = now;
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                let now = Instant::now();
+                timing.busy += now - timing.last;
+                timing.last = now;
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut extensions = span.extensions_mut();
+        let timing = match extensions.remove::<Timing>() {
+            Some(timing) => timing,
+            None => return,
+        };
+        let (busy, idle) = match self.spans.get(span.name()) {
+            Some(timers) => timers,
+            None => return,
+        };
+        let tags: Vec<_> = timing
+            .tags
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let idle_time = timing.idle + timing.last.elapsed();
+        self.client
+            .send_tagged(busy, as_ms(timing.busy), "ms", &tags);
+        self.client.send_tagged(idle, as_ms(idle_time), "ms", &tags);
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        if let Some(ref metric) = self.event_metric {
+            let metadata = event.metadata();
+            let target = match metadata.target() {
+                target if target.contains("::") => Cow::Owned(target.replace("::", ".")),
+                target => Cow::Borrowed(target),
+            };
+            let tags = [("level", level_name(metadata.level())), ("target", &target)];
+            self.client.send_tagged(metric, 1.0, "c", &tags);
+        }
+    }
+}
+
+fn level_name(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "error",
+        Level::WARN => "warn",
+        Level::INFO => "info",
+        Level::DEBUG => "debug",
+        Level::TRACE => "trace",
+    }
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tracing_subscriber::prelude::*;
+
+    use crate::testing::Recording;
+
+    fn record_lines<F: FnOnce()>(configure: fn(&mut StatsdLayer), func: F) -> Vec<String> {
+        let recording = Recording::new();
+        let mut layer = StatsdLayer::new(&recording.client("myapp"));
+        configure(&mut layer);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, func);
+        recording.lines()
+    }
+
+    #[test]
+    fn test_span_timings() {
+        let lines = record_lines(
+            |layer| {
+                layer.time_span("query");
+                layer.allow_tag_field("table");
+                layer.set_event_metric(None);
+            },
+            || {
+                let span = tracing::info_span!("query", table = "users", id = 12);
+                span.in_scope(|| std::thread::sleep(Duration::from_millis(20)));
+                std::thread::sleep(Duration::from_millis(10));
+                tracing::info_span!("other").in_scope(|| {});
+            },
+        );
+        assert_eq!(2, lines.len(), "{:?}", lines);
+        assert!(lines[0].starts_with("myapp.query.busy:"));
+        assert!(lines[0].ends_with("|ms|#table:users"));
+        assert!(lines[1].starts_with("myapp.query.idle:"));
+        assert!(lines[1].ends_with("|ms|#table:users"));
+
+        let value = |line: &str| -> f64 {
+            let value = &line[line.find(':').unwrap() + 1..line.find('|').unwrap()];
+            value.parse().unwrap()
+        };
+        assert!(value(&lines[0]) >= 20.0);
+        assert!(value(&lines[1]) >= 10.0);
+    }
+
+    #[test]
+    fn test_recorded_fields_as_tags() {
+        let lines = record_lines(
+            |layer| {
+                layer.time_span("query");
+                layer.allow_tag_field("status");
+                layer.set_event_metric(None);
+            },
+            || {
+                let span = tracing::info_span!("query", status = tracing::field::Empty);
+                span.record("status", "ok");
+            },
+        );
+        assert_eq!(2, lines.len(), "{:?}", lines);
+        assert!(lines.iter().all(|line| line.ends_with("|ms|#status:ok")));
+    }
+
+    #[test]
+    fn test_event_counts() {
+        let lines = record_lines(
+            |_| {},
+            || {
+                tracing::error!(target: "app::db", "failed");
+                tracing::info!("done");
+            },
+        );
+        assert_eq!(
+            vec![
+                "myapp.events:1|c|#level:error,target:app.db",
+                "myapp.events:1|c|#level:info,target:statsd.layer.test",
+            ],
+            lines
+        );
+    }
+}
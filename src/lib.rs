@@ -7,5 +7,23 @@
 //! will be received by the server, and there is (by design) no indication of
 //! this.
 //!
+mod batch;
+mod buffer;
 pub mod client;
+mod format;
+mod global;
+#[cfg(feature = "tracing")]
+pub mod layer;
+#[cfg(feature = "log")]
+pub mod logger;
+#[cfg(feature = "tower")]
+pub mod middleware;
+pub mod process;
+pub mod protocol;
+#[cfg(feature = "metrics")]
+pub mod recorder;
+pub mod server;
+pub mod testing;
+pub mod validation;
 pub use client::Client;
+pub use global::{global, set_global_client};
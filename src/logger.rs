@@ -0,0 +1,183 @@
+//! A `log` crate logger counting log records through a `Client`.
+//!
+//! ```ignore
+//! use statsd::logger::StatsdLogger;
+//!
+//! let client = statsd::Client::new("127.0.0.1:8125", "myapp").unwrap();
+//! StatsdLogger::new(env_logger::Logger::from_default_env(), &client)
+//!     .init(log::LevelFilter::Info)
+//!     .unwrap();
+//!
+//! // Sends `myapp.log.error:1|c`
+//! log::error!("Something went wrong");
+//! ```
+//!
+//! This module is only available with the `log` feature.
+use std::borrow::Cow;
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use crate::client::Client;
+
+/// Logger forwarding records to another logger, and counting them.
+///
+/// Each record logged by the inner logger increments the
+/// `log.<level>` counter, e.g. `log.error` or `log.warn`.
+pub struct StatsdLogger<L> {
+    inner: L,
+    client: Client,
+    /// Counter of each level, indexed by `Level as usize - 1`.
+    metrics: [String; 5],
+    tag_target: bool,
+}
+
+impl<L: Log> StatsdLogger<L> {
+    /// Create a logger forwarding records to `inner`, and counting them
+    /// with `client`.
+    pub fn new(inner: L, client: &Client) -> StatsdLogger<L> {
+        StatsdLogger {
+            inner,
+            client: client.clone(),
+            metrics: level_metrics("log"),
+            tag_target: false,
+        }
+    }
+
+    /// Set the prefix of the counters, `log` by default.
+    pub fn set_metric_prefix(&mut self, prefix: &str) {
+        self.metrics = level_metrics(prefix);
+    }
+
+    /// Tag counters with the target of records, usually their module path.
+    pub fn set_tag_target(&mut self, tag_target: bool) {
+        self.tag_target = tag_target;
+    }
+
+    /// Install the logger as the global logger, with the given maximum level.
+    pub fn init(self, max_level: LevelFilter) -> Result<(), SetLoggerError>
+    where
+        L: Send + Sync + 'static,
+    {
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+impl<L: Log> Log for StatsdLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        let metric = &self.metrics[record.level() as usize - 1];
+        if self.tag_target {
+            let target = match record.target() {
+                target if target.contains("::") => Cow::Owned(target.replace("::", ".")),
+                target => Cow::Borrowed(target),
+            };
+            self.client
+                .send_tagged(metric, 1.0, "c", &[("target", &target)]);
+        } else {
+            self.client.incr(metric);
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+        self.client.flush();
+    }
+}
+
+/// Counters of the levels, from `error` to `trace`.
+fn level_metrics(prefix: &str) -> [String; 5] {
+    [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ]
+    .map(|level| format!("{}.{}", prefix, level.as_str().to_lowercase()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use log::Level;
+    use std::sync::Mutex;
+
+    use crate::testing::Recording;
+
+    /// Logger keeping messages of records up to a level.
+    struct Memory {
+        level: Level,
+        messages: Mutex<Vec<String>>,
+    }
+
+    impl Log for Memory {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= self.level
+        }
+
+        fn log(&self, record: &Record) {
+            let mut messages = self.messages.lock().unwrap();
+            messages.push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn log(logger: &StatsdLogger<Memory>, level: Level, target: &str, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    fn setup() -> (Recording, StatsdLogger<Memory>) {
+        let recording = Recording::new();
+        let client = recording.client("myapp");
+        let inner = Memory {
+            level: Level::Info,
+            messages: Mutex::new(Vec::new()),
+        };
+        (recording, StatsdLogger::new(inner, &client))
+    }
+
+    #[test]
+    fn test_counting_records() {
+        let (recording, logger) = setup();
+        log(&logger, Level::Error, "app", "failed");
+        log(&logger, Level::Warn, "app", "slow");
+        log(&logger, Level::Debug, "app", "skipped");
+        assert_eq!(
+            vec!["failed", "slow"],
+            *logger.inner.messages.lock().unwrap()
+        );
+        assert_eq!(
+            vec!["myapp.log.error:1|c", "myapp.log.warn:1|c"],
+            recording.lines()
+        );
+    }
+
+    #[test]
+    fn test_tagging_targets() {
+        let (recording, mut logger) = setup();
+        logger.set_metric_prefix("logs");
+        logger.set_tag_target(true);
+        log(&logger, Level::Info, "app::db", "connected");
+        assert_eq!(
+            vec!["myapp.logs.info:1|c|#target:app.db"],
+            recording.lines()
+        );
+    }
+}
@@ -0,0 +1,426 @@
+//! A `tower` middleware recording HTTP request metrics through a `Client`.
+//!
+//! The middleware works with any service handling `http` requests, such as
+//! `hyper` or `axum` services:
+//!
+//! ```ignore
+//! use statsd::middleware::HttpMetricsLayer;
+//!
+//! let client = statsd::Client::new("127.0.0.1:8125", "myapp").unwrap();
+//! let mut metrics = HttpMetricsLayer::new(&client);
+//! metrics.set_route(|parts| {
+//!     parts
+//!         .extensions
+//!         .get::<axum::extract::MatchedPath>()
+//!         .map_or("unknown", |path| path.as_str())
+//!         .to_string()
+//! });
+//! let app = axum::Router::new()
+//!     .route("/users/:id", axum::routing::get(get_user))
+//!     .layer(metrics);
+//! ```
+//!
+//! This module is only available with the `tower` feature.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use http::request::Parts;
+use http::{Method, Request, Response};
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::client::Client;
+
+type Extractor<T> = Arc<dyn Fn(&Parts) -> T + Send + Sync>;
+
+/// Layer recording request counts, response counts by status class and
+/// latencies of the services it wraps.
+///
+/// For each request:
+///
+/// * the `http.requests` counter is incremented when the request is
+///   received,
+/// * the `http.responses.<class>` counter is incremented when the response
+///   is ready, where `<class>` is `2xx`, `3xx`, `4xx` or `5xx`, or `error`
+///   when the service failed,
+/// * the time until the response was ready is sent as the `http.latency`
+///   timer, in ms.
+///
+/// All metrics are tagged with the `method` of the request. They are only
+/// tagged with its `route` when a route extractor is set with
+/// `set_route()`: as each distinct tag value creates a new time series on
+/// most servers, the extractor should return the route template, such as
+/// axum's `MatchedPath`, rather than the path of the request.
+#[derive(Clone)]
+pub struct HttpMetricsLayer {
+    client: Client,
+    request_metric: Option<String>,
+    response_metrics: Option<ResponseMetrics>,
+    latency_metric: Option<String>,
+    tag_method: bool,
+    route: Option<Extractor<String>>,
+    tags: Vec<(String, Extractor<Option<String>>)>,
+}
+
+impl HttpMetricsLayer {
+    /// Create a layer sending metrics with `client`.
+    pub fn new(client: &Client) -> HttpMetricsLayer {
+        HttpMetricsLayer {
+            client: client.clone(),
+            request_metric: Some("http.requests".to_string()),
+            response_metrics: Some(ResponseMetrics::new("http.responses")),
+            latency_metric: Some("http.latency".to_string()),
+            tag_method: true,
+            route: None,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Set the counter incremented for each request, or `None` to not count
+    /// requests.
+    pub fn set_request_metric(&mut self, metric: Option<&str>) {
+        self.request_metric = metric.map(str::to_string);
+    }
+
+    /// Set the prefix of the counters incremented for each response, or
+    /// `None` to not count responses.
+    pub fn set_response_metric(&mut self, metric: Option<&str>) {
+        self.response_metrics = metric.map(ResponseMetrics::new);
+    }
+
+    /// Set the timer recording the latency of requests, or `None` to not
+    /// time requests.
+    pub fn set_latency_metric(&mut self, metric: Option<&str>) {
+        self.latency_metric = metric.map(str::to_string);
+    }
+
+    /// Tag metrics with the method of requests, `true` by default.
+    pub fn set_tag_method(&mut self, tag_method: bool) {
+        self.tag_method = tag_method;
+    }
+
+    /// Tag metrics with the route extracted from requests by `route`.
+    ///
+    /// The number of distinct routes should stay small, so don't return
+    /// the raw path of requests with path parameters.
+    pub fn set_route<F>(&mut self, route: F)
+    where
+        F: Fn(&Parts) -> String + Send + Sync + 'static,
+    {
+        self.route = Some(Arc::new(route));
+    }
+
+    /// Don't tag metrics with the route of requests, the default.
+    pub fn disable_route(&mut self) {
+        self.route = None;
+    }
+
+    /// Add a tag extracted from requests. No tag is sent when `extract`
+    /// returns `None`.
+    pub fn add_tag<F>(&mut self, key: &str, extract: F)
+    where
+        F: Fn(&Parts) -> Option<String> + Send + Sync + 'static,
+    {
+        self.tags.push((key.to_string(), Arc::new(extract)));
+    }
+
+    fn request_tags(&self, parts: &Parts) -> RequestTags {
+        RequestTags {
+            method: self.tag_method.then(|| parts.method.clone()),
+            route: self.route.as_ref().map(|route| route(parts)),
+            values: self
+                .tags
+                .iter()
+                .map(|(_, extract)| extract(parts))
+                .collect(),
+        }
+    }
+
+    fn send(&self, request: &RequestTags, metric: &str, value: f64, kind: &str) {
+        let mut tags = Vec::with_capacity(2 + self.tags.len());
+        if let Some(ref method) = request.method {
+            tags.push(("method", method.as_str()));
+        }
+        if let Some(ref route) = request.route {
+            tags.push(("route", route.as_str()));
+        }
+        for ((key, _), value) in self.tags.iter().zip(&request.values) {
+            if let Some(value) = value {
+                tags.push((key.as_str(), value.as_str()));
+            }
+        }
+        self.client.send_tagged(metric, value, kind, &tags);
+    }
+}
+
+/// Names of the response counters, built once rather than per response.
+#[derive(Clone)]
+struct ResponseMetrics {
+    /// Counters of the `1xx` to `9xx` status classes.
+    classes: [String; 9],
+    error: String,
+}
+
+impl ResponseMetrics {
+    fn new(metric: &str) -> ResponseMetrics {
+        ResponseMetrics {
+            classes: std::array::from_fn(|i| format!("{}.{}xx", metric, i + 1)),
+            error: format!("{}.error", metric),
+        }
+    }
+}
+
+/// Tag values extracted from a request, sent with the keys of the layer.
+struct RequestTags {
+    method: Option<Method>,
+    route: Option<String>,
+    values: Vec<Option<String>>,
+}
+
+impl<S> Layer<S> for HttpMetricsLayer {
+    type Service = HttpMetrics<S>;
+
+    fn layer(&self, inner: S) -> HttpMetrics<S> {
+        HttpMetrics {
+            inner,
+            layer: Arc::new(self.clone()),
+        }
+    }
+}
+
+/// Service recording metrics of the requests handled by the inner service.
+///
+/// See `HttpMetricsLayer`.
+#[derive(Clone)]
+pub struct HttpMetrics<S> {
+    inner: S,
+    layer: Arc<HttpMetricsLayer>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for HttpMetrics<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let start = Instant::now();
+        let (parts, body) = request.into_parts();
+        let tags = self.layer.request_tags(&parts);
+        if let Some(ref metric) = self.layer.request_metric {
+            self.layer.send(&tags, metric, 1.0, "c");
+        }
+        ResponseFuture {
+            inner: self.inner.call(Request::from_parts(parts, body)),
+            layer: Arc::clone(&self.layer),
+            tags,
+            start,
+        }
+    }
+}
+
+pin_project! {
+    /// Future recording the metrics of a response once it is ready.
+    pub struct ResponseFuture<F> {
+        #[pin]
+        inner: F,
+        layer: Arc<HttpMetricsLayer>,
+        tags: RequestTags,
+        start: Instant,
+    }
+}
+
+impl<F, ResBody, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.project();
+        let result = match this.inner.poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+        let layer = &**this.layer;
+        if let Some(ref metric) = layer.latency_metric {
+            let elapsed = this.start.elapsed();
+            layer.send(this.tags, metric, elapsed.as_secs_f64() * 1000.0, "ms");
+        }
+        if let Some(ref metrics) = layer.response_metrics {
+            let metric = match result {
+                // Status codes are in 100..=999.
+                Ok(ref response) => {
+                    &metrics.classes[usize::from(response.status().as_u16() / 100 - 1)]
+                }
+                Err(_) => &metrics.error,
+            };
+            layer.send(this.tags, metric, 1.0, "c");
+        }
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use http::StatusCode;
+    use std::future::{ready, Ready};
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+
+    use crate::testing::Recording;
+
+    /// Service responding with the status in the `status` query parameter,
+    /// or failing when there is none.
+    struct Mock;
+
+    impl Service<Request<()>> for Mock {
+        type Response = Response<()>;
+        type Error = &'static str;
+        type Future = Ready<Result<Response<()>, &'static str>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), &'static str>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<()>) -> Self::Future {
+            let status = request
+                .uri()
+                .query()
+                .and_then(|query| query.strip_prefix("status="))
+                .and_then(|status| status.parse().ok());
+            ready(match status {
+                Some(status) => Ok(Response::builder()
+                    .status(StatusCode::from_u16(status).unwrap())
+                    .body(())
+                    .unwrap()),
+                None => Err("failed"),
+            })
+        }
+    }
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+    }
+
+    fn record_lines<F: FnOnce(&mut HttpMetricsLayer)>(
+        configure: F,
+        requests: &[(&str, &str)],
+    ) -> Vec<String> {
+        let recording = Recording::new();
+        let mut layer = HttpMetricsLayer::new(&recording.client("myapp"));
+        configure(&mut layer);
+        let mut service = layer.layer(Mock);
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for (method, uri) in requests {
+            let request = Request::builder()
+                .method(*method)
+                .uri(*uri)
+                .body(())
+                .unwrap();
+            assert!(service.poll_ready(&mut cx).is_ready());
+            let mut future = Box::pin(service.call(request));
+            assert!(future.as_mut().poll(&mut cx).is_ready());
+        }
+
+        recording.lines()
+    }
+
+    #[test]
+    fn test_request_metrics() {
+        let lines = record_lines(
+            |_| {},
+            &[("GET", "/users?status=200"), ("POST", "/users?status=503")],
+        );
+        assert_eq!(6, lines.len(), "{:?}", lines);
+        assert_eq!("myapp.http.requests:1|c|#method:GET", lines[0]);
+        assert!(lines[1].starts_with("myapp.http.latency:"));
+        assert!(lines[1].ends_with("|ms|#method:GET"));
+        assert_eq!("myapp.http.responses.2xx:1|c|#method:GET", lines[2]);
+        assert_eq!("myapp.http.responses.5xx:1|c|#method:POST", lines[5]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let lines = record_lines(
+            |layer| {
+                layer.set_request_metric(None);
+                layer.set_latency_metric(None);
+            },
+            &[("GET", "/users")],
+        );
+        assert_eq!(vec!["myapp.http.responses.error:1|c|#method:GET"], lines);
+    }
+
+    #[test]
+    fn test_configured_names_and_tags() {
+        let lines = record_lines(
+            |layer| {
+                layer.set_request_metric(Some("api.calls"));
+                layer.set_response_metric(Some("api.status"));
+                layer.set_latency_metric(None);
+                layer.set_tag_method(false);
+                layer.set_route(|parts| {
+                    parts
+                        .uri
+                        .path()
+                        .trim_end_matches(char::is_numeric)
+                        .to_string()
+                        + ":id"
+                });
+                layer.add_tag("version", |parts| {
+                    parts
+                        .uri
+                        .path()
+                        .strip_prefix("/v2")
+                        .map(|_| "2".to_string())
+                });
+            },
+            &[
+                ("GET", "/users/12?status=404"),
+                ("GET", "/v2/users/7?status=301"),
+            ],
+        );
+        assert_eq!(
+            vec![
+                "myapp.api.calls:1|c|#route:/users/:id",
+                "myapp.api.status.4xx:1|c|#route:/users/:id",
+                "myapp.api.calls:1|c|#route:/v2/users/:id,version:2",
+                "myapp.api.status.3xx:1|c|#route:/v2/users/:id,version:2",
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn test_disabling_route() {
+        let lines = record_lines(
+            |layer| {
+                layer.set_route(|parts| parts.uri.path().to_string());
+                layer.disable_route();
+                layer.set_response_metric(None);
+                layer.set_latency_metric(None);
+            },
+            &[("DELETE", "/users/1?status=204")],
+        );
+        assert_eq!(vec!["myapp.http.requests:1|c|#method:DELETE"], lines);
+    }
+}
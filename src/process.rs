@@ -0,0 +1,320 @@
+//! Process metrics sampled from `/proc/self`.
+//!
+//! A `ProcessCollector` reports the memory, CPU time, open files, threads,
+//! context switches and uptime of the current process as gauges:
+//!
+//! ```ignore
+//! use statsd::process::ProcessCollector;
+//! use std::time::Duration;
+//!
+//! let client = statsd::Client::new("127.0.0.1:8125", "myapp").unwrap();
+//! // Sends `myapp.process.memory.rss`, `myapp.process.threads`, ... every 10s.
+//! let collector = ProcessCollector::start(&client, "process", Duration::from_secs(10));
+//! ```
+//!
+//! Sampling is only supported on Linux. Elsewhere the collector sends nothing.
+use std::io;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::client::Client;
+
+/// A sample of the metrics of the current process.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProcessStats {
+    /// Resident set size, in bytes.
+    pub rss_bytes: u64,
+    /// Virtual memory size, in bytes.
+    pub virtual_memory_bytes: u64,
+    /// CPU time spent in user mode since the process started, in seconds.
+    pub cpu_user_seconds: f64,
+    /// CPU time spent in kernel mode since the process started, in seconds.
+    pub cpu_system_seconds: f64,
+    /// Number of open file descriptors.
+    pub open_fds: u64,
+    /// Number of threads.
+    pub threads: u64,
+    /// Number of voluntary context switches since the process started.
+    pub voluntary_context_switches: u64,
+    /// Number of involuntary context switches since the process started.
+    pub involuntary_context_switches: u64,
+    /// Time since the process started, in seconds.
+    pub uptime_seconds: f64,
+}
+
+impl ProcessStats {
+    /// Sample the metrics of the current process.
+    ///
+    /// This returns an error of kind `io::ErrorKind::Unsupported` on
+    /// platforms other than Linux.
+    pub fn sample() -> io::Result<ProcessStats> {
+        #[cfg(target_os = "linux")]
+        {
+            linux::sample()
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Process metrics are only available on Linux",
+            ))
+        }
+    }
+
+    /// Send the sample as gauges.
+    pub fn report(&self, client: &Client) {
+        client.gauge_u64("memory.rss", self.rss_bytes);
+        client.gauge_u64("memory.virtual", self.virtual_memory_bytes);
+        client.gauge("cpu.user", self.cpu_user_seconds);
+        client.gauge("cpu.system", self.cpu_system_seconds);
+        client.gauge_u64("fds", self.open_fds);
+        client.gauge_u64("threads", self.threads);
+        client.gauge_u64(
+            "context_switches.voluntary",
+            self.voluntary_context_switches,
+        );
+        client.gauge_u64(
+            "context_switches.involuntary",
+            self.involuntary_context_switches,
+        );
+        client.gauge("uptime", self.uptime_seconds);
+    }
+}
+
+/// Background thread reporting process metrics at an interval.
+///
+/// The thread stops when the collector is stopped or dropped.
+pub struct ProcessCollector {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ProcessCollector {
+    /// Start reporting process metrics every `interval`, with `prefix`
+    /// appended to the prefix of `client`.
+    ///
+    /// Metrics are sampled once right away.
+    pub fn start(client: &Client, prefix: &str, interval: Duration) -> ProcessCollector {
+        let client = if prefix.is_empty() {
+            client.clone()
+        } else {
+            client.scoped(prefix)
+        };
+        let (stop, stopped) = channel();
+        let thread = thread::Builder::new()
+            .name("statsd-process".to_string())
+            .spawn(move || loop {
+                if let Ok(stats) = ProcessStats::sample() {
+                    stats.report(&client);
+                }
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            })
+            .ok();
+        ProcessCollector {
+            stop: Some(stop),
+            thread,
+        }
+    }
+
+    /// Stop reporting, waiting for the background thread to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ProcessCollector {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs;
+    use std::io;
+
+    use super::ProcessStats;
+
+    pub fn sample() -> io::Result<ProcessStats> {
+        // SAFETY: sysconf has no preconditions.
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+
+        let mut stats = ProcessStats::default();
+        let stat = fs::read_to_string("/proc/self/stat")?;
+        let uptime = fs::read_to_string("/proc/uptime")?;
+        parse_stat(&stat, &uptime, ticks, page_size, &mut stats).ok_or_else(invalid)?;
+        let status = fs::read_to_string("/proc/self/status")?;
+        parse_status(&status, &mut stats);
+        stats.open_fds = count_fds()?;
+        Ok(stats)
+    }
+
+    /// Count the open file descriptors of the process.
+    pub fn count_fds() -> io::Result<u64> {
+        // Listing the directory opens a descriptor, which shows up in it.
+        let entries = fs::read_dir("/proc/self/fd")?.count() as u64;
+        Ok(entries.saturating_sub(1))
+    }
+
+    fn invalid() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected /proc/self/stat format",
+        )
+    }
+
+    /// Parse `/proc/self/stat` and `/proc/uptime`.
+    pub fn parse_stat(
+        stat: &str,
+        uptime: &str,
+        ticks: f64,
+        page_size: u64,
+        stats: &mut ProcessStats,
+    ) -> Option<()> {
+        // The command name can contain spaces, so fields are counted from
+        // the end of it. Fields after it start at the third one, `state`.
+        let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+        let field = |number: usize| -> Option<u64> { fields.get(number - 3)?.parse().ok() };
+
+        stats.cpu_user_seconds = field(14)? as f64 / ticks;
+        stats.cpu_system_seconds = field(15)? as f64 / ticks;
+        stats.threads = field(20)?;
+        let start_seconds = field(22)? as f64 / ticks;
+        stats.virtual_memory_bytes = field(23)?;
+        stats.rss_bytes = field(24)? * page_size;
+
+        let system_uptime: f64 = uptime.split_whitespace().next()?.parse().ok()?;
+        stats.uptime_seconds = (system_uptime - start_seconds).max(0.0);
+        Some(())
+    }
+
+    /// Parse context switches from `/proc/self/status`.
+    pub fn parse_status(status: &str, stats: &mut ProcessStats) {
+        for line in status.lines() {
+            let mut parts = line.splitn(2, ':');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key, value.trim()),
+                _ => continue,
+            };
+            match key {
+                "voluntary_ctxt_switches" => {
+                    stats.voluntary_context_switches = value.parse().unwrap_or(0)
+                }
+                "nonvoluntary_ctxt_switches" => {
+                    stats.involuntary_context_switches = value.parse().unwrap_or(0)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_stat() {
+        let stat = "42 (my (weird) app) S 1 42 42 0 -1 4194304 81 0 0 0 250 50 0 0 20 0 \
+                    7 0 1000 2703360 322 18446744073709551615";
+        let mut stats = ProcessStats::default();
+        linux::parse_stat(stat, "110.50 80.00", 100.0, 4096, &mut stats).unwrap();
+        assert_eq!(2.5, stats.cpu_user_seconds);
+        assert_eq!(0.5, stats.cpu_system_seconds);
+        assert_eq!(7, stats.threads);
+        assert_eq!(2703360, stats.virtual_memory_bytes);
+        assert_eq!(322 * 4096, stats.rss_bytes);
+        assert_eq!(100.5, stats.uptime_seconds);
+
+        assert!(linux::parse_stat("42 (app) S 1", "1.0", 100.0, 4096, &mut stats).is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_status() {
+        let status = "Name:\tapp\nvoluntary_ctxt_switches:\t12\nnonvoluntary_ctxt_switches:\t3\n";
+        let mut stats = ProcessStats::default();
+        linux::parse_status(status, &mut stats);
+        assert_eq!(12, stats.voluntary_context_switches);
+        assert_eq!(3, stats.involuntary_context_switches);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_count_fds() {
+        // Other tests open and close sockets concurrently, so only check
+        // that opening and closing many files moves the count by as much.
+        const FILES: u64 = 64;
+
+        let before = linux::count_fds().unwrap();
+        let files = (0..FILES)
+            .map(|_| std::fs::File::open("/proc/self/stat").unwrap())
+            .collect::<Vec<_>>();
+        let opened = linux::count_fds().unwrap();
+        assert!(opened >= before + FILES, "{} then {}", before, opened);
+
+        drop(files);
+        let closed = linux::count_fds().unwrap();
+        assert!(closed + FILES <= opened, "{} then {}", opened, closed);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sample() {
+        let stats = ProcessStats::sample().unwrap();
+        assert!(stats.rss_bytes > 0);
+        assert!(stats.virtual_memory_bytes >= stats.rss_bytes);
+        assert!(stats.threads >= 1);
+        assert!(stats.open_fds >= 3);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_collector() {
+        use std::net::UdpSocket;
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+        let collector = ProcessCollector::start(&client, "process", Duration::from_secs(60));
+
+        let mut names = Vec::new();
+        let mut buf = [0; 1500];
+        for _ in 0..9 {
+            let len = server.recv(&mut buf).unwrap();
+            let line = String::from_utf8(buf[..len].to_vec()).unwrap();
+            assert!(line.ends_with("|g"), "{}", line);
+            names.push(line[..line.find(':').unwrap()].to_string());
+        }
+        collector.stop();
+        assert_eq!(
+            vec![
+                "myapp.process.memory.rss",
+                "myapp.process.memory.virtual",
+                "myapp.process.cpu.user",
+                "myapp.process.cpu.system",
+                "myapp.process.fds",
+                "myapp.process.threads",
+                "myapp.process.context_switches.voluntary",
+                "myapp.process.context_switches.involuntary",
+                "myapp.process.uptime",
+            ],
+            names
+        );
+    }
+}
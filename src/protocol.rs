@@ -0,0 +1,946 @@
+//! Parser for the statsd line protocol.
+//!
+//! Packets hold one line per metric, event or service check:
+//!
+//! ```text
+//! <name>:<value>[:<value>...]|<type>[|@<sample rate>][|#<tags>][|T<timestamp>]
+//! _e{<title length>,<text length>}:<title>|<text>[|<field>...]
+//! _sc|<name>|<status>[|<field>...]
+//! ```
+//!
+//! Parsed lines borrow from the input, so parsing doesn't allocate:
+//!
+//! ```
+//! use statsd::protocol::{parse_packet, Line, MetricType, Value};
+//!
+//! let packet = b"myapp.requests:1|c|@0.5|#method:GET\nmyapp.load:+2|g";
+//! let lines: Vec<Line> = parse_packet(packet).collect::<Result<_, _>>().unwrap();
+//!
+//! let requests = match &lines[0] {
+//!     Line::Metric(metric) => metric,
+//!     _ => unreachable!(),
+//! };
+//! assert_eq!("myapp.requests", requests.name);
+//! assert_eq!(MetricType::Counter, requests.metric_type);
+//! assert_eq!(Value::Number(1.0), requests.value());
+//! assert_eq!(Some(0.5), requests.sample_rate);
+//! assert_eq!(vec![("method", Some("GET"))], requests.tags.iter().collect::<Vec<_>>());
+//!
+//! // Gauge values with a sign change the gauge instead of setting it.
+//! match &lines[1] {
+//!     Line::Metric(metric) => assert_eq!(Value::Delta(2.0), metric.value()),
+//!     _ => unreachable!(),
+//! }
+//! ```
+use std::error;
+use std::fmt;
+use std::str;
+
+/// Type of a metric.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MetricType {
+    /// `c`
+    Counter,
+    /// `g`
+    Gauge,
+    /// `ms`
+    Timer,
+    /// `h`
+    Histogram,
+    /// `s`
+    Set,
+    /// `d`
+    Distribution,
+    /// `kv`
+    KeyValue,
+}
+
+impl MetricType {
+    fn parse(kind: &str) -> Option<MetricType> {
+        match kind {
+            "c" => Some(MetricType::Counter),
+            "g" => Some(MetricType::Gauge),
+            "ms" => Some(MetricType::Timer),
+            "h" => Some(MetricType::Histogram),
+            "s" => Some(MetricType::Set),
+            "d" => Some(MetricType::Distribution),
+            "kv" => Some(MetricType::KeyValue),
+            _ => None,
+        }
+    }
+
+    /// The type as written in lines, e.g. `ms` for timers.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            MetricType::Counter => "c",
+            MetricType::Gauge => "g",
+            MetricType::Timer => "ms",
+            MetricType::Histogram => "h",
+            MetricType::Set => "s",
+            MetricType::Distribution => "d",
+            MetricType::KeyValue => "kv",
+        }
+    }
+}
+
+impl fmt::Display for MetricType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A value of a metric.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'a> {
+    /// A number, always finite.
+    Number(f64),
+    /// A signed gauge value, to add to the current value of the gauge.
+    Delta(f64),
+    /// A member of a set.
+    Member(&'a str),
+}
+
+impl<'a> Value<'a> {
+    fn parse(metric_type: MetricType, value: &'a str) -> Option<Value<'a>> {
+        if metric_type == MetricType::Set {
+            return if value.is_empty() {
+                None
+            } else {
+                Some(Value::Member(value))
+            };
+        }
+        let number = parse_number(value)?;
+        if metric_type == MetricType::Gauge && value.starts_with(['+', '-']) {
+            Some(Value::Delta(number))
+        } else {
+            Some(Value::Number(number))
+        }
+    }
+}
+
+/// Parse a finite number, rejecting `inf` and `NaN`, which `f64` parsing
+/// accepts.
+fn parse_number(value: &str) -> Option<f64> {
+    value.parse().ok().filter(|number: &f64| number.is_finite())
+}
+
+/// Tags of a line, in the DogStatsD `key:value,key` format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tags<'a> {
+    raw: &'a str,
+}
+
+impl<'a> Tags<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty()
+    }
+
+    /// The tags as written in the line, without the leading `#`.
+    pub fn as_str(&self) -> &'a str {
+        self.raw
+    }
+
+    /// Iterate over `(key, value)` pairs. Tags without a `:` have no value.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> {
+        self.raw
+            .split(',')
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| match tag.find(':') {
+                Some(i) => (&tag[..i], Some(&tag[i + 1..])),
+                None => (tag, None),
+            })
+    }
+}
+
+/// A metric line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Metric<'a> {
+    pub name: &'a str,
+    pub metric_type: MetricType,
+    /// Sample rate, in `(0, 1]`.
+    pub sample_rate: Option<f64>,
+    pub tags: Tags<'a>,
+    /// Unix timestamp of the metric, in seconds.
+    pub timestamp: Option<u64>,
+    /// The `:`-separated values, already validated.
+    values: &'a str,
+}
+
+impl<'a> Metric<'a> {
+    /// Get the first value of the metric.
+    pub fn value(&self) -> Value<'a> {
+        self.values().next().unwrap_or(Value::Member(self.values))
+    }
+
+    /// Iterate over the values of the metric. DogStatsD packs several
+    /// values of a metric in one line, as `name:1:2:3|ms`. Set members
+    /// can't be packed, as they may contain `:`.
+    pub fn values(&self) -> impl Iterator<Item = Value<'a>> {
+        let metric_type = self.metric_type;
+        let count = if metric_type == MetricType::Set {
+            1
+        } else {
+            usize::MAX
+        };
+        self.values
+            .splitn(count, ':')
+            .filter_map(move |value| Value::parse(metric_type, value))
+    }
+}
+
+/// Status of a service check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ServiceCheckStatus {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+/// An event line, in the DogStatsD format.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event<'a> {
+    pub title: &'a str,
+    /// Text of the event, with line breaks escaped as `\n`.
+    pub text: &'a str,
+    /// Unix timestamp of the event, in seconds, from the `d:` field.
+    pub timestamp: Option<u64>,
+    /// `h:` field.
+    pub hostname: Option<&'a str>,
+    /// `k:` field.
+    pub aggregation_key: Option<&'a str>,
+    /// `p:` field, `normal` or `low`.
+    pub priority: Option<&'a str>,
+    /// `s:` field.
+    pub source_type: Option<&'a str>,
+    /// `t:` field, `error`, `warning`, `info` or `success`.
+    pub alert_type: Option<&'a str>,
+    pub tags: Tags<'a>,
+}
+
+/// A service check line, in the DogStatsD format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceCheck<'a> {
+    pub name: &'a str,
+    pub status: ServiceCheckStatus,
+    /// Unix timestamp of the check, in seconds, from the `d:` field.
+    pub timestamp: Option<u64>,
+    /// `h:` field.
+    pub hostname: Option<&'a str>,
+    pub tags: Tags<'a>,
+    /// `m:` field.
+    pub message: Option<&'a str>,
+}
+
+/// A parsed line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Line<'a> {
+    Metric(Metric<'a>),
+    Event(Event<'a>),
+    ServiceCheck(ServiceCheck<'a>),
+}
+
+/// What is wrong with a line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ParseErrorKind {
+    /// The line is not valid UTF-8.
+    InvalidUtf8,
+    /// The line is empty.
+    Empty,
+    /// The metric or service check has no name.
+    EmptyName,
+    /// The metric has no `:` separating its name and value.
+    MissingValue,
+    /// The value is not a finite number, or an empty set member.
+    InvalidValue,
+    /// The metric has no `|` followed by its type.
+    MissingType,
+    /// The metric type is not one of `c`, `g`, `ms`, `h`, `s`, `d` or `kv`.
+    UnknownType,
+    /// The sample rate is not a number in `(0, 1]`.
+    InvalidSampleRate,
+    /// The timestamp is not a positive integer.
+    InvalidTimestamp,
+    /// A `|` separated field is not known for this kind of line.
+    UnknownField,
+    /// The event doesn't start with `_e{<title length>,<text length>}:`.
+    InvalidEventHeader,
+    /// The title and text of the event don't match their lengths.
+    InvalidEventLength,
+    /// The service check status is not `0`, `1`, `2` or `3`.
+    InvalidStatus,
+    /// A line received over TCP is longer than the largest datagram.
+    TooLong,
+}
+
+impl ParseErrorKind {
+    /// Name in snake case, e.g. `invalid_value`, to tag metrics with.
+    pub(crate) fn name(&self) -> &'static str {
+        match *self {
+            ParseErrorKind::InvalidUtf8 => "invalid_utf8",
+            ParseErrorKind::Empty => "empty",
+            ParseErrorKind::EmptyName => "empty_name",
+            ParseErrorKind::MissingValue => "missing_value",
+            ParseErrorKind::InvalidValue => "invalid_value",
+            ParseErrorKind::MissingType => "missing_type",
+            ParseErrorKind::UnknownType => "unknown_type",
+            ParseErrorKind::InvalidSampleRate => "invalid_sample_rate",
+            ParseErrorKind::InvalidTimestamp => "invalid_timestamp",
+            ParseErrorKind::UnknownField => "unknown_field",
+            ParseErrorKind::InvalidEventHeader => "invalid_event_header",
+            ParseErrorKind::InvalidEventLength => "invalid_event_length",
+            ParseErrorKind::InvalidStatus => "invalid_status",
+            ParseErrorKind::TooLong => "too_long",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match *self {
+            ParseErrorKind::InvalidUtf8 => "Invalid UTF-8",
+            ParseErrorKind::Empty => "Empty line",
+            ParseErrorKind::EmptyName => "Empty name",
+            ParseErrorKind::MissingValue => "Missing value",
+            ParseErrorKind::InvalidValue => "Invalid value",
+            ParseErrorKind::MissingType => "Missing metric type",
+            ParseErrorKind::UnknownType => "Unknown metric type",
+            ParseErrorKind::InvalidSampleRate => "Invalid sample rate",
+            ParseErrorKind::InvalidTimestamp => "Invalid timestamp",
+            ParseErrorKind::UnknownField => "Unknown field",
+            ParseErrorKind::InvalidEventHeader => "Invalid event header",
+            ParseErrorKind::InvalidEventLength => "Event title or text length mismatch",
+            ParseErrorKind::InvalidStatus => "Invalid service check status",
+            ParseErrorKind::TooLong => "Line too long",
+        }
+    }
+}
+
+/// Error parsing a line, with the byte offset of the error in the line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    kind: ParseErrorKind,
+    offset: usize,
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, offset: usize) -> ParseError {
+        ParseError { kind, offset }
+    }
+
+    pub fn kind(&self) -> ParseErrorKind {
+        self.kind
+    }
+
+    /// Byte offset of the error in the line.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind.description(), self.offset)
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// Parse a line, without its trailing line break.
+pub fn parse_line(line: &str) -> Result<Line<'_>, ParseError> {
+    if line.is_empty() {
+        Err(ParseError::new(ParseErrorKind::Empty, 0))
+    } else if line.starts_with("_e{") {
+        parse_event(line).map(Line::Event)
+    } else if line.starts_with("_sc|") {
+        parse_service_check(line).map(Line::ServiceCheck)
+    } else {
+        parse_metric(line).map(Line::Metric)
+    }
+}
+
+/// Parse the lines of a packet. Empty lines are skipped.
+pub fn parse_packet(packet: &[u8]) -> Lines<'_> {
+    Lines {
+        lines: packet.split(|&byte| byte == b'\n'),
+    }
+}
+
+/// Iterator over the parsed lines of a packet, see `parse_packet()`.
+pub struct Lines<'a> {
+    lines: std::slice::Split<'a, u8, fn(&u8) -> bool>,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Result<Line<'a>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.find(|line| !line.is_empty())?;
+        Some(match str::from_utf8(line) {
+            Ok(line) => parse_line(line),
+            Err(e) => Err(ParseError::new(
+                ParseErrorKind::InvalidUtf8,
+                e.valid_up_to(),
+            )),
+        })
+    }
+}
+
+/// Split the `|` separated fields of a line, with their offsets.
+fn fields(line: &str, offset: usize) -> impl Iterator<Item = (usize, &str)> {
+    line.split('|').scan(offset, |offset, field| {
+        let start = *offset;
+        *offset += field.len() + 1;
+        Some((start, field))
+    })
+}
+
+fn parse_timestamp(value: &str, offset: usize) -> Result<u64, ParseError> {
+    match value.parse() {
+        Ok(timestamp) if !value.starts_with('+') => Ok(timestamp),
+        _ => Err(ParseError::new(ParseErrorKind::InvalidTimestamp, offset)),
+    }
+}
+
+fn parse_metric(line: &str) -> Result<Metric<'_>, ParseError> {
+    use self::ParseErrorKind::*;
+
+    let colon = match line.find([':', '|']) {
+        Some(i) if line.as_bytes()[i] == b':' => i,
+        Some(i) => return Err(ParseError::new(MissingValue, i)),
+        None => return Err(ParseError::new(MissingValue, line.len())),
+    };
+    if colon == 0 {
+        return Err(ParseError::new(EmptyName, 0));
+    }
+    let bar = match line[colon..].find('|') {
+        Some(i) => colon + i,
+        None => return Err(ParseError::new(MissingType, line.len())),
+    };
+    let mut fields = fields(&line[bar + 1..], bar + 1);
+    let (offset, kind) = fields.next().unwrap_or((bar + 1, ""));
+    let metric_type = MetricType::parse(kind).ok_or(ParseError::new(UnknownType, offset))?;
+
+    let values = &line[colon + 1..bar];
+    if metric_type == MetricType::Set {
+        if values.is_empty() {
+            return Err(ParseError::new(InvalidValue, colon + 1));
+        }
+    } else {
+        let mut offset = colon + 1;
+        for value in values.split(':') {
+            if Value::parse(metric_type, value).is_none() {
+                return Err(ParseError::new(InvalidValue, offset));
+            }
+            offset += value.len() + 1;
+        }
+    }
+
+    let mut metric = Metric {
+        name: &line[..colon],
+        metric_type,
+        sample_rate: None,
+        tags: Tags::default(),
+        timestamp: None,
+        values,
+    };
+    for (offset, field) in fields {
+        if let Some(rate) = field.strip_prefix('@') {
+            match parse_number(rate) {
+                Some(rate) if rate > 0.0 && rate <= 1.0 => metric.sample_rate = Some(rate),
+                _ => return Err(ParseError::new(InvalidSampleRate, offset)),
+            }
+        } else if let Some(tags) = field.strip_prefix('#') {
+            metric.tags = Tags { raw: tags };
+        } else if let Some(timestamp) = field.strip_prefix('T') {
+            metric.timestamp = Some(parse_timestamp(timestamp, offset)?);
+        } else {
+            return Err(ParseError::new(UnknownField, offset));
+        }
+    }
+    Ok(metric)
+}
+
+fn parse_event(line: &str) -> Result<Event<'_>, ParseError> {
+    use self::ParseErrorKind::*;
+
+    let header_end = line
+        .find("}:")
+        .ok_or(ParseError::new(InvalidEventHeader, 0))?;
+    let (title_len, text_len) = line[3..header_end]
+        .split_once(',')
+        .and_then(|(title, text)| Some((title.parse().ok()?, text.parse().ok()?)))
+        .filter(|_| !line[3..header_end].contains('+'))
+        .ok_or(ParseError::new(InvalidEventHeader, 3))?;
+
+    let title_start = header_end + 2;
+    let title_end = title_start
+        .checked_add(title_len)
+        .ok_or(ParseError::new(InvalidEventLength, title_start))?;
+    let text_end = title_end
+        .checked_add(1)
+        .and_then(|start| start.checked_add(text_len))
+        .ok_or(ParseError::new(InvalidEventLength, title_start))?;
+    let title = line
+        .get(title_start..title_end)
+        .ok_or(ParseError::new(InvalidEventLength, title_start))?;
+    if line.as_bytes().get(title_end) != Some(&b'|') {
+        return Err(ParseError::new(InvalidEventLength, title_end));
+    }
+    let text = line
+        .get(title_end + 1..text_end)
+        .ok_or(ParseError::new(InvalidEventLength, title_end + 1))?;
+    if title.is_empty() {
+        return Err(ParseError::new(EmptyName, title_start));
+    }
+
+    let mut event = Event {
+        title,
+        text,
+        ..Event::default()
+    };
+    let rest = &line[text_end..];
+    if rest.is_empty() {
+        return Ok(event);
+    }
+    if !rest.starts_with('|') {
+        return Err(ParseError::new(InvalidEventLength, text_end));
+    }
+    for (offset, field) in fields(&rest[1..], text_end + 1) {
+        if let Some(timestamp) = field.strip_prefix("d:") {
+            event.timestamp = Some(parse_timestamp(timestamp, offset + 2)?);
+        } else if let Some(hostname) = field.strip_prefix("h:") {
+            event.hostname = Some(hostname);
+        } else if let Some(key) = field.strip_prefix("k:") {
+            event.aggregation_key = Some(key);
+        } else if let Some(priority) = field.strip_prefix("p:") {
+            event.priority = Some(priority);
+        } else if let Some(source) = field.strip_prefix("s:") {
+            event.source_type = Some(source);
+        } else if let Some(alert) = field.strip_prefix("t:") {
+            event.alert_type = Some(alert);
+        } else if let Some(tags) = field.strip_prefix('#') {
+            event.tags = Tags { raw: tags };
+        } else {
+            return Err(ParseError::new(UnknownField, offset));
+        }
+    }
+    Ok(event)
+}
+
+fn parse_service_check(line: &str) -> Result<ServiceCheck<'_>, ParseError> {
+    use self::ParseErrorKind::*;
+
+    let mut fields = fields(&line[4..], 4);
+    let (_, name) = fields.next().unwrap_or((4, ""));
+    if name.is_empty() {
+        return Err(ParseError::new(EmptyName, 4));
+    }
+    let (offset, status) = fields
+        .next()
+        .ok_or(ParseError::new(InvalidStatus, line.len()))?;
+    let status = match status {
+        "0" => ServiceCheckStatus::Ok,
+        "1" => ServiceCheckStatus::Warning,
+        "2" => ServiceCheckStatus::Critical,
+        "3" => ServiceCheckStatus::Unknown,
+        _ => return Err(ParseError::new(InvalidStatus, offset)),
+    };
+
+    let mut check = ServiceCheck {
+        name,
+        status,
+        timestamp: None,
+        hostname: None,
+        tags: Tags::default(),
+        message: None,
+    };
+    for (offset, field) in fields {
+        if let Some(timestamp) = field.strip_prefix("d:") {
+            check.timestamp = Some(parse_timestamp(timestamp, offset + 2)?);
+        } else if let Some(hostname) = field.strip_prefix("h:") {
+            check.hostname = Some(hostname);
+        } else if let Some(tags) = field.strip_prefix('#') {
+            check.tags = Tags { raw: tags };
+        } else if field.starts_with("m:") {
+            // The message is the last field, and may contain `|`.
+            check.message = Some(&line[offset + 2..]);
+            break;
+        } else {
+            return Err(ParseError::new(UnknownField, offset));
+        }
+    }
+    Ok(check)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    use crate::testing::Recording;
+    use crate::validation::NamePolicy;
+
+    fn metric(line: &str) -> Metric<'_> {
+        match parse_line(line) {
+            Ok(Line::Metric(metric)) => metric,
+            other => panic!("Expected a metric, got {:?}", other),
+        }
+    }
+
+    fn error(line: &str) -> (ParseErrorKind, usize) {
+        let error = parse_line(line).unwrap_err();
+        (error.kind(), error.offset())
+    }
+
+    #[test]
+    fn test_parsing_metrics() {
+        let counter = metric("a.b:-2.5|c|@0.1|#k:v,flag|T1700000000");
+        assert_eq!("a.b", counter.name);
+        assert_eq!(MetricType::Counter, counter.metric_type);
+        assert_eq!(Value::Number(-2.5), counter.value());
+        assert_eq!(Some(0.1), counter.sample_rate);
+        assert_eq!(
+            vec![("k", Some("v")), ("flag", None)],
+            counter.tags.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(Some(1700000000), counter.timestamp);
+
+        assert_eq!(Value::Number(3.0), metric("g:3|g").value());
+        assert_eq!(Value::Delta(3.0), metric("g:+3|g").value());
+        assert_eq!(Value::Delta(-3.0), metric("g:-3|g").value());
+        assert_eq!(Value::Number(1e-7), metric("t:1e-7|ms").value());
+        assert_eq!(Value::Number(12.0), metric("h:12|h").value());
+        assert_eq!(Value::Number(12.0), metric("d:12|d").value());
+        assert_eq!(Value::Number(12.0), metric("kv:12|kv").value());
+        assert_eq!(Value::Member("user:12"), metric("s:user:12|s").value());
+        assert!(metric("s:user|s").tags.is_empty());
+    }
+
+    #[test]
+    fn test_parsing_packed_values() {
+        let timer = metric("t:1:2.5:3|ms");
+        assert_eq!(
+            vec![Value::Number(1.0), Value::Number(2.5), Value::Number(3.0)],
+            timer.values().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![Value::Member("a:b")],
+            metric("s:a:b|s").values().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_metric_errors() {
+        use self::ParseErrorKind::*;
+
+        assert_eq!((Empty, 0), error(""));
+        assert_eq!((MissingValue, 4), error("name"));
+        assert_eq!((MissingValue, 4), error("name|c"));
+        assert_eq!((EmptyName, 0), error(":1|c"));
+        assert_eq!((MissingType, 6), error("name:1"));
+        assert_eq!((UnknownType, 7), error("name:1|x"));
+        assert_eq!((UnknownType, 7), error("name:1|"));
+        assert_eq!((InvalidValue, 5), error("name:|c"));
+        assert_eq!((InvalidValue, 5), error("name:abc|c"));
+        assert_eq!((InvalidValue, 5), error("name:inf|ms"));
+        assert_eq!((InvalidValue, 5), error("name:NaN|g"));
+        assert_eq!((InvalidValue, 7), error("name:1:x|ms"));
+        assert_eq!((InvalidValue, 5), error("name:|s"));
+        assert_eq!((InvalidSampleRate, 9), error("name:1|c|@0"));
+        assert_eq!((InvalidSampleRate, 9), error("name:1|c|@1.5"));
+        assert_eq!((InvalidSampleRate, 9), error("name:1|c|@x"));
+        assert_eq!((InvalidTimestamp, 9), error("name:1|c|T-1"));
+        assert_eq!((UnknownField, 9), error("name:1|c|x"));
+        assert_eq!((UnknownField, 9), error("name:1|c||#a"));
+    }
+
+    #[test]
+    fn test_parsing_events() {
+        let line = "_e{5,11}:Hello|Big\\nnews|x|d:1700000000|h:web1|k:key|p:low|s:app|t:error|#a:b";
+        let event = match parse_line(line).unwrap() {
+            Line::Event(event) => event,
+            other => panic!("Expected an event, got {:?}", other),
+        };
+        assert_eq!(
+            Event {
+                title: "Hello",
+                text: "Big\\nnews|x",
+                timestamp: Some(1700000000),
+                hostname: Some("web1"),
+                aggregation_key: Some("key"),
+                priority: Some("low"),
+                source_type: Some("app"),
+                alert_type: Some("error"),
+                tags: Tags { raw: "a:b" },
+            },
+            event
+        );
+        assert!(matches!(parse_line("_e{1,0}:a|"), Ok(Line::Event(_))));
+    }
+
+    #[test]
+    fn test_event_errors() {
+        use self::ParseErrorKind::*;
+
+        assert_eq!((InvalidEventHeader, 0), error("_e{1,1:a|b"));
+        assert_eq!((InvalidEventHeader, 3), error("_e{1}:a|b"));
+        assert_eq!((InvalidEventHeader, 3), error("_e{x,1}:a|b"));
+        assert_eq!((InvalidEventHeader, 3), error("_e{+1,1}:a|b"));
+        assert_eq!((InvalidEventLength, 10), error("_e{2,1}:a|b"));
+        assert_eq!((InvalidEventLength, 10), error("_e{1,5}:a|b"));
+        assert_eq!((InvalidEventLength, 11), error("_e{1,1}:a|bc"));
+        assert_eq!(
+            (InvalidEventHeader, 3),
+            error("_e{99999999999999999999,1}:a|b")
+        );
+        assert_eq!(
+            (InvalidEventLength, 27),
+            error("_e{18446744073709551615,1}:a|b")
+        );
+        assert_eq!((EmptyName, 8), error("_e{0,1}:|b"));
+        assert_eq!((UnknownField, 12), error("_e{1,1}:a|b|z:1"));
+        assert_eq!((InvalidTimestamp, 14), error("_e{1,1}:a|b|d:x"));
+        // Lengths are in bytes, and must fall on character boundaries.
+        assert_eq!((InvalidEventLength, 8), error("_e{1,1}:é|b"));
+    }
+
+    #[test]
+    fn test_parsing_service_checks() {
+        let line = "_sc|db.up|2|d:1700000000|h:db1|#env:prod|m:Down | since 5m";
+        let check = match parse_line(line).unwrap() {
+            Line::ServiceCheck(check) => check,
+            other => panic!("Expected a service check, got {:?}", other),
+        };
+        assert_eq!(
+            ServiceCheck {
+                name: "db.up",
+                status: ServiceCheckStatus::Critical,
+                timestamp: Some(1700000000),
+                hostname: Some("db1"),
+                tags: Tags { raw: "env:prod" },
+                message: Some("Down | since 5m"),
+            },
+            check
+        );
+
+        use self::ParseErrorKind::*;
+        assert_eq!((EmptyName, 4), error("_sc||0"));
+        assert_eq!((InvalidStatus, 8), error("_sc|name"));
+        assert_eq!((InvalidStatus, 9), error("_sc|name|4"));
+        assert_eq!((UnknownField, 11), error("_sc|name|0|x"));
+    }
+
+    #[test]
+    fn test_parsing_packets() {
+        let packet = b"a:1|c\n\nb:2|x\nc:\xff|c\n";
+        let lines: Vec<_> = parse_packet(packet).collect();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].is_ok());
+        assert_eq!(
+            Some(ParseErrorKind::UnknownType),
+            lines[1].as_ref().err().map(ParseError::kind)
+        );
+        assert_eq!(
+            Err(ParseError::new(ParseErrorKind::InvalidUtf8, 2)),
+            lines[2]
+        );
+        assert_eq!(
+            "Unknown metric type at byte 4",
+            lines[1].as_ref().unwrap_err().to_string()
+        );
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Incr,
+        Decr,
+        Count(f64),
+        CountI64(i64),
+        Gauge(f64),
+        GaugeU64(u64),
+        Timer(f64),
+        Histogram(f64),
+        Distribution(f64),
+        Kv(f64),
+    }
+
+    impl Op {
+        fn send(&self, client: &crate::Client, name: &str) {
+            match *self {
+                Op::Incr => client.incr(name),
+                Op::Decr => client.decr(name),
+                Op::Count(value) => client.count(name, value),
+                Op::CountI64(value) => client.count_i64(name, value),
+                Op::Gauge(value) => client.gauge(name, value),
+                Op::GaugeU64(value) => client.gauge_u64(name, value),
+                Op::Timer(value) => client.timer(name, value),
+                Op::Histogram(value) => client.histogram(name, value),
+                Op::Distribution(value) => client.distribution(name, value),
+                Op::Kv(value) => client.kv(name, value),
+            }
+        }
+
+        fn push(&self, pipeline: &mut crate::client::Pipeline, name: &str) {
+            match *self {
+                Op::Incr => pipeline.incr(name),
+                Op::Decr => pipeline.decr(name),
+                Op::Count(value) => pipeline.count(name, value),
+                Op::CountI64(value) => pipeline.count_i64(name, value),
+                Op::Gauge(value) => pipeline.gauge(name, value),
+                Op::GaugeU64(value) => pipeline.gauge_u64(name, value),
+                Op::Timer(value) => pipeline.timer(name, value),
+                Op::Histogram(value) => pipeline.histogram(name, value),
+                Op::Distribution(value) => pipeline.distribution(name, value),
+                Op::Kv(value) => pipeline.kv(name, value),
+            }
+        }
+
+        /// The type and value a server should read.
+        fn expected(&self) -> (MetricType, Value<'static>) {
+            let gauge = |value: f64| {
+                if value < 0.0 {
+                    Value::Delta(value)
+                } else {
+                    Value::Number(value.abs())
+                }
+            };
+            match *self {
+                Op::Incr => (MetricType::Counter, Value::Number(1.0)),
+                Op::Decr => (MetricType::Counter, Value::Number(-1.0)),
+                Op::Count(value) => (MetricType::Counter, Value::Number(value)),
+                Op::CountI64(value) => (MetricType::Counter, Value::Number(value as f64)),
+                Op::Gauge(value) => (MetricType::Gauge, gauge(value)),
+                Op::GaugeU64(value) => (MetricType::Gauge, Value::Number(value as f64)),
+                Op::Timer(value) => (MetricType::Timer, Value::Number(value)),
+                Op::Histogram(value) => (MetricType::Histogram, Value::Number(value)),
+                Op::Distribution(value) => (MetricType::Distribution, Value::Number(value)),
+                Op::Kv(value) => (MetricType::KeyValue, Value::Number(value)),
+            }
+        }
+    }
+
+    fn finite() -> impl Strategy<Value = f64> {
+        prop_oneof![
+            any::<f64>().prop_filter("finite", |value| value.is_finite()),
+            -1e6..1e6f64,
+            Just(0.0),
+            Just(-0.0),
+        ]
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            Just(Op::Incr),
+            Just(Op::Decr),
+            finite().prop_map(Op::Count),
+            any::<i64>().prop_map(Op::CountI64),
+            finite().prop_map(Op::Gauge),
+            any::<u64>().prop_map(Op::GaugeU64),
+            finite().prop_map(Op::Timer),
+            finite().prop_map(Op::Histogram),
+            finite().prop_map(Op::Distribution),
+            finite().prop_map(Op::Kv),
+        ]
+    }
+
+    fn name() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9_.:|@#, \t\n\u{e9}-]{1,20}"
+    }
+
+    fn tags() -> impl Strategy<Value = Vec<(String, String)>> {
+        proptest::collection::vec(("[a-z0-9:|@#, ]{0,8}", "[a-z0-9:|@#, /]{0,8}"), 0..4)
+    }
+
+    /// Check that a recorded line reads back as what was sent.
+    fn check_line(line: &str, name: &str, op: &Op, tags: &[(String, String)]) {
+        let metric = metric(line);
+        let (metric_type, value) = op.expected();
+        let sanitize = |name: &str| NamePolicy::Sanitize.apply(name).unwrap().into_owned();
+        let sanitize_value = |value: &str| {
+            NamePolicy::Sanitize
+                .apply_tag_value(value)
+                .unwrap()
+                .into_owned()
+        };
+        assert_eq!(format!("myapp.{}", sanitize(name)), metric.name);
+        assert_eq!(metric_type, metric.metric_type);
+        assert_eq!(value, metric.value(), "{}", line);
+        assert_eq!(None, metric.sample_rate);
+        let expected_tags: Vec<_> = tags
+            .iter()
+            .map(|(key, value)| (sanitize(key), Some(sanitize_value(value))))
+            .collect();
+        let tags: Vec<_> = metric
+            .tags
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.map(str::to_string)))
+            .collect();
+        assert_eq!(expected_tags, tags);
+    }
+
+    fn tagged_client(recording: &Recording, tags: &[(String, String)]) -> crate::Client {
+        let mut client = recording.client("myapp");
+        client.set_name_policy(NamePolicy::Sanitize);
+        tags.iter()
+            .fold(client, |client, (key, value)| client.with_tag(key, value))
+    }
+
+    proptest! {
+        #[test]
+        fn test_client_round_trip(name in name(), op in op(), tags in tags()) {
+            let recording = Recording::new();
+            op.send(&tagged_client(&recording, &tags), &name);
+            let lines = recording.lines();
+            prop_assert_eq!(1, lines.len());
+            check_line(&lines[0], &name, &op, &tags);
+        }
+
+        #[test]
+        fn test_pipeline_round_trip(
+            metrics in proptest::collection::vec((name(), op()), 1..30),
+            tags in tags(),
+            max_udp_size in 1..600usize,
+        ) {
+            let recording = Recording::new();
+            let client = tagged_client(&recording, &tags);
+            let mut pipeline = client.pipeline();
+            pipeline.set_max_udp_size(max_udp_size);
+            for (name, op) in &metrics {
+                op.push(&mut pipeline, name);
+            }
+            pipeline.send(&client);
+            let lines = recording.lines();
+            prop_assert_eq!(metrics.len(), lines.len());
+            for (line, (name, op)) in lines.iter().zip(&metrics) {
+                check_line(line, name, op, &tags);
+            }
+        }
+
+        #[test]
+        fn test_sampled_round_trip(value in finite(), rate in 0.001..=1.0f64) {
+            let recording = Recording::new();
+            let client = recording.client("myapp");
+            while recording.lines().is_empty() {
+                client.sampled_count("sampled", value, rate);
+            }
+            let line = &recording.lines()[0];
+            let metric = metric(line);
+            prop_assert_eq!(Value::Number(value), metric.value());
+            prop_assert_eq!(Some(rate), metric.sample_rate);
+        }
+
+        #[test]
+        fn test_parsing_arbitrary_bytes(packet in proptest::collection::vec(any::<u8>(), 0..200)) {
+            for line in parse_packet(&packet) {
+                let _ = line;
+            }
+        }
+
+        #[test]
+        fn test_parsing_protocol_like_lines(line in "(_e\\{|_sc\\|)?[a-z0-9:|@#,.{}+T-]{0,40}") {
+            if let Err(error) = parse_line(&line) {
+                prop_assert!(error.offset() <= line.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! A `metrics` crate recorder sending metrics through a `Client`.
+//!
+//! Libraries instrumented with the `metrics` facade can be reported to
+//! statsd by installing a `StatsdRecorder`:
+//!
+//! ```ignore
+//! use statsd::recorder::StatsdRecorder;
+//!
+//! let client = statsd::Client::new("127.0.0.1:8125", "myapp").unwrap();
+//! metrics::set_global_recorder(StatsdRecorder::new(&client)).unwrap();
+//!
+//! // Sends `myapp.requests:1|c|#method:GET`
+//! metrics::counter!("requests", "method" => "GET").increment(1);
+//! ```
+//!
+//! This module is only available with the `metrics` feature.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+
+use crate::client::Client;
+
+/// Statsd metric type used to send `metrics` histograms.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HistogramType {
+    /// Send values with `Client::histogram()`.
+    #[default]
+    Histogram,
+    /// Send values with `Client::distribution()`.
+    Distribution,
+    /// Send values with `Client::timer()`.
+    Timer,
+}
+
+/// Recorder for the `metrics` crate.
+///
+/// Counters are sent as statsd counters, gauges as statsd gauges and
+/// histograms according to the configured `HistogramType`. Histograms
+/// described with a time unit are always sent as timers in ms. Labels
+/// are sent as tags. Gauge increments and decrements are sent as changes,
+/// `+1|g` or `-1|g`, so that concurrent updates add up on the server.
+///
+/// Metrics are batched into packets with a buffered client, see
+/// `Client::buffered()`.
+pub struct StatsdRecorder {
+    client: Client,
+    histogram_type: HistogramType,
+    units: Mutex<HashMap<KeyName, Unit>>,
+    handles: RwLock<HashMap<Key, Arc<Handle>>>,
+}
+
+impl StatsdRecorder {
+    /// Create a recorder sending metrics with `client`.
+    ///
+    /// Metrics are sent in packets of up to 512 bytes, and partially
+    /// filled packets are sent every 100ms.
+    pub fn new(client: &Client) -> StatsdRecorder {
+        StatsdRecorder::with_buffer(client, 512, Duration::from_millis(100))
+    }
+
+    /// Create a recorder sending metrics with `client`, in packets of up to
+    /// `max_udp_size` bytes, sending partially filled packets every
+    /// `flush_interval`.
+    pub fn with_buffer(
+        client: &Client,
+        max_udp_size: usize,
+        flush_interval: Duration,
+    ) -> StatsdRecorder {
+        StatsdRecorder {
+            client: client.buffered(max_udp_size, flush_interval),
+            histogram_type: HistogramType::default(),
+            units: Mutex::new(HashMap::new()),
+            handles: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Set the statsd metric type used to send histograms without a time unit.
+    pub fn set_histogram_type(&mut self, histogram_type: HistogramType) {
+        self.histogram_type = histogram_type;
+    }
+
+    /// Send buffered metrics.
+    pub fn flush(&self) {
+        self.client.flush();
+    }
+
+    fn handle(&self, key: &Key) -> Arc<Handle> {
+        let handles = self.handles.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(handle) = handles.get(key) {
+            return Arc::clone(handle);
+        }
+        drop(handles);
+        let mut handles = self.handles.write().unwrap_or_else(|e| e.into_inner());
+        // Another thread may have registered the metric in the meantime.
+        if let Some(handle) = handles.get(key) {
+            return Arc::clone(handle);
+        }
+        let client = key.labels().fold(self.client.clone(), |client, label| {
+            client.with_tag(label.key(), label.value())
+        });
+        let unit = self
+            .units
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(key.name())
+            .copied();
+        let histogram = match unit.and_then(ms_per_unit) {
+            Some(scale) => HistogramKind::Timer(scale),
+            None => match self.histogram_type {
+                HistogramType::Histogram => HistogramKind::Histogram,
+                HistogramType::Distribution => HistogramKind::Distribution,
+                HistogramType::Timer => HistogramKind::Timer(1.0),
+            },
+        };
+        let handle = Arc::new(Handle {
+            client,
+            name: key.name().to_string(),
+            histogram,
+        });
+        handles.insert(key.clone(), Arc::clone(&handle));
+        handle
+    }
+
+    fn describe(&self, key: KeyName, unit: Option<Unit>) {
+        if let Some(unit) = unit {
+            let mut units = self.units.lock().unwrap_or_else(|e| e.into_inner());
+            units.insert(key, unit);
+        }
+    }
+}
+
+impl Recorder for StatsdRecorder {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.describe(key, unit);
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.describe(key, unit);
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.describe(key, unit);
+    }
+
+    fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+        Counter::from_arc(self.handle(key))
+    }
+
+    fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+        Gauge::from_arc(self.handle(key))
+    }
+
+    fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+        Histogram::from_arc(self.handle(key))
+    }
+}
+
+/// Number of ms in a time unit.
+fn ms_per_unit(unit: Unit) -> Option<f64> {
+    match unit {
+        Unit::Seconds => Some(1000.0),
+        Unit::Milliseconds => Some(1.0),
+        Unit::Microseconds => Some(0.001),
+        Unit::Nanoseconds => Some(0.000_001),
+        _ => None,
+    }
+}
+
+enum HistogramKind {
+    Histogram,
+    Distribution,
+    /// Timer, with the number of ms per recorded unit.
+    Timer(f64),
+}
+
+/// A registered metric, with the client carrying its labels as tags.
+struct Handle {
+    client: Client,
+    name: String,
+    histogram: HistogramKind,
+}
+
+impl CounterFn for Handle {
+    fn increment(&self, value: u64) {
+        self.client
+            .count_i64(&self.name, i64::try_from(value).unwrap_or(i64::MAX));
+    }
+
+    /// Statsd counters can't be set, so absolute values are sent as a gauge.
+    fn absolute(&self, value: u64) {
+        self.client.gauge_u64(&self.name, value);
+    }
+}
+
+impl GaugeFn for Handle {
+    fn increment(&self, value: f64) {
+        self.client.gauge_delta(&self.name, value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.client.gauge_delta(&self.name, -value);
+    }
+
+    fn set(&self, value: f64) {
+        self.client.gauge(&self.name, value);
+    }
+}
+
+impl HistogramFn for Handle {
+    fn record(&self, value: f64) {
+        match self.histogram {
+            HistogramKind::Histogram => self.client.histogram(&self.name, value),
+            HistogramKind::Distribution => self.client.distribution(&self.name, value),
+            HistogramKind::Timer(scale) => self.client.timer(&self.name, value * scale),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::UdpSocket;
+
+    use crate::testing::Recording;
+
+    fn record_lines<F: FnOnce(&StatsdRecorder)>(histogram_type: HistogramType, func: F) -> String {
+        let recording = Recording::new();
+        let client = recording.client("myapp");
+        let mut recorder = StatsdRecorder::with_buffer(&client, 1400, Duration::from_secs(60));
+        recorder.set_histogram_type(histogram_type);
+        func(&recorder);
+        recorder.flush();
+        recording.lines().join("\n")
+    }
+
+    #[test]
+    fn test_counters() {
+        let response = record_lines(HistogramType::Histogram, |recorder| {
+            metrics::with_local_recorder(recorder, || {
+                metrics::counter!("requests").increment(1);
+                metrics::counter!("requests", "method" => "GET").increment(2);
+                metrics::counter!("total").absolute(10);
+            });
+        });
+        assert_eq!(
+            "myapp.requests:1|c\nmyapp.requests:2|c|#method:GET\nmyapp.total:10|g",
+            response
+        );
+    }
+
+    #[test]
+    fn test_gauges() {
+        let response = record_lines(HistogramType::Histogram, |recorder| {
+            metrics::with_local_recorder(recorder, || {
+                metrics::gauge!("connections").set(3.0);
+                metrics::gauge!("connections").increment(2.0);
+                metrics::gauge!("connections").decrement(1.5);
+                metrics::gauge!("connections", "pool" => "db").increment(1.0);
+            });
+        });
+        assert_eq!(
+            "myapp.connections:3|g\nmyapp.connections:+2|g\nmyapp.connections:-1.5|g\n\
+             myapp.connections:+1|g|#pool:db",
+            response
+        );
+    }
+
+    #[test]
+    fn test_histograms() {
+        let response = record_lines(HistogramType::Distribution, |recorder| {
+            metrics::with_local_recorder(recorder, || {
+                metrics::describe_histogram!("latency", Unit::Seconds, "Request latency");
+                metrics::histogram!("latency").record(0.25);
+                metrics::histogram!("size").record(128.0);
+            });
+        });
+        assert_eq!("myapp.latency:250|ms\nmyapp.size:128|d", response);
+    }
+
+    #[test]
+    fn test_batching_metrics() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+        let recorder = StatsdRecorder::with_buffer(&client, 1400, Duration::from_secs(60));
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("requests").increment(1);
+            metrics::gauge!("connections").set(3.0);
+            metrics::histogram!("size").record(128.0);
+        });
+        recorder.flush();
+
+        let mut buf = [0; 1500];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            b"myapp.requests:1|c\nmyapp.connections:3|g\nmyapp.size:128|h",
+            &buf[..len]
+        );
+    }
+
+    #[test]
+    fn test_histograms_as_timers() {
+        let response = record_lines(HistogramType::Timer, |recorder| {
+            metrics::with_local_recorder(recorder, || {
+                metrics::histogram!("latency", "route" => "/").record(12.5);
+            });
+        });
+        assert_eq!("myapp.latency:12.5|ms|#route:/", response);
+    }
+}
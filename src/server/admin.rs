@@ -0,0 +1,300 @@
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::atomic::Ordering;
+use std::time::UNIX_EPOCH;
+
+use super::{is_timeout, Handler, Key, LineReader, ReadLine, Shared, POLL_INTERVAL};
+use crate::format::Float;
+
+/// Longest command read. Longer commands are answered with `ERROR`.
+const MAX_COMMAND_SIZE: usize = 4096;
+
+const HELP: &str = "\
+Commands: stats, counters, gauges, timers, delcounters, delgauges, deltimers, health, quit
+
+";
+
+/// Serve a connection to the admin interface, answering one command per
+/// line the way the Etsy statsd management server does.
+pub(super) fn serve(shared: &Shared, stream: TcpStream) {
+    if stream.set_nonblocking(false).is_err()
+        || stream.set_read_timeout(Some(POLL_INTERVAL)).is_err()
+    {
+        return;
+    }
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut lines = LineReader::new(stream, MAX_COMMAND_SIZE);
+    let mut reply = String::new();
+    while !shared.stopped() {
+        match lines.read() {
+            Ok(ReadLine::Line(line)) => {
+                let command = String::from_utf8_lossy(line);
+                if !execute(shared, &command, &mut reply) {
+                    return;
+                }
+            }
+            Ok(ReadLine::TooLong) => reply.push_str("ERROR\n"),
+            Ok(ReadLine::Pending) => continue,
+            Ok(ReadLine::Closed) => return,
+            Err(ref e) if is_timeout(e) => continue,
+            Err(_) => return,
+        }
+        if writer.write_all(reply.as_bytes()).is_err() {
+            return;
+        }
+        reply.clear();
+    }
+}
+
+/// Write the reply to a command to `out`, returning `false` to close the
+/// connection.
+fn execute(shared: &Shared, command: &str, out: &mut String) -> bool {
+    let mut words = command.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return true,
+    };
+    let aggregator = || shared.aggregator.lock().unwrap_or_else(|e| e.into_inner());
+    match name {
+        "help" => out.push_str(HELP),
+        "stats" => {
+            let stats = shared.stats();
+            let last_message = shared.last_message.load(Ordering::Relaxed);
+            let since_message =
+                (stats.uptime.as_millis() as u64).saturating_sub(last_message) / 1000;
+            let last_flush = stats
+                .last_flush
+                .and_then(|timestamp| timestamp.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |unix_time| unix_time.as_secs());
+            let flush_time = stats.last_flush_duration.unwrap_or_default().as_millis();
+            let _ = write!(
+                out,
+                "uptime: {}\n\
+                 messages.last_msg_seen: {}\n\
+                 messages.bad_lines_seen: {}\n\
+                 messages.packets_received: {}\n\
+                 messages.metrics_received: {}\n\
+                 flush.last_flush: {}\n\
+                 flush.flush_time: {}\n\
+                 flush.backend_errors: {}\n",
+                stats.uptime.as_secs(),
+                since_message,
+                stats.bad_lines_seen(),
+                stats.packets_received,
+                stats.metrics_received,
+                last_flush,
+                flush_time,
+                stats.backend_errors
+            );
+            out.push_str("END\n\n");
+        }
+        // Metrics are copied out of the aggregator, so that the lock isn't
+        // held while they are sorted and formatted.
+        "counters" => {
+            let counters = copy(aggregator().counters());
+            write_object(out, counters, |out, value| {
+                let _ = write!(out, "{}", Float(value));
+            })
+        }
+        "gauges" => {
+            let gauges = copy(aggregator().gauges());
+            write_object(out, gauges, |out, value| {
+                let _ = write!(out, "{}", Float(value));
+            })
+        }
+        "timers" => {
+            let timers = copy(
+                aggregator()
+                    .timers()
+                    .map(|(key, values)| (key, values.to_vec())),
+            );
+            write_object(out, timers, |out, values| {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    let _ = write!(out, "{}", Float(*value));
+                }
+                out.push(']');
+            })
+        }
+        "delcounters" | "delgauges" | "deltimers" => {
+            let mut aggregator = aggregator();
+            for pattern in words {
+                let deleted = match name {
+                    "delcounters" => aggregator.delete_counters(pattern),
+                    "delgauges" => aggregator.delete_gauges(pattern),
+                    _ => aggregator.delete_timers(pattern),
+                };
+                if deleted.is_empty() {
+                    let _ = writeln!(out, "metric {} not found", pattern);
+                }
+                for key in deleted {
+                    let _ = writeln!(out, "deleted: {}", key);
+                }
+            }
+            out.push_str("END\n\n");
+        }
+        "health" => {
+            match words.next() {
+                None => {}
+                Some("up") => shared.healthy.store(true, Ordering::Relaxed),
+                Some("down") => shared.healthy.store(false, Ordering::Relaxed),
+                Some(_) => {
+                    out.push_str("ERROR\n");
+                    return true;
+                }
+            }
+            let health = if shared.healthy.load(Ordering::Relaxed) {
+                "up"
+            } else {
+                "down"
+            };
+            let _ = writeln!(out, "health: {}", health);
+        }
+        "quit" => return false,
+        _ => out.push_str("ERROR\n"),
+    }
+    true
+}
+
+/// Copy metrics borrowed from the aggregator.
+fn copy<'a, I, T>(metrics: I) -> Vec<(Key, T)>
+where
+    I: Iterator<Item = (&'a Key, T)>,
+{
+    metrics.map(|(key, value)| (key.clone(), value)).collect()
+}
+
+/// Write metrics as a JSON object keyed by name, in the Graphite tag
+/// format, followed by `END`.
+fn write_object<T, F>(out: &mut String, mut metrics: Vec<(Key, T)>, mut write_value: F)
+where
+    F: FnMut(&mut String, T),
+{
+    metrics.sort_by(|a, b| a.0.cmp(&b.0));
+    if metrics.is_empty() {
+        out.push_str("{}\n");
+    } else {
+        out.push_str("{\n");
+        let last = metrics.len() - 1;
+        for (i, (key, value)) in metrics.into_iter().enumerate() {
+            out.push_str("  \"");
+            for c in key.to_string().chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    c if c.is_control() => {
+                        let _ = write!(out, "\\u{:04x}", c as u32);
+                    }
+                    c => out.push(c),
+                }
+            }
+            out.push_str("\": ");
+            write_value(out, value);
+            out.push_str(if i < last { ",\n" } else { "\n" });
+        }
+        out.push_str("}\n");
+    }
+    out.push_str("END\n\n");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Read;
+    use std::time::Duration;
+
+    use crate::server::Server;
+
+    /// Send commands, and read replies until the server closes the
+    /// connection after `quit`.
+    fn admin(address: std::net::SocketAddr, commands: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(commands.as_bytes()).unwrap();
+        stream.write_all(b"quit\n").unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        replies
+    }
+
+    #[test]
+    fn test_inspecting_metrics() {
+        let mut server = Server::new();
+        server.set_flush_interval(Duration::from_secs(60));
+        let address = server.bind_admin("127.0.0.1:0").unwrap();
+        let running = server.start().unwrap();
+        {
+            let mut aggregator = running.shared.aggregator.lock().unwrap();
+            aggregator.add_packet(b"b:1|c\na:2|c|#k:\"v\"\ng:0.5|g\nt:3|ms\nt:1|ms\nbad");
+        }
+
+        assert_eq!(
+            "{\n  \"a;k=\\\"v\\\"\": 2,\n  \"b\": 1\n}\nEND\n\n\
+             {\n  \"g\": 0.5\n}\nEND\n\n\
+             {\n  \"t\": [3, 1]\n}\nEND\n\n\
+             ERROR\n",
+            admin(address, "counters\ngauges\n\ntimers\nsets\n")
+        );
+        let stats = admin(address, "stats\n");
+        assert!(stats.starts_with("uptime: 0\nmessages.last_msg_seen: 0\n"));
+        assert!(stats.ends_with(
+            "messages.bad_lines_seen: 1\n\
+             messages.packets_received: 0\n\
+             messages.metrics_received: 5\n\
+             flush.last_flush: 0\n\
+             flush.flush_time: 0\n\
+             flush.backend_errors: 0\n\
+             END\n\n"
+        ));
+    }
+
+    #[test]
+    fn test_deleting_metrics() {
+        let mut server = Server::new();
+        let address = server.bind_admin("127.0.0.1:0").unwrap();
+        let running = server.start().unwrap();
+        running
+            .shared
+            .aggregator
+            .lock()
+            .unwrap()
+            .add_packet(b"a.x:1|c\na.y:1|c\nb:1|c\ng:1|g\nt:1|ms");
+
+        assert_eq!(
+            "deleted: a.x\ndeleted: a.y\nmetric c not found\nEND\n\n\
+             deleted: g\nEND\n\n\
+             deleted: t\nEND\n\n\
+             {\n  \"b\": 1\n}\nEND\n\n",
+            admin(
+                address,
+                "delcounters a.* c\ndelgauges g\ndeltimers t\ncounters\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_health() {
+        let mut server = Server::new();
+        let address = server.bind_admin("127.0.0.1:0").unwrap();
+        let running = server.start().unwrap();
+        assert_eq!(
+            "health: up\nhealth: down\nhealth: down\nERROR\n",
+            admin(address, "health\nhealth down\nhealth\nhealth sideways\n")
+        );
+        assert!(!running.is_healthy());
+        running.set_healthy(true);
+        assert_eq!("health: up\n", admin(address, "health\n"));
+        let long = format!("health {}\nhealth\n", "x".repeat(2 * MAX_COMMAND_SIZE));
+        assert_eq!("ERROR\nhealth: up\n", admin(address, &long));
+    }
+}
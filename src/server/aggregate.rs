@@ -0,0 +1,1218 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::mem;
+use std::time::{Duration, SystemTime};
+
+use super::Sketch;
+use crate::protocol::{self, Line, Metric, MetricType, ParseErrorKind, Value};
+
+/// Bins of the sketches summarizing timers, enough to cover values across
+/// 17 orders of magnitude with a 1% accuracy.
+const MAX_SKETCH_BINS: usize = 2048;
+
+/// Name and tags identifying an aggregated metric.
+///
+/// Names are sanitized the way Etsy statsd does it: whitespace is replaced
+/// with `_`, `/` with `-`, and characters other than letters, digits, `_`,
+/// `-` and `.` are removed. Tags are sorted by key, and tags without a
+/// value have an empty value.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key {
+    pub name: String,
+    pub tags: Vec<(String, String)>,
+}
+
+impl Key {
+    /// Create a key without tags.
+    pub fn new(name: &str) -> Key {
+        Key {
+            name: name.to_string(),
+            tags: Vec::new(),
+        }
+    }
+}
+
+/// Keys are displayed in the Graphite tag format, `name;key=value`.
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name)?;
+        for (key, value) in &self.tags {
+            write!(f, ";{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Aggregated counter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CounterStats {
+    /// Sum of the values received during the interval, scaled by their
+    /// sample rates.
+    pub value: f64,
+    /// Value per second.
+    pub rate: f64,
+}
+
+/// Statistics of the values of a timer within a percentile threshold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Percentile {
+    /// Threshold, in percents, e.g. `90.0` for the lowest 90% of the
+    /// values, or `-10.0` for the highest 10%.
+    pub threshold: f64,
+    /// Number of values within the threshold.
+    pub count: usize,
+    /// Largest value within a positive threshold, or smallest value within
+    /// a negative one.
+    pub boundary: f64,
+    pub mean: f64,
+    pub sum: f64,
+    pub sum_squares: f64,
+}
+
+/// Number of timer values in a histogram bin.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bin {
+    /// Exclusive upper bound of the bin, which starts at the bound of the
+    /// previous bin.
+    pub upper: f64,
+    pub count: usize,
+}
+
+/// Aggregated timer, histogram or distribution.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimerStats {
+    /// Values received during the interval, sorted, unless they were
+    /// summarized by a sketch.
+    pub values: Vec<f64>,
+    /// Sketch of the values, when there were more than the aggregator
+    /// keeps.
+    pub sketch: Option<Sketch>,
+    /// Number of values received, scaled by their sample rates.
+    pub count: f64,
+    /// Count per second.
+    pub count_ps: f64,
+    pub sum: f64,
+    pub sum_squares: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub min: f64,
+    pub max: f64,
+    /// Standard deviation.
+    pub std: f64,
+    pub percentiles: Vec<Percentile>,
+    /// Histogram bins, when configured for the timer.
+    pub histogram: Vec<Bin>,
+}
+
+impl TimerStats {
+    /// Compute the statistics of samples.
+    fn compute(
+        samples: Samples,
+        interval: Duration,
+        thresholds: &[f64],
+        bins: &[f64],
+    ) -> TimerStats {
+        let Samples {
+            mut values,
+            count: scaled_count,
+            sketch,
+        } = samples;
+        values.sort_by(f64::total_cmp);
+        let sorted = Sorted {
+            values: &values,
+            sketch: sketch.as_ref(),
+        };
+        let count = sorted.len();
+        if count == 0 {
+            return TimerStats::idle(scaled_count, interval);
+        }
+        let (sum, sum_squares, min, max, variance) = match sketch {
+            Some(ref sketch) => {
+                let mean = sketch.sum() / count as f64;
+                let variance = sketch.sum_squares() / count as f64 - mean * mean;
+                (
+                    sketch.sum(),
+                    sketch.sum_squares(),
+                    sketch.min(),
+                    sketch.max(),
+                    variance.max(0.0),
+                )
+            }
+            None => {
+                let sum: f64 = values.iter().sum();
+                let mean = sum / count as f64;
+                let variance = values
+                    .iter()
+                    .map(|value| (value - mean) * (value - mean))
+                    .sum::<f64>()
+                    / count as f64;
+                let sum_squares = values.iter().map(|value| value * value).sum();
+                (sum, sum_squares, values[0], values[count - 1], variance)
+            }
+        };
+        let mid = count / 2;
+        let median = if count % 2 == 1 {
+            sorted.value_at(mid + 1)
+        } else {
+            (sorted.value_at(mid) + sorted.value_at(mid + 1)) / 2.0
+        };
+        // Percentiles are computed the way Etsy statsd does it, including
+        // every value when there is only one.
+        let percentiles = thresholds
+            .iter()
+            .filter_map(|&threshold| {
+                let in_threshold = if count > 1 {
+                    ((threshold.abs() / 100.0 * count as f64).round() as usize).min(count)
+                } else {
+                    count
+                };
+                if in_threshold == 0 {
+                    return None;
+                }
+                let ((in_sum, in_sum_squares), boundary) = if threshold > 0.0 {
+                    let lowest = sums(sorted.iter(), in_threshold);
+                    (lowest, sorted.value_at(in_threshold))
+                } else {
+                    let highest = sums(sorted.iter().rev(), in_threshold);
+                    (highest, sorted.value_at(count - in_threshold + 1))
+                };
+                Some(Percentile {
+                    threshold,
+                    count: in_threshold,
+                    boundary,
+                    mean: in_sum / in_threshold as f64,
+                    sum: in_sum,
+                    sum_squares: in_sum_squares,
+                })
+            })
+            .collect();
+        let histogram = sorted.histogram(bins);
+        TimerStats {
+            count: scaled_count,
+            count_ps: scaled_count / interval.as_secs_f64(),
+            sum,
+            sum_squares,
+            mean: sum / count as f64,
+            median,
+            min,
+            max,
+            std: variance.sqrt(),
+            percentiles,
+            histogram,
+            values,
+            sketch,
+        }
+    }
+
+    /// Statistics of a timer without values, kept by its retention.
+    fn idle(count: f64, interval: Duration) -> TimerStats {
+        TimerStats {
+            values: Vec::new(),
+            sketch: None,
+            count,
+            count_ps: count / interval.as_secs_f64(),
+            sum: 0.0,
+            sum_squares: 0.0,
+            mean: f64::NAN,
+            median: f64::NAN,
+            min: f64::NAN,
+            max: f64::NAN,
+            std: f64::NAN,
+            percentiles: Vec::new(),
+            histogram: Vec::new(),
+        }
+    }
+
+    /// Whether no values were received during the interval, for idle
+    /// timers kept by their retention. Statistics other than the count and
+    /// sums are then NaN.
+    pub fn is_empty(&self) -> bool {
+        let sketched = self.sketch.as_ref().map_or(0, Sketch::count);
+        self.values.is_empty() && sketched == 0
+    }
+
+    /// Value at quantile `q`, between 0 and 1, with the nearest-rank
+    /// method. The value is approximate when the values were summarized by
+    /// a sketch.
+    pub fn quantile(&self, q: f64) -> f64 {
+        if let Some(ref sketch) = self.sketch {
+            return sketch.quantile(q);
+        }
+        if self.values.is_empty() {
+            return f64::NAN;
+        }
+        let rank = (q * self.values.len() as f64).ceil() as usize;
+        self.values[rank.clamp(1, self.values.len()) - 1]
+    }
+}
+
+/// Values of a timer received since the last flush.
+#[derive(Default)]
+struct Samples {
+    values: Vec<f64>,
+    /// Number of values, scaled by their sample rates.
+    count: f64,
+    /// Values summarized once there were too many to keep.
+    sketch: Option<Sketch>,
+}
+
+/// Sorted values of a timer, kept exactly or summarized by a sketch.
+struct Sorted<'a> {
+    values: &'a [f64],
+    sketch: Option<&'a Sketch>,
+}
+
+impl Sorted<'_> {
+    fn len(&self) -> usize {
+        self.values.len() + self.sketch.map_or(0, |sketch| sketch.count() as usize)
+    }
+
+    /// Values with how many times they were received, in ascending order.
+    fn iter(&self) -> impl DoubleEndedIterator<Item = (f64, u64)> + '_ {
+        let sketched = self.sketch.into_iter().flat_map(Sketch::bins);
+        self.values.iter().map(|value| (*value, 1)).chain(sketched)
+    }
+
+    /// Value with `rank`, from 1 for the smallest value.
+    fn value_at(&self, rank: usize) -> f64 {
+        match self.sketch {
+            Some(sketch) => sketch.value_at(rank as u64),
+            None => self.values[rank - 1],
+        }
+    }
+
+    /// Count the values in bins with sorted upper bounds. Values above the
+    /// last bound aren't counted.
+    fn histogram(&self, bounds: &[f64]) -> Vec<Bin> {
+        let mut bins: Vec<_> = bounds
+            .iter()
+            .map(|&upper| Bin { upper, count: 0 })
+            .collect();
+        if bins.is_empty() {
+            return bins;
+        }
+        for (value, count) in self.iter() {
+            let index = bins.partition_point(|bin| bin.upper <= value);
+            if let Some(bin) = bins.get_mut(index) {
+                bin.count += count as usize;
+            }
+        }
+        bins
+    }
+}
+
+/// Metrics aggregated over a flush interval, handed to backends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    /// Time of the flush.
+    pub timestamp: SystemTime,
+    /// Flush interval, used to compute rates.
+    pub interval: Duration,
+    pub counters: BTreeMap<Key, CounterStats>,
+    /// Gauges keep their value across flushes.
+    pub gauges: BTreeMap<Key, f64>,
+    /// Number of distinct members of each set.
+    pub sets: BTreeMap<Key, usize>,
+    /// Timers, histograms and distributions.
+    pub timers: BTreeMap<Key, TimerStats>,
+}
+
+/// How long metrics which aren't updated keep being part of snapshots.
+///
+/// Idle counters, sets and timers are reported with zero counts, and idle
+/// gauges with their last value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+    /// Only report metrics updated since the last flush, like Etsy statsd
+    /// with `deleteIdleStats`.
+    Delete,
+    /// Keep reporting metrics for this many flushes after their last
+    /// update, then delete them.
+    Flushes(u32),
+    /// Keep reporting metrics forever, like Etsy statsd without
+    /// `deleteIdleStats`.
+    Keep,
+}
+
+impl Retention {
+    /// Whether a metric idle for `idle` flushes is kept.
+    pub(crate) fn keeps(self, idle: u32) -> bool {
+        match self {
+            Retention::Delete => idle == 0,
+            Retention::Flushes(flushes) => idle <= flushes,
+            Retention::Keep => true,
+        }
+    }
+}
+
+/// Aggregates metrics between flushes, the way Etsy statsd does.
+///
+/// * Counters are summed, and divided by their sample rate.
+/// * Gauges keep their last value, and `+` or `-` values change it.
+/// * Sets count their distinct members.
+/// * Timers, histograms and distributions keep every value to compute
+///   statistics, unless sketching is enabled, and count their values
+///   divided by their sample rate.
+/// * Key/values are handled as gauges.
+///
+/// Counters, sets and timers are reset on each flush. By default, only
+/// the counters, sets and timers received since the last flush are part of
+/// a snapshot, and gauges are kept forever, which retention policies
+/// change per type.
+pub struct Aggregator {
+    percentiles: Vec<f64>,
+    counters: Metrics<f64>,
+    gauges: Metrics<f64>,
+    sets: Metrics<HashSet<String>>,
+    timers: Metrics<Samples>,
+    counter_retention: Retention,
+    gauge_retention: Retention,
+    set_retention: Retention,
+    timer_retention: Retention,
+    max_metrics: Option<usize>,
+    /// Values dropped since the aggregator was created, and at the last
+    /// flush, because of `max_metrics`.
+    overflowed: u64,
+    overflowed_at_flush: u64,
+    /// Names matched by timers, and the bounds of their histogram bins.
+    histograms: Vec<(String, Vec<f64>)>,
+    /// Number of values of a timer kept before sketching them, and the
+    /// relative accuracy of the sketch.
+    sketching: Option<(usize, f64)>,
+    /// Metrics added, including those dropped because of `max_metrics`.
+    metrics: u64,
+    bad_lines: HashMap<ParseErrorKind, u64>,
+    /// Identity of the metric being added, reused to avoid allocating.
+    scratch: String,
+}
+
+impl Aggregator {
+    pub fn new() -> Aggregator {
+        Aggregator {
+            percentiles: vec![90.0],
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            sets: HashMap::new(),
+            timers: HashMap::new(),
+            counter_retention: Retention::Delete,
+            gauge_retention: Retention::Keep,
+            set_retention: Retention::Delete,
+            timer_retention: Retention::Delete,
+            max_metrics: None,
+            overflowed: 0,
+            overflowed_at_flush: 0,
+            histograms: Vec::new(),
+            sketching: None,
+            metrics: 0,
+            bad_lines: HashMap::new(),
+            scratch: String::new(),
+        }
+    }
+
+    /// Set the percentile thresholds computed for timers, `[90.0]` by
+    /// default. Negative thresholds select the highest values, e.g.
+    /// `-10.0` for the top 10%.
+    pub fn set_percentiles(&mut self, percentiles: &[f64]) {
+        self.percentiles = percentiles.to_vec();
+    }
+
+    /// Count the values of timers whose name contains `metric` in bins
+    /// with these upper bounds, like the Etsy statsd `histogram` setting.
+    ///
+    /// The first matching `metric` applies, and an empty one matches every
+    /// timer. A bin bounded by `f64::INFINITY` counts the values above the
+    /// other bins.
+    pub fn add_histogram(&mut self, metric: &str, bins: &[f64]) {
+        let mut bins = bins.to_vec();
+        bins.sort_by(f64::total_cmp);
+        self.histograms.push((metric.to_string(), bins));
+    }
+
+    /// Summarize the values of a timer in a sketch once there are more
+    /// than `max_values` of them in a flush interval, bounding memory.
+    ///
+    /// The median, percentiles and histograms of sketched timers are within
+    /// `relative_accuracy`, e.g. `0.01` for 1%, of the exact values. Their
+    /// count, sum, mean, minimum, maximum and standard deviation stay exact.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `relative_accuracy` isn't between 0 and 1, exclusive.
+    pub fn set_sketching(&mut self, max_values: usize, relative_accuracy: f64) {
+        assert!(
+            relative_accuracy > 0.0 && relative_accuracy < 1.0,
+            "The relative accuracy must be between 0 and 1"
+        );
+        self.sketching = Some((max_values, relative_accuracy));
+    }
+
+    /// Set how long idle counters are reported, `Retention::Delete` by
+    /// default. Idle counters are reported as zeros.
+    pub fn set_counter_retention(&mut self, retention: Retention) {
+        self.counter_retention = retention;
+    }
+
+    /// Set how long idle gauges are reported, `Retention::Keep` by default.
+    pub fn set_gauge_retention(&mut self, retention: Retention) {
+        self.gauge_retention = retention;
+    }
+
+    /// Set how long idle sets are reported, `Retention::Delete` by default.
+    pub fn set_set_retention(&mut self, retention: Retention) {
+        self.set_retention = retention;
+    }
+
+    /// Set how long idle timers are reported, `Retention::Delete` by
+    /// default. Idle timers have a zero count, and NaN statistics.
+    pub fn set_timer_retention(&mut self, retention: Retention) {
+        self.timer_retention = retention;
+    }
+
+    /// Track at most `max_metrics` metrics, of all types, so that a flood
+    /// of new names can't exhaust memory.
+    ///
+    /// Values of new metrics over the limit are dropped, and counted by
+    /// the `statsd.cardinality_overflow` counter of every snapshot. Idle
+    /// metrics kept by retention policies count towards the limit.
+    pub fn set_max_metrics(&mut self, max_metrics: usize) {
+        self.max_metrics = Some(max_metrics);
+    }
+
+    /// Add the metrics of a packet. Events, service checks and invalid
+    /// lines are skipped.
+    pub fn add_packet(&mut self, packet: &[u8]) {
+        for line in protocol::parse_packet(packet) {
+            match line {
+                Ok(Line::Metric(metric)) => self.add(&metric),
+                Ok(_) => {}
+                Err(error) => *self.bad_lines.entry(error.kind()).or_insert(0) += 1,
+            }
+        }
+    }
+
+    /// Count a line which couldn't be parsed.
+    pub(crate) fn add_bad_line(&mut self, kind: ParseErrorKind) {
+        *self.bad_lines.entry(kind).or_insert(0) += 1;
+    }
+
+    /// Add a metric.
+    pub fn add(&mut self, metric: &Metric) {
+        self.metrics += 1;
+        let mut scratch = mem::take(&mut self.scratch);
+        write_identity(metric, &mut scratch);
+        let rate = metric.sample_rate.unwrap_or(1.0);
+        let full = self.max_metrics.is_some_and(|max| self.tracked() >= max);
+        for value in metric.values() {
+            match (metric.metric_type, value) {
+                (MetricType::Counter, Value::Number(value)) => {
+                    match entry(&mut self.counters, &scratch, metric, full, || 0.0) {
+                        Some(counter) => *counter += value / rate,
+                        None => self.overflowed += 1,
+                    }
+                }
+                (MetricType::Gauge, Value::Delta(delta)) => {
+                    match entry(&mut self.gauges, &scratch, metric, full, || 0.0) {
+                        Some(gauge) => *gauge += delta,
+                        None => self.overflowed += 1,
+                    }
+                }
+                (MetricType::Gauge, Value::Number(value))
+                | (MetricType::KeyValue, Value::Number(value)) => {
+                    match entry(&mut self.gauges, &scratch, metric, full, || 0.0) {
+                        Some(gauge) => *gauge = value,
+                        None => self.overflowed += 1,
+                    }
+                }
+                (MetricType::Set, Value::Member(member)) => {
+                    match entry(&mut self.sets, &scratch, metric, full, HashSet::new) {
+                        Some(members) if !members.contains(member) => {
+                            members.insert(member.to_string());
+                        }
+                        Some(_) => {}
+                        None => self.overflowed += 1,
+                    }
+                }
+                (MetricType::Timer, Value::Number(value))
+                | (MetricType::Histogram, Value::Number(value))
+                | (MetricType::Distribution, Value::Number(value)) => {
+                    let samples =
+                        match entry(&mut self.timers, &scratch, metric, full, Samples::default) {
+                            Some(samples) => samples,
+                            None => {
+                                self.overflowed += 1;
+                                continue;
+                            }
+                        };
+                    samples.count += 1.0 / rate;
+                    if let Some(ref mut sketch) = samples.sketch {
+                        sketch.add(value);
+                        continue;
+                    }
+                    samples.values.push(value);
+                    match self.sketching {
+                        Some((max_values, accuracy)) if samples.values.len() > max_values => {
+                            let mut sketch = Sketch::new(accuracy, MAX_SKETCH_BINS);
+                            for value in mem::take(&mut samples.values) {
+                                sketch.add(value);
+                            }
+                            samples.sketch = Some(sketch);
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.scratch = scratch;
+    }
+
+    /// Number of invalid lines skipped since the aggregator was created.
+    pub fn bad_lines(&self) -> u64 {
+        self.bad_lines.values().sum()
+    }
+
+    /// Number of invalid lines skipped since the aggregator was created,
+    /// by what is wrong with them. Kinds without invalid lines are left
+    /// out.
+    pub fn bad_lines_by_kind(&self) -> impl Iterator<Item = (ParseErrorKind, u64)> + '_ {
+        self.bad_lines.iter().map(|(kind, count)| (*kind, *count))
+    }
+
+    /// Number of metrics added since the aggregator was created, including
+    /// the values dropped because of the limit set by `set_max_metrics()`.
+    pub fn metrics_received(&self) -> u64 {
+        self.metrics
+    }
+
+    /// Number of values dropped because of the limit set by
+    /// `set_max_metrics()`, since the aggregator was created.
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed
+    }
+
+    /// Number of metrics tracked, of all types.
+    fn tracked(&self) -> usize {
+        self.counters.len() + self.gauges.len() + self.sets.len() + self.timers.len()
+    }
+
+    /// Counters, with their sums since the last flush.
+    pub fn counters(&self) -> impl Iterator<Item = (&Key, f64)> {
+        self.counters
+            .values()
+            .map(|tracked| (&tracked.key, tracked.value))
+    }
+
+    /// Gauges, with their values.
+    pub fn gauges(&self) -> impl Iterator<Item = (&Key, f64)> {
+        self.gauges
+            .values()
+            .map(|tracked| (&tracked.key, tracked.value))
+    }
+
+    /// Timers, with their values since the last flush in the order they
+    /// were received. Sketched values are left out.
+    pub fn timers(&self) -> impl Iterator<Item = (&Key, &[f64])> {
+        self.timers
+            .values()
+            .map(|tracked| (&tracked.key, tracked.value.values.as_slice()))
+    }
+
+    /// Delete the counters matching `pattern`, returning their keys.
+    ///
+    /// Patterns match keys displayed in the Graphite tag format, like
+    /// `name;key=value`, exactly, or by prefix when they end with `*`.
+    pub fn delete_counters(&mut self, pattern: &str) -> Vec<Key> {
+        delete(&mut self.counters, pattern)
+    }
+
+    /// Delete the gauges matching `pattern`, returning their keys.
+    pub fn delete_gauges(&mut self, pattern: &str) -> Vec<Key> {
+        delete(&mut self.gauges, pattern)
+    }
+
+    /// Delete the timers matching `pattern`, returning their keys.
+    pub fn delete_timers(&mut self, pattern: &str) -> Vec<Key> {
+        delete(&mut self.timers, pattern)
+    }
+
+    /// Compute a snapshot of the metrics received since the last flush,
+    /// and of the idle metrics kept by retention policies, and reset
+    /// counters, sets and timers.
+    pub fn flush(&mut self, interval: Duration) -> Snapshot {
+        self.drain().snapshot(interval)
+    }
+
+    /// Take the metrics received since the last flush, leaving the
+    /// statistics to be computed without borrowing the aggregator.
+    pub(crate) fn drain(&mut self) -> Drained {
+        let overflowed = self.max_metrics.map(|_| {
+            let overflowed = self.overflowed - self.overflowed_at_flush;
+            self.overflowed_at_flush = self.overflowed;
+            overflowed
+        });
+        Drained {
+            timestamp: SystemTime::now(),
+            percentiles: self.percentiles.clone(),
+            histograms: self.histograms.clone(),
+            counters: take(&mut self.counters, self.counter_retention, mem::take),
+            gauges: take(&mut self.gauges, self.gauge_retention, |gauge| *gauge),
+            sets: take(&mut self.sets, self.set_retention, mem::take),
+            timers: take(&mut self.timers, self.timer_retention, mem::take),
+            overflowed,
+        }
+    }
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Metrics taken from an aggregator by a flush.
+pub(crate) struct Drained {
+    timestamp: SystemTime,
+    percentiles: Vec<f64>,
+    histograms: Vec<(String, Vec<f64>)>,
+    counters: Vec<(Key, f64)>,
+    gauges: Vec<(Key, f64)>,
+    sets: Vec<(Key, HashSet<String>)>,
+    timers: Vec<(Key, Samples)>,
+    /// Values dropped because of the cardinality limit, when there is one.
+    overflowed: Option<u64>,
+}
+
+impl Drained {
+    pub fn snapshot(self, interval: Duration) -> Snapshot {
+        let seconds = interval.as_secs_f64();
+        let percentiles = self.percentiles;
+        let histograms = self.histograms;
+        let overflow = self
+            .overflowed
+            .map(|overflowed| (Key::new("statsd.cardinality_overflow"), overflowed as f64));
+        Snapshot {
+            timestamp: self.timestamp,
+            interval,
+            counters: self
+                .counters
+                .into_iter()
+                .chain(overflow)
+                .map(|(key, value)| {
+                    let rate = value / seconds;
+                    (key, CounterStats { value, rate })
+                })
+                .collect(),
+            gauges: self.gauges.into_iter().collect(),
+            sets: self
+                .sets
+                .into_iter()
+                .map(|(key, members)| (key, members.len()))
+                .collect(),
+            timers: self
+                .timers
+                .into_iter()
+                .map(|(key, samples)| {
+                    let bins = histograms
+                        .iter()
+                        .find(|(metric, _)| key.name.contains(metric.as_str()))
+                        .map_or(&[][..], |(_, bins)| bins);
+                    let stats = TimerStats::compute(samples, interval, &percentiles, bins);
+                    (key, stats)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Sum, and sum of squares, of the first `n` values.
+fn sums<I: Iterator<Item = (f64, u64)>>(values: I, n: usize) -> (f64, f64) {
+    let mut remaining = n as u64;
+    let (mut sum, mut sum_squares) = (0.0, 0.0);
+    for (value, count) in values {
+        if remaining == 0 {
+            break;
+        }
+        let taken = count.min(remaining);
+        remaining -= taken;
+        sum += value * taken as f64;
+        sum_squares += value * value * taken as f64;
+    }
+    (sum, sum_squares)
+}
+
+/// Aggregated metrics of a type, by identity.
+type Metrics<T> = HashMap<String, Tracked<T>>;
+
+/// An aggregated metric.
+struct Tracked<T> {
+    key: Key,
+    value: T,
+    /// Number of flushes since the metric was last updated.
+    idle: u32,
+}
+
+/// Get the value of a metric to update it, inserting `default()` the
+/// first time the metric is seen, unless the aggregator is `full`.
+fn entry<'a, T>(
+    map: &'a mut Metrics<T>,
+    identity: &str,
+    metric: &Metric,
+    full: bool,
+    default: impl FnOnce() -> T,
+) -> Option<&'a mut T> {
+    // Look up with a borrowed identity first, so known metrics don't
+    // allocate.
+    if map.contains_key(identity) {
+        let tracked = map.get_mut(identity).unwrap();
+        tracked.idle = 0;
+        return Some(&mut tracked.value);
+    }
+    if full {
+        return None;
+    }
+    let tracked = map.entry(identity.to_string()).or_insert_with(|| Tracked {
+        key: key(metric),
+        value: default(),
+        idle: 0,
+    });
+    Some(&mut tracked.value)
+}
+
+/// Take the values of the metrics to report at a flush, leaving
+/// `reset(value)` in their place, and forget the metrics idle for longer
+/// than `retention`.
+fn take<T, F>(map: &mut Metrics<T>, retention: Retention, mut reset: F) -> Vec<(Key, T)>
+where
+    F: FnMut(&mut T) -> T,
+{
+    if retention == Retention::Delete {
+        // Metrics are moved rather than copied.
+        return map
+            .drain()
+            .filter(|(_, tracked)| tracked.idle == 0)
+            .map(|(_, tracked)| (tracked.key, tracked.value))
+            .collect();
+    }
+    let mut taken = Vec::with_capacity(map.len());
+    map.retain(|_, tracked| {
+        if retention.keeps(tracked.idle) {
+            taken.push((tracked.key.clone(), reset(&mut tracked.value)));
+        }
+        tracked.idle = tracked.idle.saturating_add(1);
+        retention.keeps(tracked.idle)
+    });
+    taken
+}
+
+/// Remove the metrics with a key matching `pattern`, sorted by key.
+fn delete<T>(map: &mut Metrics<T>, pattern: &str) -> Vec<Key> {
+    let matches = |key: &Key| match pattern.strip_suffix('*') {
+        Some(prefix) => key.to_string().starts_with(prefix),
+        None => key.to_string() == pattern,
+    };
+    let mut deleted = Vec::new();
+    map.retain(|_, tracked| {
+        if matches(&tracked.key) {
+            deleted.push(tracked.key.clone());
+            return false;
+        }
+        true
+    });
+    deleted.sort();
+    deleted
+}
+
+fn key(metric: &Metric) -> Key {
+    let mut name = String::with_capacity(metric.name.len());
+    write_name(metric.name, &mut name);
+    let mut tags: Vec<(String, String)> = metric
+        .tags
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.unwrap_or("").to_string()))
+        .collect();
+    tags.sort();
+    Key { name, tags }
+}
+
+/// Write a string identifying the key of a metric.
+fn write_identity(metric: &Metric, buf: &mut String) {
+    buf.clear();
+    write_name(metric.name, buf);
+    let mut tags: Vec<_> = metric.tags.iter().collect();
+    if tags.is_empty() {
+        return;
+    }
+    tags.sort_unstable();
+    for (key, value) in tags {
+        let _ = write!(buf, "|{}:{}", key, value.unwrap_or(""));
+    }
+}
+
+/// Write a name sanitized the way Etsy statsd does it.
+fn write_name(name: &str, buf: &mut String) {
+    let mut whitespace = false;
+    for c in name.chars() {
+        if c.is_whitespace() {
+            if !whitespace {
+                buf.push('_');
+            }
+            whitespace = true;
+            continue;
+        }
+        whitespace = false;
+        match c {
+            '/' => buf.push('-'),
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' => buf.push(c),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn aggregate(packet: &str) -> Snapshot {
+        let mut aggregator = Aggregator::new();
+        aggregator.add_packet(packet.as_bytes());
+        aggregator.flush(Duration::from_secs(10))
+    }
+
+    #[test]
+    fn test_counters() {
+        let snapshot = aggregate("a:1|c\na:2|c|@0.5\nb:3|c|#y:2,x:1\nb:1|c|#x:1,y:2\nb:1|c");
+        let b = Key {
+            name: "b".to_string(),
+            tags: vec![
+                ("x".to_string(), "1".to_string()),
+                ("y".to_string(), "2".to_string()),
+            ],
+        };
+        assert_eq!(3, snapshot.counters.len());
+        assert_eq!(
+            CounterStats {
+                value: 5.0,
+                rate: 0.5
+            },
+            snapshot.counters[&Key::new("a")]
+        );
+        assert_eq!(4.0, snapshot.counters[&b].value);
+        assert_eq!(1.0, snapshot.counters[&Key::new("b")].value);
+    }
+
+    #[test]
+    fn test_gauges_and_sets() {
+        let mut aggregator = Aggregator::new();
+        aggregator.add_packet(b"g:10|g\ng:+5|g\ng:-2|g\nkv:3|kv\ns:a|s\ns:b|s\ns:a|s");
+        let snapshot = aggregator.flush(Duration::from_secs(10));
+        assert_eq!(13.0, snapshot.gauges[&Key::new("g")]);
+        assert_eq!(3.0, snapshot.gauges[&Key::new("kv")]);
+        assert_eq!(2, snapshot.sets[&Key::new("s")]);
+
+        aggregator.add_packet(b"g:-3|g");
+        let snapshot = aggregator.flush(Duration::from_secs(10));
+        assert_eq!(10.0, snapshot.gauges[&Key::new("g")]);
+        assert_eq!(3.0, snapshot.gauges[&Key::new("kv")]);
+        assert!(snapshot.sets.is_empty());
+    }
+
+    #[test]
+    fn test_counting_lines() {
+        let mut aggregator = Aggregator::new();
+        aggregator.add_packet(b"a:1|c\nb:x|c\nc:1|q\nt:1:2|ms\n_e{1,1}:a|b\nd:2|c");
+        aggregator.add_packet(b"e:1|c|@2");
+        assert_eq!(3, aggregator.metrics_received());
+        assert_eq!(3, aggregator.bad_lines());
+        let mut bad_lines: Vec<_> = aggregator
+            .bad_lines_by_kind()
+            .map(|(kind, count)| (kind.name(), count))
+            .collect();
+        bad_lines.sort();
+        assert_eq!(
+            vec![
+                ("invalid_sample_rate", 1),
+                ("invalid_value", 1),
+                ("unknown_type", 1)
+            ],
+            bad_lines
+        );
+    }
+
+    #[test]
+    fn test_inspecting_and_deleting() {
+        let mut aggregator = Aggregator::new();
+        aggregator.add_packet(b"a.x:1|c\na.y:2|c|#k:v\nb:3|c\ng:1|g\nt:2|ms\nt:1|ms\nbad");
+        assert_eq!(1, aggregator.bad_lines());
+        let mut counters: Vec<_> = aggregator.counters().collect();
+        counters.sort_by(|a, b| a.0.cmp(b.0));
+        assert_eq!(3, counters.len());
+        assert_eq!((&Key::new("b"), 3.0), counters[2]);
+        assert_eq!(
+            vec![(&Key::new("t"), &[2.0, 1.0][..])],
+            aggregator.timers().collect::<Vec<_>>()
+        );
+
+        let deleted: Vec<_> = aggregator
+            .delete_counters("a.*")
+            .iter()
+            .map(Key::to_string)
+            .collect();
+        assert_eq!(vec!["a.x", "a.y;k=v"], deleted);
+        assert!(aggregator.delete_counters("b.*").is_empty());
+        assert_eq!(vec![Key::new("b")], aggregator.delete_counters("b"));
+        assert_eq!(vec![Key::new("g")], aggregator.delete_gauges("g"));
+        assert_eq!(vec![Key::new("t")], aggregator.delete_timers("*"));
+        let snapshot = aggregator.flush(Duration::from_secs(10));
+        assert!(snapshot.counters.is_empty() && snapshot.gauges.is_empty());
+    }
+
+    #[test]
+    fn test_timers() {
+        let mut packet = String::new();
+        for value in (1..=10).rev() {
+            packet.push_str(&format!("t:{}|ms\n", value));
+        }
+        packet.push_str("h:4:2|h\nd:1|d");
+        let snapshot = aggregate(&packet);
+
+        let timer = &snapshot.timers[&Key::new("t")];
+        assert_eq!(10.0, timer.count);
+        assert_eq!(1.0, timer.count_ps);
+        assert_eq!(55.0, timer.sum);
+        assert_eq!(385.0, timer.sum_squares);
+        assert_eq!(5.5, timer.mean);
+        assert_eq!(5.5, timer.median);
+        assert_eq!(1.0, timer.min);
+        assert_eq!(10.0, timer.max);
+        assert!((timer.std - 8.25f64.sqrt()).abs() < 1e-12);
+        assert_eq!(
+            vec![Percentile {
+                threshold: 90.0,
+                count: 9,
+                boundary: 9.0,
+                mean: 5.0,
+                sum: 45.0,
+                sum_squares: 285.0,
+            }],
+            timer.percentiles
+        );
+        assert!(timer.histogram.is_empty());
+
+        let histogram = &snapshot.timers[&Key::new("h")];
+        assert_eq!(vec![2.0, 4.0], histogram.values);
+        assert_eq!(3.0, histogram.median);
+        assert_eq!(1.0, snapshot.timers[&Key::new("d")].percentiles[0].boundary);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut aggregator = Aggregator::new();
+        aggregator.set_percentiles(&[50.0, 99.0, 1.0]);
+        for value in 1..=100 {
+            aggregator.add_packet(format!("t:{}|ms", value).as_bytes());
+        }
+        aggregator.add_packet(b"single:7|ms");
+        let snapshot = aggregator.flush(Duration::from_secs(1));
+        let thresholds = |name: &str| -> Vec<(f64, f64)> {
+            snapshot.timers[&Key::new(name)]
+                .percentiles
+                .iter()
+                .map(|percentile| (percentile.threshold, percentile.boundary))
+                .collect()
+        };
+        assert_eq!(
+            vec![(50.0, 50.0), (99.0, 99.0), (1.0, 1.0)],
+            thresholds("t")
+        );
+        assert_eq!(
+            vec![(50.0, 7.0), (99.0, 7.0), (1.0, 7.0)],
+            thresholds("single")
+        );
+    }
+
+    #[test]
+    fn test_top_percentiles() {
+        let mut aggregator = Aggregator::new();
+        aggregator.set_percentiles(&[-10.0, -0.1]);
+        for value in 1..=20 {
+            aggregator.add_packet(format!("t:{}|ms", value).as_bytes());
+        }
+        let snapshot = aggregator.flush(Duration::from_secs(1));
+        assert_eq!(
+            vec![Percentile {
+                threshold: -10.0,
+                count: 2,
+                boundary: 19.0,
+                mean: 19.5,
+                sum: 39.0,
+                sum_squares: 761.0,
+            }],
+            snapshot.timers[&Key::new("t")].percentiles
+        );
+    }
+
+    #[test]
+    fn test_sample_rates() {
+        let snapshot = aggregate("t:10|ms|@0.1\nt:20|ms|@0.5\nt:30|ms");
+        let timer = &snapshot.timers[&Key::new("t")];
+        assert_eq!(13.0, timer.count);
+        assert_eq!(1.3, timer.count_ps);
+        assert_eq!(20.0, timer.mean);
+        assert_eq!(20.0, timer.quantile(0.5));
+    }
+
+    #[test]
+    fn test_histograms() {
+        let mut aggregator = Aggregator::new();
+        aggregator.add_histogram("api.", &[f64::INFINITY, 10.0, 100.0]);
+        aggregator.add_histogram("", &[50.0]);
+        aggregator.add_packet(b"api.latency:5:10:50:500|ms\ndb:20:60|ms");
+        let snapshot = aggregator.flush(Duration::from_secs(1));
+        let bins = |name| -> Vec<(f64, usize)> {
+            snapshot.timers[&Key::new(name)]
+                .histogram
+                .iter()
+                .map(|bin| (bin.upper, bin.count))
+                .collect()
+        };
+        assert_eq!(
+            vec![(10.0, 1), (100.0, 2), (f64::INFINITY, 1)],
+            bins("api.latency")
+        );
+        // Values above the last bin aren't counted.
+        assert_eq!(vec![(50.0, 1)], bins("db"));
+    }
+
+    #[test]
+    fn test_sketching() {
+        let mut aggregator = Aggregator::new();
+        aggregator.set_sketching(100, 0.01);
+        aggregator.set_percentiles(&[90.0, -10.0]);
+        aggregator.add_histogram("", &[500.0, f64::INFINITY]);
+        for value in 1..=1000 {
+            aggregator.add_packet(format!("t:{}|ms", value).as_bytes());
+        }
+        aggregator.add_packet(b"small:1:2|ms");
+        let snapshot = aggregator.flush(Duration::from_secs(1));
+        assert!(snapshot.timers[&Key::new("small")].sketch.is_none());
+
+        let timer = &snapshot.timers[&Key::new("t")];
+        assert!(timer.values.is_empty());
+        assert_eq!(1000, timer.sketch.as_ref().unwrap().count());
+        assert_eq!(1000.0, timer.count);
+        assert_eq!(500_500.0, timer.sum);
+        assert_eq!(500.5, timer.mean);
+        assert_eq!((1.0, 1000.0), (timer.min, timer.max));
+        assert!((timer.std - 288.6749902572095).abs() < 1e-6);
+        let close = |approximate: f64, exact: f64| (approximate - exact).abs() <= exact * 0.01;
+        assert!(close(timer.median, 500.5));
+        assert!(close(timer.quantile(0.99), 990.0));
+        let (upper, lower) = (&timer.percentiles[0], &timer.percentiles[1]);
+        assert_eq!((900, 100), (upper.count, lower.count));
+        assert!(close(upper.boundary, 900.0) && close(upper.mean, 450.5));
+        assert!(close(lower.boundary, 901.0) && close(lower.mean, 950.5));
+        let counts: Vec<_> = timer.histogram.iter().map(|bin| bin.count).collect();
+        assert_eq!(1000, counts.iter().sum::<usize>());
+        assert!(counts[0].abs_diff(499) <= 5);
+    }
+
+    #[test]
+    fn test_retention() {
+        let mut aggregator = Aggregator::new();
+        aggregator.set_counter_retention(Retention::Keep);
+        aggregator.set_gauge_retention(Retention::Delete);
+        aggregator.set_set_retention(Retention::Flushes(1));
+        aggregator.set_timer_retention(Retention::Flushes(2));
+        aggregator.add_packet(b"c:2|c\ng:1|g\ns:a|s\nt:5|ms");
+        let interval = Duration::from_secs(1);
+        let snapshot = aggregator.flush(interval);
+        assert_eq!(2.0, snapshot.counters[&Key::new("c")].value);
+        assert_eq!(1.0, snapshot.gauges[&Key::new("g")]);
+        assert!(!snapshot.timers[&Key::new("t")].is_empty());
+
+        let snapshot = aggregator.flush(interval);
+        assert_eq!(0.0, snapshot.counters[&Key::new("c")].value);
+        assert!(snapshot.gauges.is_empty());
+        assert_eq!(0, snapshot.sets[&Key::new("s")]);
+        let timer = &snapshot.timers[&Key::new("t")];
+        assert!(timer.is_empty());
+        assert_eq!((0.0, 0.0), (timer.count, timer.count_ps));
+        assert!(timer.mean.is_nan() && timer.percentiles.is_empty());
+
+        let snapshot = aggregator.flush(interval);
+        assert!(snapshot.sets.is_empty());
+        assert!(snapshot.timers[&Key::new("t")].is_empty());
+        // Updates reset the retention.
+        aggregator.add_packet(b"s:b|s\ng:+3|g");
+        let snapshot = aggregator.flush(interval);
+        assert_eq!(1, snapshot.sets[&Key::new("s")]);
+        assert_eq!(3.0, snapshot.gauges[&Key::new("g")]);
+        assert!(snapshot.timers.is_empty());
+
+        let snapshot = aggregator.flush(interval);
+        assert_eq!(
+            vec![&Key::new("c")],
+            snapshot.counters.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![&Key::new("s")],
+            snapshot.sets.keys().collect::<Vec<_>>()
+        );
+        // The set was reported for the last time.
+        assert_eq!(1, aggregator.tracked());
+    }
+
+    #[test]
+    fn test_limiting_cardinality() {
+        let mut aggregator = Aggregator::new();
+        aggregator.set_max_metrics(2);
+        aggregator.add_packet(b"a:1|c\nb:1|g\nc:1|c\nd:1:2|ms\na:1|c\ns:x|s");
+        assert_eq!(4, aggregator.overflowed());
+        let snapshot = aggregator.flush(Duration::from_secs(1));
+        assert_eq!(2.0, snapshot.counters[&Key::new("a")].value);
+        assert_eq!(
+            4.0,
+            snapshot.counters[&Key::new("statsd.cardinality_overflow")].value
+        );
+        assert!(snapshot.timers.is_empty() && snapshot.sets.is_empty());
+
+        // The counter was deleted at the flush, leaving room for another
+        // metric, and the gauge is kept.
+        aggregator.add_packet(b"c:1|c\ne:1|c\nb:2|g");
+        let snapshot = aggregator.flush(Duration::from_secs(1));
+        assert_eq!(1.0, snapshot.counters[&Key::new("c")].value);
+        assert_eq!(2.0, snapshot.gauges[&Key::new("b")]);
+        assert_eq!(
+            1.0,
+            snapshot.counters[&Key::new("statsd.cardinality_overflow")].value
+        );
+        assert_eq!(5, aggregator.overflowed());
+    }
+
+    #[test]
+    fn test_displaying_keys() {
+        let key = Key {
+            name: "a.b".to_string(),
+            tags: vec![("env".to_string(), "prod".to_string())],
+        };
+        assert_eq!("a.b;env=prod", key.to_string());
+        assert_eq!("a.b", Key::new("a.b").to_string());
+    }
+
+    #[test]
+    fn test_resetting_on_flush() {
+        let mut aggregator = Aggregator::new();
+        aggregator.add_packet(b"c:1|c\nt:1|ms");
+        aggregator.flush(Duration::from_secs(1));
+        let snapshot = aggregator.flush(Duration::from_secs(1));
+        assert!(snapshot.counters.is_empty());
+        assert!(snapshot.timers.is_empty());
+    }
+
+    #[test]
+    fn test_sanitizing_names() {
+        let snapshot = aggregate("my app/requests  total!:1|c\n_e{1,1}:a|b\nbad line");
+        let names: Vec<_> = snapshot.counters.keys().map(|key| &key.name).collect();
+        assert_eq!(vec!["my_app-requests_total"], names);
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::{self, Write};
+use std::time::UNIX_EPOCH;
+
+use super::{Backend, Percentile, Snapshot};
+use crate::format::Float;
+
+/// Backend printing aggregated metrics, one per line.
+///
+/// ```text
+/// flush 1700000000
+/// counter myapp.requests;method=GET value=4 rate=0.4
+/// gauge myapp.load value=0.5
+/// set myapp.users count=3
+/// timer myapp.latency count=2 count_ps=0.2 sum=20 mean=10 median=10 min=8 max=12 std=2 count_90=2 mean_90=10 upper_90=12 sum_90=20 sum_squares_90=208
+/// ```
+///
+/// Metrics are sorted by name within each type. Tags are printed in the
+/// Graphite tag format.
+pub struct ConsoleBackend<W = io::Stdout> {
+    writer: W,
+}
+
+impl ConsoleBackend {
+    /// Create a backend printing to the standard output.
+    pub fn new() -> ConsoleBackend {
+        ConsoleBackend {
+            writer: io::stdout(),
+        }
+    }
+}
+
+impl Default for ConsoleBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> ConsoleBackend<W> {
+    /// Create a backend printing to `writer`.
+    pub fn with_writer(writer: W) -> ConsoleBackend<W> {
+        ConsoleBackend { writer }
+    }
+
+    /// Get the writer, e.g. to check what was printed in tests.
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    fn write(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        let timestamp = snapshot
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let mut out = io::BufWriter::new(&mut self.writer);
+        writeln!(out, "flush {}", timestamp)?;
+        for (key, counter) in &snapshot.counters {
+            writeln!(
+                out,
+                "counter {} value={} rate={}",
+                key,
+                Float(counter.value),
+                Float(counter.rate)
+            )?;
+        }
+        for (key, value) in &snapshot.gauges {
+            writeln!(out, "gauge {} value={}", key, Float(*value))?;
+        }
+        for (key, count) in &snapshot.sets {
+            writeln!(out, "set {} count={}", key, count)?;
+        }
+        for (key, timer) in &snapshot.timers {
+            if timer.is_empty() {
+                writeln!(
+                    out,
+                    "timer {} count={} count_ps={}",
+                    key,
+                    Float(timer.count),
+                    Float(timer.count_ps)
+                )?;
+                continue;
+            }
+            write!(
+                out,
+                "timer {} count={} count_ps={} sum={} mean={} median={} min={} max={} std={}",
+                key,
+                Float(timer.count),
+                Float(timer.count_ps),
+                Float(timer.sum),
+                Float(timer.mean),
+                Float(timer.median),
+                Float(timer.min),
+                Float(timer.max),
+                Float(timer.std),
+            )?;
+            for percentile in &timer.percentiles {
+                for (name, value) in &percentile_stats(percentile) {
+                    write!(out, " {}={}", name, Float(*value))?;
+                }
+            }
+            for bin in &timer.histogram {
+                write!(out, " bin_{}={}", threshold_name(bin.upper), bin.count)?;
+            }
+            writeln!(out)?;
+        }
+        out.flush()
+    }
+}
+
+impl<W: Write + Send> Backend for ConsoleBackend<W> {
+    fn flush(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        self.write(snapshot)
+    }
+}
+
+/// Name of a percentile threshold or histogram bin in metric names, e.g.
+/// `99_9` for `99.9`, `top10` for `-10`, or `inf`.
+pub(crate) fn threshold_name(threshold: f64) -> String {
+    Float(threshold)
+        .to_string()
+        .replace('.', "_")
+        .replace('-', "top")
+}
+
+/// Statistics of a percentile, named the way Etsy statsd names them, e.g.
+/// `mean_90` or `lower_top10`.
+pub(crate) fn percentile_stats(percentile: &Percentile) -> [(String, f64); 5] {
+    let threshold = threshold_name(percentile.threshold);
+    let boundary = if percentile.threshold > 0.0 {
+        "upper"
+    } else {
+        "lower"
+    };
+    [
+        (format!("count_{}", threshold), percentile.count as f64),
+        (format!("mean_{}", threshold), percentile.mean),
+        (format!("{}_{}", boundary, threshold), percentile.boundary),
+        (format!("sum_{}", threshold), percentile.sum),
+        (format!("sum_squares_{}", threshold), percentile.sum_squares),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::{Duration, SystemTime};
+
+    use crate::server::Aggregator;
+
+    #[test]
+    fn test_printing_snapshots() {
+        let mut aggregator = Aggregator::new();
+        aggregator.set_percentiles(&[90.0, -50.0]);
+        aggregator.add_histogram("t", &[10.0, f64::INFINITY]);
+        aggregator.add_packet(b"b:4|c|#method:GET\na:1|c\nload:0.5|g\nusers:x|s\nt:8|ms\nt:12|ms");
+        let mut snapshot = aggregator.flush(Duration::from_secs(10));
+        snapshot.timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+
+        let mut backend = ConsoleBackend::with_writer(Vec::new());
+        backend.flush(&snapshot).unwrap();
+        assert_eq!(
+            "flush 1700000000\n\
+             counter a value=1 rate=0.1\n\
+             counter b;method=GET value=4 rate=0.4\n\
+             gauge load value=0.5\n\
+             set users count=1\n\
+             timer t count=2 count_ps=0.2 sum=20 mean=10 median=10 min=8 max=12 std=2 \
+             count_90=2 mean_90=10 upper_90=12 sum_90=20 sum_squares_90=208 \
+             count_top50=1 mean_top50=12 lower_top50=12 sum_top50=12 sum_squares_top50=144 \
+             bin_10=1 bin_inf=1\n",
+            String::from_utf8(backend.writer().clone()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::console::{percentile_stats, threshold_name};
+use super::{Backend, Key, Snapshot};
+use crate::format::Float;
+
+/// Backend sending aggregated metrics to carbon, in the Graphite plaintext
+/// protocol.
+///
+/// Metrics are named the way the Etsy statsd Graphite backend names them.
+/// With the legacy namespace, which is the default:
+///
+/// ```text
+/// stats.<counter> <rate>
+/// stats_counts.<counter> <value>
+/// stats.gauges.<gauge> <value>
+/// stats.sets.<set>.count <count>
+/// stats.timers.<timer>.<statistic> <value>
+/// stats.timers.<timer>.histogram.bin_<bin> <count>
+/// ```
+///
+/// Without it, counters are sent as `stats.counters.<counter>.rate` and
+/// `stats.counters.<counter>.count`. Tags are sent in the Graphite tag
+/// format, `name;key=value`.
+///
+/// The connection is opened on the first flush, and reopened on the next
+/// flush after an error. Metrics which couldn't be sent are kept until
+/// they can be sent, up to a maximum buffer size, after which the oldest
+/// flushes are dropped. The latest flush is always kept, even when it is
+/// larger than the maximum buffer size.
+pub struct GraphiteBackend {
+    addresses: Vec<SocketAddr>,
+    stream: Option<TcpStream>,
+    legacy_namespace: bool,
+    timeout: Duration,
+    max_buffer_size: usize,
+    /// Flushes waiting to be sent, oldest first.
+    pending: VecDeque<Vec<u8>>,
+    pending_size: usize,
+    flushes_dropped: u64,
+}
+
+impl GraphiteBackend {
+    /// Create a backend sending metrics to carbon at `address`, usually on
+    /// port 2003.
+    pub fn new<A: ToSocketAddrs>(address: A) -> io::Result<GraphiteBackend> {
+        let addresses: Vec<_> = address.to_socket_addrs()?.collect();
+        if addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The address doesn't resolve to any socket address",
+            ));
+        }
+        Ok(GraphiteBackend {
+            addresses,
+            stream: None,
+            legacy_namespace: true,
+            timeout: Duration::from_secs(2),
+            max_buffer_size: 8 * 1024 * 1024,
+            pending: VecDeque::new(),
+            pending_size: 0,
+            flushes_dropped: 0,
+        })
+    }
+
+    /// Set whether counters are sent under `stats.` and `stats_counts.`,
+    /// `true` by default, or under `stats.counters.`.
+    pub fn set_legacy_namespace(&mut self, legacy_namespace: bool) {
+        self.legacy_namespace = legacy_namespace;
+    }
+
+    /// Set the timeout of connecting and sending, 2 seconds by default.
+    ///
+    /// Backends are called one after the other, so this also bounds how
+    /// long an unresponsive carbon delays the next backends.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set how many bytes of metrics are kept while carbon can't be
+    /// reached, 8 MiB by default.
+    pub fn set_max_buffer_size(&mut self, max_buffer_size: usize) {
+        self.max_buffer_size = max_buffer_size;
+    }
+
+    /// Number of bytes of metrics waiting to be sent.
+    pub fn buffered(&self) -> usize {
+        self.pending_size
+    }
+
+    /// Number of flushes dropped to keep the buffer under its maximum
+    /// size.
+    pub fn flushes_dropped(&self) -> u64 {
+        self.flushes_dropped
+    }
+
+    fn format(&self, snapshot: &Snapshot) -> Vec<u8> {
+        let timestamp = snapshot
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let mut out = Lines {
+            buf: Vec::new(),
+            timestamp,
+        };
+        for (key, counter) in &snapshot.counters {
+            if self.legacy_namespace {
+                out.push("stats.", key, "", counter.rate);
+                out.push("stats_counts.", key, "", counter.value);
+            } else {
+                out.push("stats.counters.", key, ".rate", counter.rate);
+                out.push("stats.counters.", key, ".count", counter.value);
+            }
+        }
+        for (key, value) in &snapshot.gauges {
+            out.push("stats.gauges.", key, "", *value);
+        }
+        for (key, count) in &snapshot.sets {
+            out.push("stats.sets.", key, ".count", *count as f64);
+        }
+        for (key, timer) in &snapshot.timers {
+            if timer.is_empty() {
+                // Like Etsy statsd, idle timers only have counts.
+                out.push_timer(key, "count", timer.count);
+                out.push_timer(key, "count_ps", timer.count_ps);
+                continue;
+            }
+            let stats = [
+                ("count", timer.count),
+                ("count_ps", timer.count_ps),
+                ("sum", timer.sum),
+                ("sum_squares", timer.sum_squares),
+                ("mean", timer.mean),
+                ("median", timer.median),
+                ("lower", timer.min),
+                ("upper", timer.max),
+                ("std", timer.std),
+            ];
+            for (name, value) in stats.iter() {
+                out.push_timer(key, name, *value);
+            }
+            for percentile in &timer.percentiles {
+                for (name, value) in &percentile_stats(percentile) {
+                    out.push_timer(key, name, *value);
+                }
+            }
+            for bin in &timer.histogram {
+                let name = format!("histogram.bin_{}", threshold_name(bin.upper));
+                out.push_timer(key, &name, bin.count as f64);
+            }
+        }
+        out.buf
+    }
+
+    /// Queue the lines of a flush, dropping older flushes to make room.
+    fn enqueue(&mut self, lines: Vec<u8>) {
+        while self.pending_size + lines.len() > self.max_buffer_size {
+            match self.pending.pop_front() {
+                Some(dropped) => {
+                    self.pending_size -= dropped.len();
+                    self.flushes_dropped += 1;
+                }
+                None => break,
+            }
+        }
+        self.pending_size += lines.len();
+        self.pending.push_back(lines);
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut error = None;
+        for address in &self.addresses {
+            match TcpStream::connect_timeout(address, self.timeout) {
+                Ok(stream) => {
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable)))
+    }
+
+    /// Send the pending flushes, oldest first.
+    fn send(&mut self) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.stream = Some(self.connect()?);
+        }
+        let stream = self.stream.as_mut().unwrap();
+        while let Some(lines) = self.pending.front() {
+            // A flush that was partly sent is sent again entirely, which
+            // is better than a line split over two connections.
+            if let Err(e) = stream.write_all(lines) {
+                self.stream = None;
+                return Err(e);
+            }
+            self.pending_size -= lines.len();
+            self.pending.pop_front();
+        }
+        Ok(())
+    }
+}
+
+impl Backend for GraphiteBackend {
+    fn flush(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        let lines = self.format(snapshot);
+        if !lines.is_empty() {
+            self.enqueue(lines);
+        }
+        // Metrics that couldn't be sent are sent on the next flush.
+        self.send()
+    }
+}
+
+/// Graphite plaintext lines of a flush.
+struct Lines {
+    buf: Vec<u8>,
+    timestamp: u64,
+}
+
+impl Lines {
+    /// Add a `<prefix><name><suffix>[;tags] <value> <timestamp>` line.
+    fn push(&mut self, prefix: &str, key: &Key, suffix: &str, value: f64) {
+        let _ = write!(self.buf, "{}{}{}", prefix, key.name, suffix);
+        for (key, value) in &key.tags {
+            let _ = write!(self.buf, ";{}={}", key, value);
+        }
+        let _ = writeln!(self.buf, " {} {}", Float(value), self.timestamp);
+    }
+
+    fn push_timer(&mut self, key: &Key, statistic: &str, value: f64) {
+        self.push("stats.timers.", key, &format!(".{}", statistic), value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::SystemTime;
+
+    use crate::server::{Aggregator, Retention};
+
+    fn snapshot(packet: &[u8]) -> Snapshot {
+        let mut aggregator = Aggregator::new();
+        aggregator.add_packet(packet);
+        let mut snapshot = aggregator.flush(Duration::from_secs(10));
+        snapshot.timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+        snapshot
+    }
+
+    fn format(backend: &GraphiteBackend, snapshot: &Snapshot) -> String {
+        String::from_utf8(backend.format(snapshot)).unwrap()
+    }
+
+    /// Read from the first connection until `len` bytes were received.
+    fn receive(listener: &TcpListener, len: usize) -> String {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_legacy_namespace() {
+        let backend = GraphiteBackend::new("127.0.0.1:2003").unwrap();
+        let snapshot = snapshot(b"hits:4|c|#route:home\nload:0.5|g\nusers:x|s\nt:8|ms\nt:12|ms");
+        assert_eq!(
+            "stats.hits;route=home 0.4 1700000000\n\
+             stats_counts.hits;route=home 4 1700000000\n\
+             stats.gauges.load 0.5 1700000000\n\
+             stats.sets.users.count 1 1700000000\n\
+             stats.timers.t.count 2 1700000000\n\
+             stats.timers.t.count_ps 0.2 1700000000\n\
+             stats.timers.t.sum 20 1700000000\n\
+             stats.timers.t.sum_squares 208 1700000000\n\
+             stats.timers.t.mean 10 1700000000\n\
+             stats.timers.t.median 10 1700000000\n\
+             stats.timers.t.lower 8 1700000000\n\
+             stats.timers.t.upper 12 1700000000\n\
+             stats.timers.t.std 2 1700000000\n\
+             stats.timers.t.count_90 2 1700000000\n\
+             stats.timers.t.mean_90 10 1700000000\n\
+             stats.timers.t.upper_90 12 1700000000\n\
+             stats.timers.t.sum_90 20 1700000000\n\
+             stats.timers.t.sum_squares_90 208 1700000000\n",
+            format(&backend, &snapshot)
+        );
+    }
+
+    #[test]
+    fn test_counters_namespace() {
+        let mut backend = GraphiteBackend::new("127.0.0.1:2003").unwrap();
+        backend.set_legacy_namespace(false);
+        assert_eq!(
+            "stats.counters.hits.rate 0.4 1700000000\n\
+             stats.counters.hits.count 4 1700000000\n",
+            format(&backend, &snapshot(b"hits:4|c"))
+        );
+    }
+
+    #[test]
+    fn test_histograms() {
+        let backend = GraphiteBackend::new("127.0.0.1:2003").unwrap();
+        let mut aggregator = Aggregator::new();
+        aggregator.set_percentiles(&[]);
+        aggregator.add_histogram("", &[0.5, f64::INFINITY]);
+        aggregator.add_packet(b"t:1|ms");
+        let mut snapshot = aggregator.flush(Duration::from_secs(10));
+        snapshot.timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+        let lines = format(&backend, &snapshot);
+        assert!(lines.ends_with(
+            "stats.timers.t.std 0 1700000000\n\
+             stats.timers.t.histogram.bin_0_5 0 1700000000\n\
+             stats.timers.t.histogram.bin_inf 1 1700000000\n"
+        ));
+    }
+
+    #[test]
+    fn test_idle_timers() {
+        let backend = GraphiteBackend::new("127.0.0.1:2003").unwrap();
+        let mut aggregator = Aggregator::new();
+        aggregator.set_timer_retention(Retention::Keep);
+        aggregator.add_packet(b"t:1|ms");
+        aggregator.flush(Duration::from_secs(10));
+        let mut snapshot = aggregator.flush(Duration::from_secs(10));
+        snapshot.timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+        assert_eq!(
+            "stats.timers.t.count 0 1700000000\n\
+             stats.timers.t.count_ps 0 1700000000\n",
+            format(&backend, &snapshot)
+        );
+    }
+
+    #[test]
+    fn test_sending() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut backend = GraphiteBackend::new(listener.local_addr().unwrap()).unwrap();
+        backend.flush(&snapshot(b"a:1|g")).unwrap();
+        backend.flush(&snapshot(b"b:2|g")).unwrap();
+        assert_eq!(0, backend.buffered());
+        assert_eq!(
+            "stats.gauges.a 1 1700000000\nstats.gauges.b 2 1700000000\n",
+            receive(&listener, 56)
+        );
+    }
+
+    #[test]
+    fn test_buffering_until_carbon_is_up() {
+        // Find a free port, where nothing listens.
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut backend = GraphiteBackend::new(address).unwrap();
+        backend.set_max_buffer_size(56);
+        assert!(backend.flush(&snapshot(b"a:1|g")).is_err());
+        assert!(backend.flush(&snapshot(b"b:2|g")).is_err());
+        assert_eq!(56, backend.buffered());
+        // The oldest flush is dropped to keep the buffer under its maximum
+        // size.
+        assert!(backend.flush(&snapshot(b"c:3|g")).is_err());
+        assert_eq!(56, backend.buffered());
+        assert_eq!(1, backend.flushes_dropped());
+
+        let listener = TcpListener::bind(address).unwrap();
+        backend.flush(&snapshot(b"d:4|g")).unwrap();
+        assert_eq!(0, backend.buffered());
+        assert_eq!(
+            "stats.gauges.c 3 1700000000\nstats.gauges.d 4 1700000000\n",
+            receive(&listener, 56)
+        );
+    }
+
+    #[test]
+    fn test_keeping_flushes_larger_than_the_buffer() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut backend = GraphiteBackend::new(address).unwrap();
+        backend.set_max_buffer_size(10);
+        assert!(backend.flush(&snapshot(b"a:1|g")).is_err());
+        assert_eq!(28, backend.buffered());
+        assert_eq!(0, backend.flushes_dropped());
+        assert!(backend.flush(&snapshot(b"b:2|g")).is_err());
+        assert_eq!(28, backend.buffered());
+        assert_eq!(1, backend.flushes_dropped());
+
+        let listener = TcpListener::bind(address).unwrap();
+        backend.flush(&snapshot(b"")).unwrap();
+        assert_eq!("stats.gauges.b 2 1700000000\n", receive(&listener, 28));
+    }
+
+    #[test]
+    fn test_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut backend = GraphiteBackend::new(listener.local_addr().unwrap()).unwrap();
+        backend.flush(&snapshot(b"a:1|g")).unwrap();
+        assert_eq!("stats.gauges.a 1 1700000000\n", receive(&listener, 28));
+
+        // The first connection was closed by carbon. Writes may still
+        // succeed until the connection is reset, and flushes sent by then
+        // are lost, but the backend eventually reconnects.
+        listener.set_nonblocking(true).unwrap();
+        for _ in 0..100 {
+            let _ = backend.flush(&snapshot(b"b:2|g"));
+            if let Ok((mut stream, _)) = listener.accept() {
+                stream.set_nonblocking(false).unwrap();
+                let mut buf = [0; 28];
+                stream.read_exact(&mut buf).unwrap();
+                assert_eq!(b"stats.gauges.b 2 1700000000\n", &buf);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("The backend didn't reconnect");
+    }
+}
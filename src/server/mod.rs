@@ -0,0 +1,1121 @@
+//! An embeddable statsd server.
+//!
+//! The server receives metrics over UDP, TCP or Unix datagram sockets,
+//! aggregates them the way Etsy statsd does, and hands the aggregated
+//! metrics to backends at every flush interval:
+//!
+//! ```
+//! use statsd::server::{Server, Snapshot};
+//! use std::time::Duration;
+//!
+//! let mut server = Server::new();
+//! let address = server.bind_udp("127.0.0.1:0").unwrap();
+//! server.set_flush_interval(Duration::from_secs(10));
+//! server.add_backend(|snapshot: &Snapshot| {
+//!     for (key, counter) in &snapshot.counters {
+//!         println!("{} {}/s", key.name, counter.rate);
+//!     }
+//! });
+//! let running = server.start().unwrap();
+//!
+//! let client = statsd::Client::new(address, "myapp").unwrap();
+//! client.incr("requests");
+//!
+//! // Metrics received since the last flush are flushed on shutdown.
+//! running.shutdown();
+//! ```
+//!
+//! The server reports on itself with internal metrics added to every
+//! snapshot under `statsd.`, and with `RunningServer::stats()`. An admin
+//! interface compatible with the Etsy statsd management port can be
+//! enabled with `Server::bind_admin()`.
+//!
+//! A `Proxy` relays the metrics it receives to upstream servers instead of
+//! aggregating them.
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+mod admin;
+mod aggregate;
+mod console;
+mod graphite;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod proxy;
+mod sketch;
+
+pub use self::aggregate::{
+    Aggregator, Bin, CounterStats, Key, Percentile, Retention, Snapshot, TimerStats,
+};
+pub use self::console::ConsoleBackend;
+pub use self::graphite::GraphiteBackend;
+#[cfg(feature = "prometheus")]
+pub use self::prometheus::{Mapping, PrometheusBackend, PrometheusHandle, TimerType};
+pub use self::proxy::{Proxy, Routing, RunningProxy};
+pub use self::sketch::Sketch;
+
+use crate::protocol::ParseErrorKind;
+
+/// How often blocked threads check whether the server is shutting down.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Largest UDP payload.
+const MAX_PACKET_SIZE: usize = 65_535;
+
+/// Longest line read from TCP connections, the largest UDP payload over
+/// IPv4. Longer lines are dropped.
+const MAX_LINE_SIZE: usize = 65_507;
+
+/// Most TCP connections served at once by a listener. Further
+/// connections are closed right away.
+const MAX_CONNECTIONS: usize = 1024;
+
+/// Destination of the metrics aggregated by a server.
+///
+/// Backends are called from the flushing thread, one after the other, so
+/// a slow backend delays the next ones. Closures taking a `&Snapshot` are
+/// backends that never fail.
+pub trait Backend: Send {
+    /// Handle the metrics aggregated over a flush interval.
+    ///
+    /// Errors are counted by the `statsd.backend_errors` internal metric,
+    /// and the backend is still called at the next flush.
+    fn flush(&mut self, snapshot: &Snapshot) -> io::Result<()>;
+}
+
+impl<F: FnMut(&Snapshot) + Send> Backend for F {
+    fn flush(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        self(snapshot);
+        Ok(())
+    }
+}
+
+/// A statsd server, configured before being started.
+pub struct Server {
+    sockets: Sockets,
+    admin: Vec<TcpListener>,
+    flush_interval: Duration,
+    /// Aggregator configured before being started.
+    aggregator: Aggregator,
+    internal_metrics: bool,
+    backends: Vec<Box<dyn Backend>>,
+}
+
+impl Server {
+    /// Create a server flushing every 10 seconds, without sockets or
+    /// backends.
+    pub fn new() -> Server {
+        Server {
+            sockets: Sockets::default(),
+            admin: Vec::new(),
+            flush_interval: Duration::from_secs(10),
+            aggregator: Aggregator::new(),
+            internal_metrics: true,
+            backends: Vec::new(),
+        }
+    }
+
+    /// Receive metrics on a UDP socket bound to `address`. The bound
+    /// address is returned, to find the port when binding to port 0.
+    pub fn bind_udp<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<SocketAddr> {
+        self.sockets.bind_udp(address)
+    }
+
+    /// Receive metrics on TCP connections to `address`, one metric per
+    /// line. The bound address is returned.
+    pub fn bind_tcp<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<SocketAddr> {
+        self.sockets.bind_tcp(address)
+    }
+
+    /// Receive metrics on a Unix datagram socket created at `path`.
+    ///
+    /// A socket left at `path` by a previous server is replaced. The
+    /// socket is removed when the server shuts down.
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.sockets.bind_unix(path.as_ref())
+    }
+
+    /// Serve the admin interface on TCP connections to `address`, usually
+    /// on port 8126. The bound address is returned.
+    ///
+    /// Like the Etsy statsd management port, the interface answers one
+    /// command per line:
+    ///
+    /// - `stats`: the uptime, seconds since the last message, and the
+    ///   statistics of `RunningServer::stats()`.
+    /// - `counters`, `gauges`, `timers`: the metrics not flushed yet, as a
+    ///   JSON object.
+    /// - `delcounters`, `delgauges`, `deltimers` followed by names: delete
+    ///   metrics, by prefix for names ending with `*`.
+    /// - `health`, `health up`, `health down`: get or set the health
+    ///   reported to load balancers.
+    /// - `help`, `quit`.
+    pub fn bind_admin<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        self.admin.push(listener);
+        Ok(address)
+    }
+
+    /// Set how often aggregated metrics are handed to backends.
+    pub fn set_flush_interval(&mut self, flush_interval: Duration) {
+        self.flush_interval = flush_interval;
+    }
+
+    /// Set the percentile thresholds computed for timers, `[90.0]` by
+    /// default. See `Aggregator::set_percentiles()`.
+    pub fn set_percentiles(&mut self, percentiles: &[f64]) {
+        self.aggregator.set_percentiles(percentiles);
+    }
+
+    /// Count the values of timers whose name contains `metric` in
+    /// histogram bins. See `Aggregator::add_histogram()`.
+    pub fn add_histogram(&mut self, metric: &str, bins: &[f64]) {
+        self.aggregator.add_histogram(metric, bins);
+    }
+
+    /// Summarize the values of timers in sketches beyond `max_values` per
+    /// flush interval. See `Aggregator::set_sketching()`.
+    pub fn set_sketching(&mut self, max_values: usize, relative_accuracy: f64) {
+        self.aggregator.set_sketching(max_values, relative_accuracy);
+    }
+
+    /// Set how long idle counters are reported. See
+    /// `Aggregator::set_counter_retention()`.
+    pub fn set_counter_retention(&mut self, retention: Retention) {
+        self.aggregator.set_counter_retention(retention);
+    }
+
+    /// Set how long idle gauges are reported, forever by default.
+    pub fn set_gauge_retention(&mut self, retention: Retention) {
+        self.aggregator.set_gauge_retention(retention);
+    }
+
+    /// Set how long idle sets are reported.
+    pub fn set_set_retention(&mut self, retention: Retention) {
+        self.aggregator.set_set_retention(retention);
+    }
+
+    /// Set how long idle timers are reported.
+    pub fn set_timer_retention(&mut self, retention: Retention) {
+        self.aggregator.set_timer_retention(retention);
+    }
+
+    /// Track at most `max_metrics` metrics. See
+    /// `Aggregator::set_max_metrics()`.
+    pub fn set_max_metrics(&mut self, max_metrics: usize) {
+        self.aggregator.set_max_metrics(max_metrics);
+    }
+
+    /// Set whether the internal metrics of the server are added to every
+    /// snapshot, which they are by default:
+    ///
+    /// - `statsd.packets_received`, `statsd.metrics_received`: counters of
+    ///   the datagrams and TCP lines, and the metrics in them.
+    /// - `statsd.bad_lines_seen`: counter of invalid lines, also tagged
+    ///   with the `kind` of error, e.g. `invalid_value`.
+    /// - `statsd.backend_errors`: counter of the errors returned by
+    ///   backends since the previous snapshot.
+    /// - `statsd.last_flush`, `statsd.flush_duration`: gauges of the Unix
+    ///   time of the previous flush, and of how long it took with its
+    ///   backends, in milliseconds.
+    ///
+    /// The `statsd.cardinality_overflow` counter of `set_max_metrics()` is
+    /// added regardless.
+    pub fn set_internal_metrics(&mut self, internal_metrics: bool) {
+        self.internal_metrics = internal_metrics;
+    }
+
+    /// Add a backend receiving the aggregated metrics at each flush.
+    pub fn add_backend<B: Backend + 'static>(&mut self, backend: B) {
+        self.backends.push(Box::new(backend));
+    }
+
+    /// Start receiving metrics, and flushing them every flush interval.
+    ///
+    /// Each socket is served by its own thread, as is each TCP connection.
+    pub fn start(self) -> io::Result<RunningServer> {
+        let shared = Arc::new(Shared {
+            aggregator: Mutex::new(self.aggregator),
+            stop: AtomicBool::new(false),
+            packets: AtomicU64::new(0),
+            started: Instant::now(),
+            last_message: AtomicU64::new(0),
+            healthy: AtomicBool::new(true),
+            flushes: Mutex::new(Flushes::default()),
+        });
+        let mut running = RunningServer {
+            shared: Arc::clone(&shared),
+            commands: None,
+            flusher: None,
+            receivers: Receivers::default(),
+        };
+        self.sockets.serve(&shared, &mut running.receivers)?;
+        for listener in self.admin {
+            let shared = Arc::clone(&shared);
+            running
+                .receivers
+                .threads
+                .push(spawn("statsd-admin", move || {
+                    accept_connections(&shared, listener, admin::serve)
+                })?);
+        }
+
+        let (commands, received) = channel();
+        let flusher = Flusher {
+            shared,
+            interval: self.flush_interval,
+            internal_metrics: self.internal_metrics,
+            backends: self.backends,
+            reported: Totals::default(),
+            backend_errors: 0,
+        };
+        running.flusher = Some(spawn("statsd-flush", move || flusher.run(received))?);
+        running.commands = Some(commands);
+        Ok(running)
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A started server.
+///
+/// The server shuts down when `shutdown()` is called or when it is dropped.
+pub struct RunningServer {
+    shared: Arc<Shared>,
+    commands: Option<Sender<Command>>,
+    flusher: Option<JoinHandle<()>>,
+    receivers: Receivers,
+}
+
+impl RunningServer {
+    /// Flush the metrics received so far, waiting for the backends.
+    pub fn flush(&self) {
+        if let Some(ref commands) = self.commands {
+            let (done, wait) = channel();
+            if commands.send(Command::Flush(done)).is_ok() {
+                let _ = wait.recv();
+            }
+        }
+    }
+
+    /// Number of datagrams and TCP lines received since the server started.
+    pub fn packets_received(&self) -> u64 {
+        self.shared.packets.load(Ordering::Relaxed)
+    }
+
+    /// Statistics of the server since it started.
+    pub fn stats(&self) -> ServerStats {
+        self.shared.stats()
+    }
+
+    /// Whether the server reports being healthy to the `health` admin
+    /// command, `true` unless set otherwise.
+    pub fn is_healthy(&self) -> bool {
+        self.shared.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Set whether the server reports being healthy, for instance to be
+    /// taken out of a load balancer before shutting down.
+    pub fn set_healthy(&self, healthy: bool) {
+        self.shared.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    /// Stop receiving metrics, and flush the metrics received since the
+    /// last flush.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        self.receivers.join();
+        // The flusher flushes one last time once the channel is closed.
+        drop(self.commands.take());
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// State shared by the threads of a server.
+struct Shared {
+    aggregator: Mutex<Aggregator>,
+    stop: AtomicBool,
+    packets: AtomicU64,
+    started: Instant,
+    /// Milliseconds from `started` to the last packet.
+    last_message: AtomicU64,
+    healthy: AtomicBool,
+    flushes: Mutex<Flushes>,
+}
+
+impl Shared {
+    fn stats(&self) -> ServerStats {
+        let (metrics_received, bad_lines, metrics_overflowed) = {
+            let aggregator = self.aggregator.lock().unwrap_or_else(|e| e.into_inner());
+            (
+                aggregator.metrics_received(),
+                aggregator.bad_lines_by_kind().collect(),
+                aggregator.overflowed(),
+            )
+        };
+        let flushes = self.flushes.lock().unwrap_or_else(|e| e.into_inner());
+        ServerStats {
+            uptime: self.started.elapsed(),
+            packets_received: self.packets.load(Ordering::Relaxed),
+            metrics_received,
+            bad_lines,
+            metrics_overflowed,
+            flushes: flushes.count,
+            backend_errors: flushes.backend_errors,
+            last_flush: flushes.last.map(|(timestamp, _)| timestamp),
+            last_flush_duration: flushes.last.map(|(_, duration)| duration),
+        }
+    }
+}
+
+impl Handler for Shared {
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    fn handle(&self, packet: &[u8]) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last_message.store(elapsed, Ordering::Relaxed);
+        let mut aggregator = self.aggregator.lock().unwrap_or_else(|e| e.into_inner());
+        aggregator.add_packet(packet);
+    }
+
+    fn handle_too_long(&self) {
+        let mut aggregator = self.aggregator.lock().unwrap_or_else(|e| e.into_inner());
+        aggregator.add_bad_line(ParseErrorKind::TooLong);
+    }
+}
+
+/// Statistics of a running server, returned by `RunningServer::stats()`.
+///
+/// Counts are totals since the server started, while the internal metrics
+/// added to snapshots count what happened over a flush interval.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerStats {
+    /// Time since the server started.
+    pub uptime: Duration,
+    /// Number of datagrams and TCP lines received.
+    pub packets_received: u64,
+    /// Number of metrics received, including those dropped because of
+    /// `Server::set_max_metrics()`.
+    pub metrics_received: u64,
+    /// Number of invalid lines, by what is wrong with them.
+    pub bad_lines: HashMap<ParseErrorKind, u64>,
+    /// Number of values dropped because of `Server::set_max_metrics()`.
+    pub metrics_overflowed: u64,
+    /// Number of flushes.
+    pub flushes: u64,
+    /// Number of errors returned by backends.
+    pub backend_errors: u64,
+    /// Time of the last flush.
+    pub last_flush: Option<SystemTime>,
+    /// How long the last flush took, with its backends.
+    pub last_flush_duration: Option<Duration>,
+}
+
+impl ServerStats {
+    /// Number of invalid lines, of all kinds.
+    pub fn bad_lines_seen(&self) -> u64 {
+        self.bad_lines.values().sum()
+    }
+}
+
+/// Flushes done by the flusher, for `ServerStats`.
+#[derive(Default)]
+struct Flushes {
+    count: u64,
+    backend_errors: u64,
+    /// Time of the last flush, and how long it took.
+    last: Option<(SystemTime, Duration)>,
+}
+
+/// Totals reported by the internal metrics of the previous snapshot.
+#[derive(Default)]
+struct Totals {
+    packets: u64,
+    metrics: u64,
+    bad_lines: HashMap<ParseErrorKind, u64>,
+}
+
+enum Command {
+    /// Flush now, and signal when done.
+    Flush(Sender<()>),
+}
+
+struct Flusher {
+    shared: Arc<Shared>,
+    interval: Duration,
+    internal_metrics: bool,
+    backends: Vec<Box<dyn Backend>>,
+    reported: Totals,
+    /// Errors returned by backends since the previous snapshot.
+    backend_errors: u64,
+}
+
+impl Flusher {
+    fn run(mut self, commands: Receiver<Command>) {
+        let mut next = Instant::now() + self.interval;
+        loop {
+            let timeout = next.saturating_duration_since(Instant::now());
+            match commands.recv_timeout(timeout) {
+                Ok(Command::Flush(done)) => {
+                    self.flush();
+                    let _ = done.send(());
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.flush();
+                    next += self.interval;
+                    let now = Instant::now();
+                    if next < now {
+                        // Skip the flushes missed by slow backends.
+                        next = now + self.interval;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.flush();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let started = Instant::now();
+        let (drained, totals) = {
+            let mut aggregator = self
+                .shared
+                .aggregator
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            let totals = Totals {
+                packets: self.shared.packets.load(Ordering::Relaxed),
+                metrics: aggregator.metrics_received(),
+                bad_lines: aggregator.bad_lines_by_kind().collect(),
+            };
+            (aggregator.drain(), totals)
+        };
+        // Statistics are computed without the lock, so receiving threads
+        // aren't blocked.
+        let mut snapshot = drained.snapshot(self.interval);
+        if self.internal_metrics {
+            self.add_internal_metrics(&mut snapshot, &totals);
+        }
+        self.reported = totals;
+        self.backend_errors = 0;
+        for backend in &mut self.backends {
+            if backend.flush(&snapshot).is_err() {
+                self.backend_errors += 1;
+            }
+        }
+
+        let mut flushes = self
+            .shared
+            .flushes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        flushes.count += 1;
+        flushes.backend_errors += self.backend_errors;
+        flushes.last = Some((snapshot.timestamp, started.elapsed()));
+    }
+
+    /// Add the internal metrics, counting what happened since the previous
+    /// snapshot.
+    fn add_internal_metrics(&self, snapshot: &mut Snapshot, totals: &Totals) {
+        let seconds = self.interval.as_secs_f64();
+        let mut count = |key: Key, value: u64| {
+            let value = value as f64;
+            let rate = value / seconds;
+            snapshot.counters.insert(key, CounterStats { value, rate });
+        };
+        count(
+            Key::new("statsd.packets_received"),
+            totals.packets - self.reported.packets,
+        );
+        count(
+            Key::new("statsd.metrics_received"),
+            totals.metrics - self.reported.metrics,
+        );
+        let mut bad_lines = 0;
+        for (kind, total) in &totals.bad_lines {
+            let seen = total - self.reported.bad_lines.get(kind).copied().unwrap_or(0);
+            if seen > 0 {
+                let key = Key {
+                    name: "statsd.bad_lines_seen".to_string(),
+                    tags: vec![("kind".to_string(), kind.name().to_string())],
+                };
+                count(key, seen);
+                bad_lines += seen;
+            }
+        }
+        count(Key::new("statsd.bad_lines_seen"), bad_lines);
+        count(Key::new("statsd.backend_errors"), self.backend_errors);
+
+        let flushes = self
+            .shared
+            .flushes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some((timestamp, duration)) = flushes.last {
+            let unix_time = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+            snapshot
+                .gauges
+                .insert(Key::new("statsd.last_flush"), unix_time.as_secs() as f64);
+            snapshot.gauges.insert(
+                Key::new("statsd.flush_duration"),
+                duration.as_secs_f64() * 1000.0,
+            );
+        }
+    }
+}
+
+/// Destination of the packets received by socket threads.
+trait Handler: Send + Sync + 'static {
+    /// Whether socket threads should stop.
+    fn stopped(&self) -> bool;
+
+    /// Handle a datagram, or a line received over TCP.
+    fn handle(&self, packet: &[u8]);
+
+    /// Count a line received over TCP and dropped for being longer than
+    /// `MAX_LINE_SIZE`.
+    fn handle_too_long(&self);
+}
+
+/// Sockets bound before being served.
+#[derive(Default)]
+struct Sockets {
+    udp: Vec<UdpSocket>,
+    tcp: Vec<TcpListener>,
+    #[cfg(unix)]
+    unix: Vec<(UnixDatagram, PathBuf)>,
+}
+
+impl Sockets {
+    fn bind_udp<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<SocketAddr> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let address = socket.local_addr()?;
+        self.udp.push(socket);
+        Ok(address)
+    }
+
+    fn bind_tcp<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        self.tcp.push(listener);
+        Ok(address)
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(&mut self, path: &Path) -> io::Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        let socket = UnixDatagram::bind(path)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        self.unix.push((socket, path.to_path_buf()));
+        Ok(())
+    }
+
+    /// Serve each socket with its own thread, handing packets to `handler`.
+    ///
+    /// Threads are added to `receivers` as they are started, so they can
+    /// be stopped if starting one of them fails.
+    fn serve<H: Handler>(self, handler: &Arc<H>, receivers: &mut Receivers) -> io::Result<()> {
+        for socket in self.udp {
+            let handler = Arc::clone(handler);
+            receivers.threads.push(spawn("statsd-udp", move || {
+                receive_datagrams(&*handler, |buf| socket.recv(buf))
+            })?);
+        }
+        #[cfg(unix)]
+        for (socket, path) in self.unix {
+            let handler = Arc::clone(handler);
+            receivers.unix_paths.push(path);
+            receivers.threads.push(spawn("statsd-unix", move || {
+                receive_datagrams(&*handler, |buf| socket.recv(buf))
+            })?);
+        }
+        for listener in self.tcp {
+            let handler = Arc::clone(handler);
+            receivers.threads.push(spawn("statsd-tcp", move || {
+                accept_connections(&handler, listener, read_lines)
+            })?);
+        }
+        Ok(())
+    }
+}
+
+/// Threads serving sockets.
+#[derive(Default)]
+struct Receivers {
+    threads: Vec<JoinHandle<()>>,
+    /// Paths of the Unix sockets, removed once their threads stopped.
+    #[cfg(unix)]
+    unix_paths: Vec<PathBuf>,
+}
+
+impl Receivers {
+    /// Wait for the threads to notice that their handler stopped.
+    fn join(&mut self) {
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        #[cfg(unix)]
+        for path in self.unix_paths.drain(..) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn spawn<F: FnOnce() + Send + 'static>(name: &str, func: F) -> io::Result<JoinHandle<()>> {
+    thread::Builder::new().name(name.to_string()).spawn(func)
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+    )
+}
+
+fn receive_datagrams<H, R>(handler: &H, mut recv: R)
+where
+    H: Handler,
+    R: FnMut(&mut [u8]) -> io::Result<usize>,
+{
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+    while !handler.stopped() {
+        match recv(&mut buf) {
+            Ok(len) => handler.handle(&buf[..len]),
+            Err(ref e) if is_timeout(e) => {}
+            // Errors such as ICMP port unreachable notifications don't
+            // prevent receiving the next packets.
+            Err(_) => {}
+        }
+    }
+}
+
+/// Accept connections until the handler stops, serving each with `serve`
+/// on its own thread, up to `MAX_CONNECTIONS` at once.
+fn accept_connections<H: Handler>(
+    handler: &Arc<H>,
+    listener: TcpListener,
+    serve: fn(&H, TcpStream),
+) {
+    let mut connections = Vec::new();
+    while !handler.stopped() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                connections.retain(|connection: &JoinHandle<()>| !connection.is_finished());
+                if connections.len() >= MAX_CONNECTIONS {
+                    // Dropping the stream closes the connection.
+                    continue;
+                }
+                let handler = Arc::clone(handler);
+                if let Ok(connection) =
+                    spawn("statsd-tcp-connection", move || serve(&*handler, stream))
+                {
+                    connections.push(connection);
+                }
+            }
+            Err(ref e) if is_timeout(e) => thread::sleep(POLL_INTERVAL),
+            Err(_) => thread::sleep(POLL_INTERVAL),
+        }
+    }
+    for connection in connections {
+        let _ = connection.join();
+    }
+}
+
+fn read_lines<H: Handler>(handler: &H, stream: TcpStream) {
+    if stream.set_nonblocking(false).is_err()
+        || stream.set_read_timeout(Some(POLL_INTERVAL)).is_err()
+    {
+        return;
+    }
+    let mut lines = LineReader::new(stream, MAX_LINE_SIZE);
+    while !handler.stopped() {
+        match lines.read() {
+            Ok(ReadLine::Line(line)) => handler.handle(line),
+            Ok(ReadLine::TooLong) => handler.handle_too_long(),
+            Ok(ReadLine::Pending) => {}
+            Ok(ReadLine::Closed) => return,
+            Err(ref e) if is_timeout(e) => {}
+            Err(_) => return,
+        }
+    }
+    // A last line without a line break.
+    if let Some(line) = lines.partial() {
+        handler.handle(line);
+    }
+}
+
+/// Result of `LineReader::read()`.
+enum ReadLine<'a> {
+    /// A complete line, with its line break unless it is the last line of
+    /// the stream.
+    Line(&'a [u8]),
+    /// A line longer than the maximum size was found. It is skipped up to
+    /// its line break.
+    TooLong,
+    /// The line isn't complete yet.
+    Pending,
+    /// The stream was closed.
+    Closed,
+}
+
+/// Reader of lines of bounded size from a stream with a read timeout.
+struct LineReader<R> {
+    reader: BufReader<R>,
+    max_size: usize,
+    line: Vec<u8>,
+    /// Whether `line` holds a line returned by the last read.
+    complete: bool,
+    /// Whether the rest of a line too long is being skipped.
+    skipping: bool,
+}
+
+impl<R: io::Read> LineReader<R> {
+    fn new(reader: R, max_size: usize) -> LineReader<R> {
+        LineReader {
+            reader: BufReader::new(reader),
+            max_size,
+            line: Vec::new(),
+            complete: false,
+            skipping: false,
+        }
+    }
+
+    /// Read the next line, without buffering more than `max_size` bytes
+    /// and a line break.
+    ///
+    /// On timeouts, the bytes read so far are kept, and the rest of the
+    /// line is appended on the next call.
+    fn read(&mut self) -> io::Result<ReadLine<'_>> {
+        if self.complete {
+            self.line.clear();
+            self.complete = false;
+        }
+        let limit = (self.max_size + 1 - self.line.len()) as u64;
+        let read = (&mut self.reader)
+            .take(limit)
+            .read_until(b'\n', &mut self.line)?;
+        if read == 0 {
+            // A last line without a line break.
+            if self.line.is_empty() || self.skipping {
+                return Ok(ReadLine::Closed);
+            }
+            self.complete = true;
+            return Ok(ReadLine::Line(&self.line));
+        }
+        if self.line.ends_with(b"\n") {
+            if self.skipping {
+                self.skipping = false;
+                self.line.clear();
+                return Ok(ReadLine::Pending);
+            }
+            self.complete = true;
+            return Ok(ReadLine::Line(&self.line));
+        }
+        if self.line.len() > self.max_size {
+            self.line.clear();
+            if !self.skipping {
+                self.skipping = true;
+                return Ok(ReadLine::TooLong);
+            }
+        }
+        Ok(ReadLine::Pending)
+    }
+
+    /// The start of a line that isn't complete yet, if any.
+    fn partial(&self) -> Option<&[u8]> {
+        if self.complete || self.skipping || self.line.is_empty() {
+            return None;
+        }
+        Some(&self.line)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Write;
+
+    use crate::client::Client;
+
+    type Snapshots = Arc<Mutex<Vec<Snapshot>>>;
+
+    fn server(flush_interval: Duration) -> (Server, Snapshots) {
+        let snapshots = Arc::new(Mutex::new(Vec::new()));
+        let mut server = Server::new();
+        server.set_flush_interval(flush_interval);
+        server.set_internal_metrics(false);
+        let recorded = Arc::clone(&snapshots);
+        server.add_backend(move |snapshot: &Snapshot| {
+            recorded.lock().unwrap().push(snapshot.clone());
+        });
+        (server, snapshots)
+    }
+
+    fn wait_for_packets(running: &RunningServer, count: u64) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while running.packets_received() < count {
+            assert!(Instant::now() < deadline, "Packets were not received");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn last(snapshots: &Snapshots) -> Snapshot {
+        snapshots.lock().unwrap().last().cloned().unwrap()
+    }
+
+    #[test]
+    fn test_receiving_udp() {
+        let (mut server, snapshots) = server(Duration::from_secs(60));
+        let address = server.bind_udp("127.0.0.1:0").unwrap();
+        let running = server.start().unwrap();
+
+        let client = Client::new(address, "myapp").unwrap();
+        client.incr("requests");
+        client.count("requests", 2.0);
+        client.gauge("load", 0.5);
+        client.timer("latency", 12.0);
+        let mut pipeline = client.pipeline();
+        pipeline.timer("latency", 8.0);
+        pipeline.incr("requests");
+        pipeline.send(&client);
+        wait_for_packets(&running, 5);
+        running.flush();
+
+        let snapshot = last(&snapshots);
+        assert_eq!(60, snapshot.interval.as_secs());
+        assert_eq!(4.0, snapshot.counters[&Key::new("myapp.requests")].value);
+        assert_eq!(0.5, snapshot.gauges[&Key::new("myapp.load")]);
+        let latency = &snapshot.timers[&Key::new("myapp.latency")];
+        assert_eq!(vec![8.0, 12.0], latency.values);
+        assert_eq!(10.0, latency.mean);
+
+        running.flush();
+        let snapshot = last(&snapshots);
+        assert!(snapshot.counters.is_empty());
+        assert_eq!(0.5, snapshot.gauges[&Key::new("myapp.load")]);
+        running.shutdown();
+    }
+
+    #[test]
+    fn test_receiving_tcp() {
+        let (mut server, snapshots) = server(Duration::from_secs(60));
+        let address = server.bind_tcp("127.0.0.1:0").unwrap();
+        let running = server.start().unwrap();
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"a:1|c\na:2|c\nset:x|s\nset:").unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        stream.write_all(b"y|s").unwrap();
+        drop(stream);
+        wait_for_packets(&running, 4);
+        running.flush();
+
+        let snapshot = last(&snapshots);
+        assert_eq!(3.0, snapshot.counters[&Key::new("a")].value);
+        assert_eq!(2, snapshot.sets[&Key::new("set")]);
+    }
+
+    #[test]
+    fn test_dropping_long_tcp_lines() {
+        let (mut server, snapshots) = server(Duration::from_secs(60));
+        let address = server.bind_tcp("127.0.0.1:0").unwrap();
+        let running = server.start().unwrap();
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"a:1|c\n").unwrap();
+        let chunk = vec![b'x'; 16 * 1024];
+        for _ in 0..(4 * MAX_LINE_SIZE / chunk.len()) {
+            stream.write_all(&chunk).unwrap();
+        }
+        stream.write_all(b"\nb:2|c\n").unwrap();
+        drop(stream);
+        wait_for_packets(&running, 2);
+        running.flush();
+
+        let snapshot = last(&snapshots);
+        assert_eq!(1.0, snapshot.counters[&Key::new("a")].value);
+        assert_eq!(2.0, snapshot.counters[&Key::new("b")].value);
+        let stats = running.stats();
+        assert_eq!(2, stats.packets_received);
+        assert_eq!(1, stats.bad_lines[&ParseErrorKind::TooLong]);
+    }
+
+    #[test]
+    fn test_reading_bounded_lines() {
+        let mut lines = LineReader::new(&b"short\ntoo long line\nlast"[..], 8);
+        let mut read = Vec::new();
+        loop {
+            match lines.read().unwrap() {
+                ReadLine::Line(line) => read.push(String::from_utf8_lossy(line).into_owned()),
+                ReadLine::TooLong => read.push("too long".to_string()),
+                ReadLine::Pending => {}
+                ReadLine::Closed => break,
+            }
+        }
+        assert_eq!(vec!["short\n", "too long", "last"], read);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_receiving_unix_datagrams() {
+        let path = std::env::temp_dir().join(format!("statsd-test-{}.sock", std::process::id()));
+        let (mut server, snapshots) = server(Duration::from_secs(60));
+        server.bind_unix(&path).unwrap();
+        let running = server.start().unwrap();
+
+        let socket = UnixDatagram::unbound().unwrap();
+        socket.send_to(b"a:1|c|#x:y", &path).unwrap();
+        wait_for_packets(&running, 1);
+        running.shutdown();
+        assert!(!path.exists());
+
+        let snapshot = last(&snapshots);
+        let key = Key {
+            name: "a".to_string(),
+            tags: vec![("x".to_string(), "y".to_string())],
+        };
+        assert_eq!(1.0, snapshot.counters[&key].value);
+    }
+
+    #[test]
+    fn test_flushing_periodically_and_on_shutdown() {
+        let (mut server, snapshots) = server(Duration::from_millis(50));
+        let address = server.bind_udp("127.0.0.1:0").unwrap();
+        let running = server.start().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while snapshots.lock().unwrap().len() < 2 {
+            assert!(Instant::now() < deadline, "No periodic flushes");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let client = Client::new(address, "").unwrap();
+        client.incr("last");
+        wait_for_packets(&running, 1);
+        running.shutdown();
+        // The counter may have been flushed periodically before shutting
+        // down, but was flushed exactly once.
+        let counters: Vec<_> = snapshots
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|snapshot| snapshot.counters.get(&Key::new("last")).copied())
+            .collect();
+        assert_eq!(
+            vec![CounterStats {
+                value: 1.0,
+                rate: 20.0
+            }],
+            counters
+        );
+    }
+
+    struct FailingBackend;
+
+    impl Backend for FailingBackend {
+        fn flush(&mut self, _: &Snapshot) -> io::Result<()> {
+            Err(io::ErrorKind::ConnectionRefused.into())
+        }
+    }
+
+    #[test]
+    fn test_internal_metrics() {
+        let (mut server, snapshots) = server(Duration::from_secs(10));
+        server.set_internal_metrics(true);
+        server.add_backend(FailingBackend);
+        let address = server.bind_udp("127.0.0.1:0").unwrap();
+        let running = server.start().unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .send_to(b"a:1|c\nb:2|g\nc:x|c\nd|c", address)
+            .unwrap();
+        socket.send_to(b"e:1|q", address).unwrap();
+        wait_for_packets(&running, 2);
+        running.flush();
+
+        let snapshot = last(&snapshots);
+        let counter = |name: &str| snapshot.counters[&Key::new(name)].value;
+        assert_eq!(2.0, counter("statsd.packets_received"));
+        assert_eq!(2.0, counter("statsd.metrics_received"));
+        assert_eq!(3.0, counter("statsd.bad_lines_seen"));
+        assert_eq!(0.0, counter("statsd.backend_errors"));
+        let invalid_values = Key {
+            name: "statsd.bad_lines_seen".to_string(),
+            tags: vec![("kind".to_string(), "invalid_value".to_string())],
+        };
+        assert_eq!(0.1, snapshot.counters[&invalid_values].rate);
+        // There was no previous flush.
+        assert!(!snapshot.gauges.contains_key(&Key::new("statsd.last_flush")));
+
+        let stats = running.stats();
+        assert_eq!(2, stats.packets_received);
+        assert_eq!(2, stats.metrics_received);
+        assert_eq!(3, stats.bad_lines_seen());
+        assert_eq!(1, stats.bad_lines[&ParseErrorKind::MissingValue]);
+        assert_eq!(1, stats.flushes);
+        assert_eq!(1, stats.backend_errors);
+        assert_eq!(Some(snapshot.timestamp), stats.last_flush);
+        assert!(stats.last_flush_duration.is_some());
+
+        running.flush();
+        let previous = snapshot.timestamp.duration_since(UNIX_EPOCH).unwrap();
+        let snapshot = last(&snapshots);
+        let counter = |name: &str| snapshot.counters[&Key::new(name)].value;
+        assert_eq!(0.0, counter("statsd.packets_received"));
+        assert_eq!(0.0, counter("statsd.bad_lines_seen"));
+        assert!(!snapshot.counters.contains_key(&invalid_values));
+        assert_eq!(1.0, counter("statsd.backend_errors"));
+        assert_eq!(
+            previous.as_secs() as f64,
+            snapshot.gauges[&Key::new("statsd.last_flush")]
+        );
+        assert!(snapshot.gauges[&Key::new("statsd.flush_duration")] >= 0.0);
+        assert_eq!(2, running.stats().backend_errors);
+    }
+}
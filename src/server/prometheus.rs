@@ -0,0 +1,777 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use regex::Regex;
+
+use super::{is_timeout, spawn, Backend, Key, Retention, Sketch, Snapshot, POLL_INTERVAL};
+use crate::format::Float;
+
+/// How long scrapers get to send a request and read the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest request line and headers read from scrapers.
+const MAX_REQUEST_SIZE: u64 = 16 * 1024;
+
+type Labels = Vec<(String, String)>;
+
+/// How timers are exposed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimerType {
+    /// A summary, with the quantiles of the values received during the
+    /// last flush interval in which the timer was received, and the total
+    /// sum and count of values.
+    Summary,
+    /// A histogram, counting the values in buckets with these upper
+    /// bounds, in the units values were sent in (milliseconds for timers).
+    Histogram(Vec<f64>),
+}
+
+/// Rule mapping statsd metric names to Prometheus metric names and labels,
+/// like the mappings of the Prometheus `statsd_exporter`.
+///
+/// ```
+/// use statsd::server::Mapping;
+///
+/// // `myapp.api.requests` is exposed as `requests{service="api"}`.
+/// let mut mapping = Mapping::glob("myapp.*.requests", "requests");
+/// mapping.add_label("service", "$1");
+/// ```
+///
+/// Captures are expanded like `regex::Captures::expand` does it, so a
+/// capture followed by a letter, digit or `_` must be written `${1}`.
+#[derive(Clone, Debug)]
+pub struct Mapping {
+    pattern: Regex,
+    name: String,
+    labels: Labels,
+    timer_type: Option<TimerType>,
+}
+
+impl Mapping {
+    /// Map names matching a glob pattern, where `*` matches any part of a
+    /// name between dots. The parts matched by `*` are captured as `$1`,
+    /// `$2` and so on.
+    pub fn glob(pattern: &str, name: &str) -> Mapping {
+        let mut regex = String::from("^");
+        for (i, part) in pattern.split('*').enumerate() {
+            if i > 0 {
+                regex.push_str("([^.]*)");
+            }
+            regex.push_str(&regex::escape(part));
+        }
+        regex.push('$');
+        let pattern = Regex::new(&regex).expect("Escaped globs are valid regexes");
+        Mapping::new(pattern, name)
+    }
+
+    /// Map names matching a regular expression. Groups are captured as
+    /// `$1` or by name as `$name`.
+    ///
+    /// The regular expression isn't anchored, use `^` and `$` to match
+    /// whole names.
+    pub fn regex(pattern: &str, name: &str) -> Result<Mapping, regex::Error> {
+        Ok(Mapping::new(Regex::new(pattern)?, name))
+    }
+
+    fn new(pattern: Regex, name: &str) -> Mapping {
+        Mapping {
+            pattern,
+            name: name.to_string(),
+            labels: Vec::new(),
+            timer_type: None,
+        }
+    }
+
+    /// Add a label to the metrics matching the rule. The value can refer
+    /// to captures. Mapped labels replace tags with the same name.
+    pub fn add_label(&mut self, name: &str, value: &str) {
+        self.labels.push((label_name(name), value.to_string()));
+    }
+
+    /// Set how the timers matching the rule are exposed, instead of the
+    /// backend's timer type.
+    pub fn set_timer_type(&mut self, timer_type: TimerType) {
+        self.timer_type = Some(timer_type);
+    }
+
+    /// Map `name` to a metric name and labels if it matches the rule.
+    fn apply(&self, name: &str) -> Option<(String, Labels)> {
+        let captures = self.pattern.captures(name)?;
+        let mut mapped = String::new();
+        captures.expand(&self.name, &mut mapped);
+        let labels = self
+            .labels
+            .iter()
+            .map(|(name, template)| {
+                let mut value = String::new();
+                captures.expand(template, &mut value);
+                (name.clone(), value)
+            })
+            .collect();
+        Some((mapped, labels))
+    }
+}
+
+/// Backend exposing aggregated metrics in the Prometheus text format.
+///
+/// Counters are exposed as totals since the server started, gauges and
+/// sets as gauges, and timers as summaries or histograms. Statsd names are
+/// mapped to Prometheus names with the first matching `Mapping`, and tags
+/// are exposed as labels. Names which don't match any mapping have
+/// characters other than letters, digits, `_` and `:` replaced with `_`,
+/// so `myapp.requests` is exposed as `myapp_requests`.
+///
+/// Metrics are served over HTTP on `/metrics` by `listen()`, and can also
+/// be rendered by a `PrometheusHandle` to serve them from another HTTP
+/// server.
+///
+/// Prometheus counters can't decrease, so negative counter values are
+/// ignored. Metrics named like a metric of another type are ignored.
+///
+/// Like the TTL of the Prometheus `statsd_exporter`, series missing from
+/// the snapshots of many flushes in a row stop being exposed, so that the
+/// number of series doesn't grow forever.
+pub struct PrometheusBackend {
+    mappings: Vec<Mapping>,
+    timer_type: TimerType,
+    quantiles: Vec<f64>,
+    retention: Retention,
+    registry: Arc<Mutex<Registry>>,
+    stop: Arc<AtomicBool>,
+    listeners: Vec<JoinHandle<()>>,
+}
+
+impl PrometheusBackend {
+    /// Create a backend without mappings, exposing timers as summaries of
+    /// the 0.5, 0.9 and 0.99 quantiles.
+    pub fn new() -> PrometheusBackend {
+        PrometheusBackend {
+            mappings: Vec::new(),
+            timer_type: TimerType::Summary,
+            quantiles: vec![0.5, 0.9, 0.99],
+            retention: Retention::Flushes(30),
+            registry: Arc::new(Mutex::new(Registry::default())),
+            stop: Arc::new(AtomicBool::new(false)),
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Add a mapping rule, tried after the rules added before.
+    pub fn add_mapping(&mut self, mapping: Mapping) {
+        self.mappings.push(mapping);
+    }
+
+    /// Set how timers are exposed, as summaries by default.
+    pub fn set_timer_type(&mut self, timer_type: TimerType) {
+        self.timer_type = timer_type;
+    }
+
+    /// Set the quantiles of summaries, between 0 and 1.
+    pub fn set_quantiles(&mut self, quantiles: &[f64]) {
+        self.quantiles = quantiles.to_vec();
+    }
+
+    /// Set how long series missing from snapshots keep being exposed,
+    /// `Retention::Flushes(30)` by default, 5 minutes with the default
+    /// flush interval.
+    ///
+    /// Metrics are only missing from snapshots once the aggregator stops
+    /// reporting them, which depends on its own retention.
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+    }
+
+    /// Serve the metrics over HTTP on `/metrics` at `address`, until the
+    /// backend is dropped. The bound address is returned.
+    pub fn listen<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        let registry = Arc::clone(&self.registry);
+        let stop = Arc::clone(&self.stop);
+        self.listeners.push(spawn("statsd-prometheus", move || {
+            serve(&listener, &registry, &stop)
+        })?);
+        Ok(address)
+    }
+
+    /// Get a handle rendering the metrics.
+    pub fn handle(&self) -> PrometheusHandle {
+        PrometheusHandle {
+            registry: Arc::clone(&self.registry),
+        }
+    }
+
+    /// Get the Prometheus name and labels of a statsd metric, and the
+    /// timer type of the matching mapping.
+    fn map(&self, key: &Key) -> (String, Labels, Option<&TimerType>) {
+        let mut labels: Labels = key
+            .tags
+            .iter()
+            .map(|(name, value)| (label_name(name), value.clone()))
+            .collect();
+        let mapped = self
+            .mappings
+            .iter()
+            .find_map(|mapping| mapping.apply(&key.name).map(|mapped| (mapping, mapped)));
+        let (name, timer_type) = match mapped {
+            Some((mapping, (name, mapped_labels))) => {
+                for (name, value) in mapped_labels {
+                    labels.retain(|(label, _)| *label != name);
+                    labels.push((name, value));
+                }
+                (name, mapping.timer_type.as_ref())
+            }
+            None => (key.name.clone(), None),
+        };
+        labels.sort();
+        labels.dedup_by(|a, b| a.0 == b.0);
+        (metric_name(&name), labels, timer_type)
+    }
+}
+
+impl Default for PrometheusBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for PrometheusBackend {
+    fn flush(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        let mut registry = lock(&self.registry);
+        registry.start_flush();
+        for (key, counter) in &snapshot.counters {
+            if counter.value < 0.0 {
+                continue;
+            }
+            let (name, labels, _) = self.map(key);
+            if let Some(Series::Counter(total)) =
+                registry.series(name, labels, Series::Counter(0.0))
+            {
+                *total += counter.value;
+            }
+        }
+        let sets = snapshot
+            .sets
+            .iter()
+            .map(|(key, count)| (key, *count as f64));
+        for (key, value) in snapshot.gauges.iter().map(|(k, v)| (k, *v)).chain(sets) {
+            let (name, labels, _) = self.map(key);
+            if let Some(Series::Gauge(gauge)) = registry.series(name, labels, Series::Gauge(0.0)) {
+                *gauge = value;
+            }
+        }
+        for (key, timer) in &snapshot.timers {
+            let (name, labels, timer_type) = self.map(key);
+            let new = match timer_type.unwrap_or(&self.timer_type) {
+                TimerType::Summary => Series::Summary {
+                    quantiles: Vec::new(),
+                    sum: 0.0,
+                    count: 0.0,
+                },
+                TimerType::Histogram(bounds) => Series::Histogram {
+                    bounds: bounds.clone(),
+                    counts: vec![0; bounds.len()],
+                    sum: 0.0,
+                    count: 0,
+                },
+            };
+            match registry.series(name, labels, new) {
+                Some(Series::Summary {
+                    quantiles,
+                    sum,
+                    count,
+                }) => {
+                    *quantiles = self
+                        .quantiles
+                        .iter()
+                        .map(|q| (*q, timer.quantile(*q)))
+                        .collect();
+                    *sum += timer.sum;
+                    *count += timer.count;
+                }
+                Some(Series::Histogram {
+                    bounds,
+                    counts,
+                    sum,
+                    count,
+                }) => {
+                    let sketched = timer.sketch.iter().flat_map(Sketch::bins);
+                    let values = timer.values.iter().map(|value| (*value, 1)).chain(sketched);
+                    for (value, times) in values {
+                        for (bound, bucket) in bounds.iter().zip(counts.iter_mut()) {
+                            if value <= *bound {
+                                *bucket += times;
+                            }
+                        }
+                        // The count isn't scaled by sample rates, to match
+                        // the `+Inf` bucket.
+                        *count += times;
+                    }
+                    *sum += timer.sum;
+                }
+                _ => {}
+            }
+        }
+        registry.expire(self.retention);
+        Ok(())
+    }
+}
+
+impl Drop for PrometheusBackend {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for listener in self.listeners.drain(..) {
+            let _ = listener.join();
+        }
+    }
+}
+
+/// Handle rendering the metrics of a `PrometheusBackend`, e.g. to serve
+/// them from an existing HTTP server.
+#[derive(Clone)]
+pub struct PrometheusHandle {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl PrometheusHandle {
+    /// Render the metrics in the Prometheus text format, version 0.0.4.
+    pub fn render(&self) -> String {
+        lock(&self.registry).render()
+    }
+}
+
+enum Series {
+    Counter(f64),
+    Gauge(f64),
+    Summary {
+        quantiles: Vec<(f64, f64)>,
+        sum: f64,
+        /// Number of values, scaled by their sample rates.
+        count: f64,
+    },
+    Histogram {
+        bounds: Vec<f64>,
+        /// Cumulative counts of the buckets.
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+impl Series {
+    fn kind(&self) -> &'static str {
+        match self {
+            Series::Counter(_) => "counter",
+            Series::Gauge(_) => "gauge",
+            Series::Summary { .. } => "summary",
+            Series::Histogram { .. } => "histogram",
+        }
+    }
+}
+
+struct Family {
+    kind: &'static str,
+    /// Series, and the number of flushes since they were last updated.
+    series: BTreeMap<Labels, (Series, u32)>,
+}
+
+#[derive(Default)]
+struct Registry {
+    families: BTreeMap<String, Family>,
+}
+
+impl Registry {
+    /// Get the series with a name and labels, adding `new` if there is
+    /// none. `None` is returned if the name is used by another type.
+    fn series(&mut self, name: String, labels: Labels, new: Series) -> Option<&mut Series> {
+        let family = self.families.entry(name).or_insert_with(|| Family {
+            kind: new.kind(),
+            series: BTreeMap::new(),
+        });
+        if family.kind != new.kind() {
+            return None;
+        }
+        let (series, idle) = family.series.entry(labels).or_insert((new, 0));
+        *idle = 0;
+        Some(series)
+    }
+
+    /// Count a flush for every series, before updating those of the
+    /// snapshot.
+    fn start_flush(&mut self) {
+        for family in self.families.values_mut() {
+            for (_, idle) in family.series.values_mut() {
+                *idle = idle.saturating_add(1);
+            }
+        }
+    }
+
+    /// Remove series which weren't updated for longer than `retention`.
+    fn expire(&mut self, retention: Retention) {
+        for family in self.families.values_mut() {
+            family.series.retain(|_, (_, idle)| retention.keeps(*idle));
+        }
+        self.families.retain(|_, family| !family.series.is_empty());
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, family) in &self.families {
+            let _ = writeln!(out, "# TYPE {} {}", name, family.kind);
+            for (labels, (series, _)) in &family.series {
+                let mut sample = |suffix, extra: Option<(&str, f64)>, value| {
+                    write_sample(&mut out, name, suffix, labels, extra, value)
+                };
+                match series {
+                    Series::Counter(value) | Series::Gauge(value) => sample("", None, *value),
+                    Series::Summary {
+                        quantiles,
+                        sum,
+                        count,
+                    } => {
+                        for (q, value) in quantiles {
+                            sample("", Some(("quantile", *q)), *value);
+                        }
+                        sample("_sum", None, *sum);
+                        sample("_count", None, *count);
+                    }
+                    Series::Histogram {
+                        bounds,
+                        counts,
+                        sum,
+                        count,
+                    } => {
+                        for (bound, bucket) in bounds.iter().zip(counts) {
+                            sample("_bucket", Some(("le", *bound)), *bucket as f64);
+                        }
+                        sample("_bucket", Some(("le", f64::INFINITY)), *count as f64);
+                        sample("_sum", None, *sum);
+                        sample("_count", None, *count as f64);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Write a `name{labels} value` line.
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    suffix: &str,
+    labels: &[(String, String)],
+    extra: Option<(&str, f64)>,
+    value: f64,
+) {
+    out.push_str(name);
+    out.push_str(suffix);
+    let extra = extra.map(|(name, value)| (name, Number(value).to_string()));
+    let mut labels = labels
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .chain(extra.as_ref().map(|(name, value)| (*name, value.as_str())))
+        .peekable();
+    if labels.peek().is_some() {
+        out.push('{');
+        for (i, (name, value)) in labels.enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str(name);
+            out.push_str("=\"");
+            for c in value.chars() {
+                match c {
+                    '\\' => out.push_str("\\\\"),
+                    '"' => out.push_str("\\\""),
+                    '\n' => out.push_str("\\n"),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", Number(value));
+}
+
+/// A sample value, in the Prometheus notation.
+struct Number(f64);
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_nan() {
+            f.write_str("NaN")
+        } else if self.0 == f64::INFINITY {
+            f.write_str("+Inf")
+        } else if self.0 == f64::NEG_INFINITY {
+            f.write_str("-Inf")
+        } else {
+            write!(f, "{}", Float(self.0))
+        }
+    }
+}
+
+/// Replace characters other than letters, digits, `_` and `:` with `_`.
+fn metric_name(name: &str) -> String {
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Replace characters other than letters, digits and `_` with `_`.
+fn label_name(name: &str) -> String {
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn sanitize<F: Fn(char) -> bool>(name: &str, valid: F) -> String {
+    let mut sanitized = String::with_capacity(name.len() + 1);
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.push('_');
+    }
+    sanitized.extend(name.chars().map(|c| if valid(c) { c } else { '_' }));
+    sanitized
+}
+
+fn lock(registry: &Mutex<Registry>) -> MutexGuard<'_, Registry> {
+    registry.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn serve(listener: &TcpListener, registry: &Mutex<Registry>, stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            // Scrapes are rare enough to be served one at a time.
+            Ok((stream, _)) => {
+                let _ = respond(&stream, registry);
+            }
+            Err(ref e) if is_timeout(e) => thread::sleep(POLL_INTERVAL),
+            Err(_) => thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+fn respond(mut stream: &TcpStream, registry: &Mutex<Registry>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Headers are read so that closing the connection doesn't reset it
+    // before the response is read, but are otherwise ignored.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) if target.split('?').next() == Some("/metrics") => {
+            ("200 OK", lock(registry).render())
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+        (Some(_), Some(_)) => ("405 Method Not Allowed", String::new()),
+        _ => ("400 Bad Request", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::server::Aggregator;
+
+    fn render(backend: &mut PrometheusBackend, packets: &[&[u8]]) -> String {
+        let mut aggregator = Aggregator::new();
+        for packet in packets {
+            aggregator.add_packet(packet);
+            backend
+                .flush(&aggregator.flush(Duration::from_secs(10)))
+                .unwrap();
+        }
+        backend.handle().render()
+    }
+
+    #[test]
+    fn test_glob_mappings() {
+        let mut mapping = Mapping::glob("myapp.*.requests.*", "${1}_requests");
+        mapping.add_label("status", "$2");
+        assert_eq!(
+            Some((
+                "api_requests".to_string(),
+                vec![("status".to_string(), "ok".to_string())]
+            )),
+            mapping.apply("myapp.api.requests.ok")
+        );
+        assert_eq!(None, mapping.apply("myapp.api.v1.requests.ok"));
+        assert_eq!(None, mapping.apply("myapp.api.requests"));
+        assert_eq!(None, mapping.apply("other.myapp.api.requests.ok"));
+    }
+
+    #[test]
+    fn test_regex_mappings() {
+        let mut mapping = Mapping::regex(r"^(?P<app>\w+)\.latency\.(.+)$", "latency").unwrap();
+        mapping.add_label("app", "$app");
+        mapping.add_label("endpoint", "$2");
+        let (name, labels) = mapping.apply("myapp.latency.users.get").unwrap();
+        assert_eq!("latency", name);
+        assert_eq!(
+            vec![
+                ("app".to_string(), "myapp".to_string()),
+                ("endpoint".to_string(), "users.get".to_string())
+            ],
+            labels
+        );
+        assert!(Mapping::regex("(", "name").is_err());
+    }
+
+    #[test]
+    fn test_counters_and_gauges() {
+        let mut backend = PrometheusBackend::new();
+        let mut mapping = Mapping::glob("myapp.*.hits", "hits_total");
+        mapping.add_label("route", "$1");
+        backend.add_mapping(mapping);
+        let exposition = render(
+            &mut backend,
+            &[
+                b"myapp.home.hits:2|c\nmyapp.load:0.5|g\nusers:a|s\nusers:b|s",
+                b"myapp.home.hits:1|c|#method:GET\nmyapp.home.hits:3|c\nmyapp.home.hits:-9|c",
+                b"1st.value:1|g|#region:\"eu\\west\"",
+            ],
+        );
+        assert_eq!(
+            "# TYPE _1st_value gauge\n\
+             _1st_value{region=\"\\\"eu\\\\west\\\"\"} 1\n\
+             # TYPE hits_total counter\n\
+             hits_total{method=\"GET\",route=\"home\"} 1\n\
+             hits_total{route=\"home\"} 2\n\
+             # TYPE myapp_load gauge\n\
+             myapp_load 0.5\n\
+             # TYPE users gauge\n\
+             users 2\n",
+            exposition
+        );
+    }
+
+    #[test]
+    fn test_expiring_series() {
+        let mut backend = PrometheusBackend::new();
+        backend.set_retention(Retention::Flushes(1));
+        render(&mut backend, &[b"hits:1|c\nload:2|g"]);
+        // Each render uses a new aggregator, so the gauge is missing from
+        // the next snapshots.
+        let exposition = render(&mut backend, &[b""]);
+        assert_eq!(
+            "# TYPE hits counter\nhits 1\n# TYPE load gauge\nload 2\n",
+            exposition
+        );
+        let exposition = render(&mut backend, &[b"hits:1|c"]);
+        assert_eq!("# TYPE hits counter\nhits 2\n", exposition);
+    }
+
+    #[test]
+    fn test_summaries() {
+        let mut backend = PrometheusBackend::new();
+        backend.set_quantiles(&[0.5, 0.99]);
+        let exposition = render(
+            &mut backend,
+            &[
+                b"latency:10|ms\nlatency:20|ms",
+                b"latency:1|ms\nlatency:2|ms\nlatency:3|ms",
+            ],
+        );
+        assert_eq!(
+            "# TYPE latency summary\n\
+             latency{quantile=\"0.5\"} 2\n\
+             latency{quantile=\"0.99\"} 3\n\
+             latency_sum 36\n\
+             latency_count 5\n",
+            exposition
+        );
+    }
+
+    #[test]
+    fn test_histograms() {
+        let mut backend = PrometheusBackend::new();
+        backend.set_timer_type(TimerType::Histogram(vec![10.0, 100.0]));
+        let mut mapping = Mapping::glob("db.*", "db_$1");
+        mapping.set_timer_type(TimerType::Summary);
+        backend.add_mapping(mapping);
+        let exposition = render(
+            &mut backend,
+            &[
+                b"latency:5|ms\nlatency:10|ms\nlatency:50|ms",
+                b"latency:500|ms\ndb.query:4|ms",
+            ],
+        );
+        assert_eq!(
+            "# TYPE db_query summary\n\
+             db_query{quantile=\"0.5\"} 4\n\
+             db_query{quantile=\"0.9\"} 4\n\
+             db_query{quantile=\"0.99\"} 4\n\
+             db_query_sum 4\n\
+             db_query_count 1\n\
+             # TYPE latency histogram\n\
+             latency_bucket{le=\"10\"} 2\n\
+             latency_bucket{le=\"100\"} 3\n\
+             latency_bucket{le=\"+Inf\"} 4\n\
+             latency_sum 565\n\
+             latency_count 4\n",
+            exposition
+        );
+    }
+
+    #[test]
+    fn test_conflicting_types() {
+        let mut backend = PrometheusBackend::new();
+        let exposition = render(&mut backend, &[b"a.b:1|c", b"a_b:1|g"]);
+        assert_eq!("# TYPE a_b counter\na_b 1\n", exposition);
+    }
+
+    fn get(address: SocketAddr, target: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serving_metrics() {
+        let mut backend = PrometheusBackend::new();
+        let address = backend.listen("127.0.0.1:0").unwrap();
+        render(&mut backend, &[b"hits:1|c"]);
+
+        let response = get(address, "/metrics?name[]=hits");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Length: 27\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n# TYPE hits counter\nhits 1\n"));
+
+        let response = get(address, "/");
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+        drop(backend);
+        assert!(TcpStream::connect(address).is_err());
+    }
+}
@@ -0,0 +1,620 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::path::Path;
+
+use super::{Handler, Receivers, Sockets};
+use crate::client::{Client, StatsdError};
+use crate::protocol::{self, Line};
+use crate::validation::NamePolicy;
+
+/// Largest UDP payload over IPv4, the largest packet relayed.
+const MAX_RELAYED_SIZE: usize = 65_507;
+
+/// Points of each upstream on the hash ring.
+const VIRTUAL_NODES: usize = 160;
+
+/// How relayed lines are spread over upstreams.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Routing {
+    /// Relay every line to every upstream.
+    Broadcast,
+    /// Relay each metric to one upstream, chosen by hashing its name on a
+    /// consistent hash ring. Each metric is aggregated by a single
+    /// upstream, and adding an upstream only moves a share of the metrics.
+    ConsistentHash,
+}
+
+/// A statsd proxy, relaying the metrics it receives to upstream servers.
+///
+/// ```
+/// use statsd::server::{Proxy, Routing};
+/// use statsd::Client;
+///
+/// let mut proxy = Proxy::new();
+/// proxy.bind_udp("127.0.0.1:0").unwrap();
+/// proxy.add_upstream(Client::new("127.0.0.1:8125", "").unwrap());
+/// proxy.add_upstream(Client::new("127.0.0.1:8126", "").unwrap());
+/// proxy.set_routing(Routing::ConsistentHash);
+/// proxy.add_tag("dc", "eu-west").unwrap();
+/// let running = proxy.start().unwrap();
+/// ```
+///
+/// Each metric line is parsed, dropped unless its name is allowed, has its
+/// prefix rewritten and constant tags added, and is relayed. Events and
+/// service checks are relayed unchanged, and invalid lines are dropped.
+///
+/// Lines of a received packet are relayed in one packet to each upstream,
+/// unless re-batching is enabled with `set_batching()`.
+pub struct Proxy {
+    sockets: Sockets,
+    upstreams: Vec<Client>,
+    routing: Routing,
+    batching: Option<(usize, Duration)>,
+    rewrites: Vec<(String, String)>,
+    /// Constant tags, in `key:value,key:value` form.
+    tags: String,
+    allowed: Vec<String>,
+    denied: Vec<String>,
+}
+
+impl Proxy {
+    /// Create a proxy without sockets or upstreams, broadcasting lines.
+    pub fn new() -> Proxy {
+        Proxy {
+            sockets: Sockets::default(),
+            upstreams: Vec::new(),
+            routing: Routing::Broadcast,
+            batching: None,
+            rewrites: Vec::new(),
+            tags: String::new(),
+            allowed: Vec::new(),
+            denied: Vec::new(),
+        }
+    }
+
+    /// Receive metrics on a UDP socket bound to `address`. The bound
+    /// address is returned.
+    pub fn bind_udp<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<SocketAddr> {
+        self.sockets.bind_udp(address)
+    }
+
+    /// Receive metrics on TCP connections to `address`, one metric per
+    /// line. The bound address is returned.
+    pub fn bind_tcp<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<SocketAddr> {
+        self.sockets.bind_tcp(address)
+    }
+
+    /// Receive metrics on a Unix datagram socket created at `path`.
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.sockets.bind_unix(path.as_ref())
+    }
+
+    /// Relay metrics to the server `client` sends to.
+    ///
+    /// Lines are relayed as is, without the prefix and tags of the client.
+    pub fn add_upstream(&mut self, client: Client) {
+        self.upstreams.push(client);
+    }
+
+    /// Set how lines are spread over upstreams, broadcast by default.
+    pub fn set_routing(&mut self, routing: Routing) {
+        self.routing = routing;
+    }
+
+    /// Re-batch relayed lines into packets of up to `max_udp_size` bytes,
+    /// sent when full or every `flush_interval`.
+    pub fn set_batching(&mut self, max_udp_size: usize, flush_interval: Duration) {
+        self.batching = Some((max_udp_size, flush_interval));
+    }
+
+    /// Replace the `from` prefix of metric names with `to`. Only the first
+    /// matching rewrite is applied, and an empty `from` adds a prefix to
+    /// every name. Metrics left without a name by a rewrite are dropped.
+    ///
+    /// A `to` prefix with characters of the line protocol is rejected with
+    /// `StatsdError::InvalidMetric`, as it would corrupt relayed lines.
+    pub fn add_prefix_rewrite(&mut self, from: &str, to: &str) -> Result<(), StatsdError> {
+        NamePolicy::Reject.apply(to)?;
+        self.rewrites.push((from.to_string(), to.to_string()));
+        Ok(())
+    }
+
+    /// Add a tag to every relayed metric.
+    ///
+    /// Tags with characters of the line protocol in their key or value are
+    /// rejected with `StatsdError::InvalidMetric`, like `NamePolicy::Reject`
+    /// does, as they would corrupt every relayed line.
+    pub fn add_tag(&mut self, key: &str, value: &str) -> Result<(), StatsdError> {
+        NamePolicy::Reject.apply(key)?;
+        NamePolicy::Reject.apply_tag_value(value)?;
+        if !self.tags.is_empty() {
+            self.tags.push(',');
+        }
+        self.tags.push_str(key);
+        self.tags.push(':');
+        self.tags.push_str(value);
+        Ok(())
+    }
+
+    /// Only relay metrics with a name matching `pattern`, or another
+    /// allowed pattern. In patterns, `*` matches any characters, dots
+    /// included. Names are matched as received, before being rewritten.
+    pub fn allow(&mut self, pattern: &str) {
+        self.allowed.push(pattern.to_string());
+    }
+
+    /// Drop metrics with a name matching `pattern`, even if it is allowed.
+    pub fn deny(&mut self, pattern: &str) {
+        self.denied.push(pattern.to_string());
+    }
+
+    /// Start relaying metrics.
+    pub fn start(self) -> io::Result<RunningProxy> {
+        if self.upstreams.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The proxy has no upstream",
+            ));
+        }
+        let upstreams: Vec<_> = match self.batching {
+            Some((max_udp_size, flush_interval)) => self
+                .upstreams
+                .iter()
+                .map(|upstream| upstream.buffered(max_udp_size, flush_interval))
+                .collect(),
+            None => self.upstreams,
+        };
+        let ring = match self.routing {
+            Routing::Broadcast => None,
+            Routing::ConsistentHash => Some(Ring::new(&upstreams)),
+        };
+        let relay = Arc::new(Relay {
+            stop: AtomicBool::new(false),
+            packets: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            batched: self.batching.is_some(),
+            upstreams,
+            ring,
+            rewrites: self.rewrites,
+            tags: self.tags,
+            allowed: self.allowed,
+            denied: self.denied,
+        });
+        let mut running = RunningProxy {
+            relay: Arc::clone(&relay),
+            receivers: Receivers::default(),
+        };
+        self.sockets.serve(&relay, &mut running.receivers)?;
+        Ok(running)
+    }
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A started proxy.
+///
+/// The proxy shuts down when `shutdown()` is called or when it is dropped.
+pub struct RunningProxy {
+    relay: Arc<Relay>,
+    receivers: Receivers,
+}
+
+impl RunningProxy {
+    /// Number of datagrams and TCP lines received since the proxy started.
+    pub fn packets_received(&self) -> u64 {
+        self.relay.packets.load(Ordering::Relaxed)
+    }
+
+    /// Number of lines dropped because they were invalid or filtered out.
+    pub fn lines_dropped(&self) -> u64 {
+        self.relay.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stop receiving metrics, and send the re-batched lines.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.relay.stop.store(true, Ordering::Relaxed);
+        self.receivers.join();
+        for upstream in &self.relay.upstreams {
+            upstream.flush();
+        }
+    }
+}
+
+impl Drop for RunningProxy {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// State shared by the threads of a proxy.
+struct Relay {
+    stop: AtomicBool,
+    packets: AtomicU64,
+    dropped: AtomicU64,
+    batched: bool,
+    upstreams: Vec<Client>,
+    ring: Option<Ring>,
+    rewrites: Vec<(String, String)>,
+    tags: String,
+    allowed: Vec<String>,
+    denied: Vec<String>,
+}
+
+impl Relay {
+    /// Rewrite a line, returning it with the length of the key it is
+    /// routed by, or `None` if it is dropped.
+    fn rewrite(&self, line: &[u8]) -> Option<(String, usize)> {
+        let line = str::from_utf8(line).ok()?;
+        let metric = match protocol::parse_line(line).ok()? {
+            Line::Metric(metric) => metric,
+            // Events and service checks are routed by their whole line.
+            _ => return Some((line.to_string(), line.len())),
+        };
+        if !self.is_allowed(metric.name) {
+            return None;
+        }
+        let (from, to) = self
+            .rewrites
+            .iter()
+            .find(|(from, _)| metric.name.starts_with(from.as_str()))
+            .map_or(("", ""), |(from, to)| (from.as_str(), to.as_str()));
+
+        let mut relayed = String::with_capacity(line.len() + to.len() + self.tags.len() + 2);
+        relayed.push_str(to);
+        relayed.push_str(&metric.name[from.len()..]);
+        let name_len = relayed.len();
+        if name_len == 0 {
+            return None;
+        }
+        let rest = &line[metric.name.len()..];
+        if self.tags.is_empty() {
+            relayed.push_str(rest);
+            return Some((relayed, name_len));
+        }
+        let mut tagged = false;
+        for (i, field) in rest.split('|').enumerate() {
+            if i > 0 {
+                relayed.push('|');
+            }
+            relayed.push_str(field);
+            if i > 0 && field.starts_with('#') && !tagged {
+                if field.len() > 1 {
+                    relayed.push(',');
+                }
+                relayed.push_str(&self.tags);
+                tagged = true;
+            }
+        }
+        if !tagged {
+            relayed.push_str("|#");
+            relayed.push_str(&self.tags);
+        }
+        Some((relayed, name_len))
+    }
+
+    fn is_allowed(&self, name: &str) -> bool {
+        let matches = |pattern: &String| glob_match(pattern.as_bytes(), name.as_bytes());
+        (self.allowed.is_empty() || self.allowed.iter().any(matches))
+            && !self.denied.iter().any(matches)
+    }
+}
+
+impl Handler for Relay {
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    fn handle_too_long(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handle(&self, packet: &[u8]) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        // Without re-batching, the lines of a packet are relayed in one
+        // packet to each upstream.
+        let mut packets = vec![
+            Vec::new();
+            if self.batched {
+                0
+            } else {
+                self.upstreams.len()
+            }
+        ];
+        for line in packet.split(|&b| b == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            let (relayed, key_len) = match self.rewrite(line) {
+                Some(relayed) => relayed,
+                None => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            let targets = match self.ring {
+                Some(ref ring) => {
+                    let target = ring.get(&relayed.as_bytes()[..key_len]);
+                    target..target + 1
+                }
+                None => 0..self.upstreams.len(),
+            };
+            for target in targets {
+                if self.batched {
+                    self.upstreams[target].send(relayed.as_bytes());
+                    continue;
+                }
+                let packet = &mut packets[target];
+                if !packet.is_empty() {
+                    if packet.len() + relayed.len() + 1 > MAX_RELAYED_SIZE {
+                        self.upstreams[target].send(packet);
+                        packet.clear();
+                    } else {
+                        packet.push(b'\n');
+                    }
+                }
+                packet.extend_from_slice(relayed.as_bytes());
+            }
+        }
+        for (upstream, packet) in self.upstreams.iter().zip(&packets) {
+            if !packet.is_empty() {
+                upstream.send(packet);
+            }
+        }
+    }
+}
+
+/// Consistent hash ring, with many points per upstream so that keys are
+/// spread evenly.
+struct Ring {
+    /// Hashes of the points, and the index of their upstream.
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    /// Create a ring placing upstreams by address, so that the ring
+    /// doesn't depend on the order of the upstreams. Recording clients,
+    /// which have no address, are placed by index.
+    fn new(upstreams: &[Client]) -> Ring {
+        let mut points = Vec::with_capacity(upstreams.len() * VIRTUAL_NODES);
+        for (index, upstream) in upstreams.iter().enumerate() {
+            let address = upstream
+                .server_address()
+                .map_or_else(|| index.to_string(), |address| address.to_string());
+            for node in 0..VIRTUAL_NODES {
+                let point = format!("{}-{}", address, node);
+                points.push((hash(point.as_bytes()), index));
+            }
+        }
+        points.sort_unstable();
+        Ring { points }
+    }
+
+    /// Index of the upstream of `key`: the upstream of the first point
+    /// after the hash of `key`, wrapping around.
+    fn get(&self, key: &[u8]) -> usize {
+        let hash = hash(key);
+        let i = self.points.partition_point(|(point, _)| *point < hash);
+        self.points[i % self.points.len()].1
+    }
+}
+
+/// FNV-1a hash, with the murmur3 finalizer to spread similar keys.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Match `name` against a pattern where `*` matches any characters.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*`, and of the name where it started matching.
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == name[n] {
+            p += 1;
+            n += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the last `*` match one more character.
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Instant;
+
+    use crate::testing::Recording;
+
+    fn wait_for_packets(running: &RunningProxy, count: u64) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while running.packets_received() < count {
+            assert!(Instant::now() < deadline, "Packets were not received");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn relay(proxy: Proxy, packets: &[&[u8]]) -> RunningProxy {
+        let mut proxy = proxy;
+        let address = proxy.bind_udp("127.0.0.1:0").unwrap();
+        let running = proxy.start().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for packet in packets {
+            socket.send_to(packet, address).unwrap();
+        }
+        wait_for_packets(&running, packets.len() as u64);
+        running
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"myapp.*", b"myapp.requests.ok"));
+        assert!(glob_match(b"*.errors", b"myapp.db.errors"));
+        assert!(glob_match(b"my*.*s", b"myapp.requests"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a**b", b"ab"));
+        assert!(!glob_match(b"myapp.*", b"myapp"));
+        assert!(!glob_match(b"*.errors", b"myapp.errors.count"));
+    }
+
+    #[test]
+    fn test_relaying_to_every_upstream() {
+        let (first, second) = (Recording::new(), Recording::new());
+        let mut proxy = Proxy::new();
+        proxy.add_upstream(first.client(""));
+        proxy.add_upstream(second.client(""));
+        let running = relay(proxy, &[b"a:1|c\nb:2|g", b"_e{1,1}:t|x\nbad"]);
+        assert_eq!(1, running.lines_dropped());
+        running.shutdown();
+
+        for recording in &[first, second] {
+            assert_eq!(vec!["a:1|c", "b:2|g", "_e{1,1}:t|x"], recording.lines());
+        }
+    }
+
+    #[test]
+    fn test_rewriting_and_filtering() {
+        let recording = Recording::new();
+        let mut proxy = Proxy::new();
+        proxy.add_upstream(recording.client(""));
+        proxy.add_prefix_rewrite("edge.", "dc1.").unwrap();
+        proxy.add_prefix_rewrite("gone", "").unwrap();
+        proxy.add_prefix_rewrite("", "other.").unwrap();
+        assert!(proxy.add_prefix_rewrite("a", "x:1|c").is_err());
+        assert!(proxy.add_prefix_rewrite("a", "x\n").is_err());
+        proxy.add_tag("dc", "1").unwrap();
+        proxy.add_tag("env", "prod").unwrap();
+        assert!(proxy.add_tag("dc|x", "1").is_err());
+        assert!(proxy.add_tag("env", "prod\nevil:1|c").is_err());
+        proxy.allow("edge.*");
+        proxy.allow("app.*");
+        proxy.deny("*.debug");
+        let running = relay(
+            proxy,
+            &[b"edge.hits:1|c|@0.5|#route:home|T1700000000\n\
+                edge.load:2|g\n\
+                app.t:3|ms|#\n\
+                edge.debug:1|c\n\
+                gone:1|c\n\
+                skipped:1|c"],
+        );
+        assert_eq!(3, running.lines_dropped());
+        running.shutdown();
+
+        assert_eq!(
+            vec![
+                "dc1.hits:1|c|@0.5|#route:home,dc:1,env:prod|T1700000000",
+                "dc1.load:2|g|#dc:1,env:prod",
+                "other.app.t:3|ms|#dc:1,env:prod",
+            ],
+            recording.lines()
+        );
+    }
+
+    #[test]
+    fn test_consistent_hashing() {
+        let recordings = [Recording::new(), Recording::new(), Recording::new()];
+        let mut proxy = Proxy::new();
+        for recording in &recordings {
+            proxy.add_upstream(recording.client(""));
+        }
+        proxy.set_routing(Routing::ConsistentHash);
+        let packet: String = (0..300)
+            .map(|i| format!("metric.{}:1|c\n", i % 100))
+            .collect();
+        let running = relay(proxy, &[packet.as_bytes()]);
+        running.shutdown();
+
+        let mut total = 0;
+        for (i, recording) in recordings.iter().enumerate() {
+            let lines = recording.lines();
+            // Each upstream gets a share of the metrics, and every line of
+            // a metric.
+            assert!(lines.len() >= 30, "{:?}", lines);
+            for line in &lines {
+                assert_eq!(3, lines.iter().filter(|other| *other == line).count());
+                for other in &recordings[i + 1..] {
+                    assert!(!other.lines().contains(line));
+                }
+            }
+            total += lines.len();
+        }
+        assert_eq!(300, total);
+    }
+
+    #[test]
+    fn test_ring_is_stable() {
+        let clients: Vec<_> = (0..3)
+            .map(|i| Client::new(("127.0.0.1", 8125 + i), "").unwrap())
+            .collect();
+        let ring = Ring::new(&clients);
+        let reversed: Vec<_> = clients.iter().rev().cloned().collect();
+        let reversed_ring = Ring::new(&reversed);
+        let fewer_ring = Ring::new(&clients[..2]);
+        let mut moved = 0;
+        for i in 0..1000 {
+            let key = format!("metric.{}", i);
+            let upstream = ring.get(key.as_bytes());
+            assert_eq!(upstream, 2 - reversed_ring.get(key.as_bytes()));
+            if upstream < 2 {
+                // Removing an upstream only moves its own metrics.
+                assert_eq!(upstream, fewer_ring.get(key.as_bytes()));
+            } else {
+                moved += 1;
+            }
+        }
+        assert!(moved > 200 && moved < 450, "{} metrics moved", moved);
+    }
+
+    #[test]
+    fn test_batching() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut proxy = Proxy::new();
+        proxy.add_upstream(Client::new(upstream.local_addr().unwrap(), "").unwrap());
+        proxy.set_batching(512, Duration::from_secs(60));
+        let running = relay(proxy, &[b"a:1|c", b"b:2|c", b"c:3|c"]);
+        running.shutdown();
+
+        let mut buf = [0; 512];
+        let len = upstream.recv(&mut buf).unwrap();
+        assert_eq!(b"a:1|c\nb:2|c\nc:3|c", &buf[..len]);
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+
+/// A DDSketch, summarizing values in bounded memory.
+///
+/// Values are counted in bins growing exponentially, so that the value of
+/// a bin is within the relative accuracy of every value in it, and
+/// quantiles are within the relative accuracy of the exact ones. When
+/// there are more bins than the maximum, the bins of the smallest
+/// magnitudes are merged, which only loses accuracy for values close to
+/// zero. The count, sum, minimum and maximum are exact.
+///
+/// ```
+/// use statsd::server::Sketch;
+///
+/// let mut sketch = Sketch::new(0.01, 2048);
+/// for value in 1..=1000 {
+///     sketch.add(value as f64);
+/// }
+/// assert!((sketch.quantile(0.99) - 990.0).abs() <= 9.9);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sketch {
+    gamma: f64,
+    ln_gamma: f64,
+    max_bins: usize,
+    /// Counts of positive values, by bin index.
+    positive: BTreeMap<i32, u64>,
+    /// Counts of negative values, by bin index of their magnitude.
+    negative: BTreeMap<i32, u64>,
+    zeros: u64,
+    count: u64,
+    sum: f64,
+    sum_squares: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Sketch {
+    /// Create a sketch with quantiles within `relative_accuracy` of the
+    /// exact ones, e.g. `0.01` for 1%, keeping at most `max_bins` bins for
+    /// positive values, and as many for negative values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `relative_accuracy` isn't between 0 and 1, exclusive.
+    pub fn new(relative_accuracy: f64, max_bins: usize) -> Sketch {
+        assert!(
+            relative_accuracy > 0.0 && relative_accuracy < 1.0,
+            "The relative accuracy must be between 0 and 1"
+        );
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+        Sketch {
+            gamma,
+            ln_gamma: gamma.ln(),
+            max_bins: max_bins.max(1),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zeros: 0,
+            count: 0,
+            sum: 0.0,
+            sum_squares: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Add a value. Infinite and NaN values are ignored.
+    pub fn add(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        self.count += 1;
+        self.sum += value;
+        self.sum_squares += value * value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        let magnitude = value.abs();
+        if magnitude < f64::MIN_POSITIVE {
+            self.zeros += 1;
+            return;
+        }
+        let index = (magnitude.ln() / self.ln_gamma).ceil() as i32;
+        let bins = if value > 0.0 {
+            &mut self.positive
+        } else {
+            &mut self.negative
+        };
+        *bins.entry(index).or_insert(0) += 1;
+        if bins.len() > self.max_bins {
+            // Merge the bin of the smallest magnitude into the next one.
+            let (_, count) = bins.pop_first().unwrap();
+            *bins.values_mut().next().unwrap() += count;
+        }
+    }
+
+    /// Number of values added.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of the values added.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    pub(crate) fn sum_squares(&self) -> f64 {
+        self.sum_squares
+    }
+
+    /// Smallest value, or infinity when empty.
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    /// Largest value, or minus infinity when empty.
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    /// Approximate value at quantile `q`, between 0 and 1, with the
+    /// nearest-rank method. NaN is returned when the sketch is empty.
+    pub fn quantile(&self, q: f64) -> f64 {
+        let rank = (q * self.count as f64).ceil() as u64;
+        self.value_at(rank.clamp(1, self.count.max(1)))
+    }
+
+    /// Approximate value with `rank`, from 1 for the smallest value. NaN
+    /// is returned when there is no such value.
+    pub(crate) fn value_at(&self, rank: u64) -> f64 {
+        let mut seen = 0;
+        for (value, count) in self.bins() {
+            seen += count;
+            if seen >= rank {
+                return value;
+            }
+        }
+        f64::NAN
+    }
+
+    /// Values of the bins, sorted, with the number of values in each.
+    pub fn bins(&self) -> impl DoubleEndedIterator<Item = (f64, u64)> + '_ {
+        let negative = self
+            .negative
+            .iter()
+            .rev()
+            .map(move |(index, count)| (self.value(-self.magnitude(*index)), *count));
+        let zeros = Some((0.0, self.zeros)).filter(|(_, count)| *count > 0);
+        let positive = self
+            .positive
+            .iter()
+            .map(move |(index, count)| (self.value(self.magnitude(*index)), *count));
+        negative.chain(zeros).chain(positive)
+    }
+
+    /// Magnitude of the values of a bin.
+    fn magnitude(&self, index: i32) -> f64 {
+        2.0 * (index as f64 * self.ln_gamma).exp() / (self.gamma + 1.0)
+    }
+
+    /// Clamp the value of a bin to the range of the values added, which is
+    /// exact.
+    fn value(&self, value: f64) -> f64 {
+        value.clamp(self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quantiles() {
+        let mut sketch = Sketch::new(0.01, 2048);
+        assert!(sketch.quantile(0.5).is_nan());
+        for value in (1..=10_000).rev() {
+            sketch.add(value as f64 / 10.0);
+        }
+        assert_eq!(10_000, sketch.count());
+        assert_eq!(0.1, sketch.min());
+        assert_eq!(1000.0, sketch.max());
+        assert!((sketch.sum() - 5_000_500.0).abs() < 1e-6);
+        for &q in &[0.01, 0.25, 0.5, 0.9, 0.999] {
+            let exact = (q * 10_000.0f64).ceil() / 10.0;
+            let approximate = sketch.quantile(q);
+            assert!(
+                (approximate - exact).abs() <= exact * 0.01,
+                "q{}: {} instead of {}",
+                q,
+                approximate,
+                exact
+            );
+        }
+        assert_eq!(1000.0, sketch.quantile(1.0));
+    }
+
+    #[test]
+    fn test_negative_values_and_zeros() {
+        let mut sketch = Sketch::new(0.02, 2048);
+        for value in &[-100.0, -1.0, 0.0, 0.0, 1.0, 100.0, f64::NAN] {
+            sketch.add(*value);
+        }
+        let bins: Vec<_> = sketch.bins().map(|(_, count)| count).collect();
+        assert_eq!(vec![1, 1, 2, 1, 1], bins);
+        assert!((sketch.value_at(1) + 100.0).abs() <= 2.0);
+        assert_eq!(0.0, sketch.value_at(3));
+        assert!((sketch.value_at(6) - 100.0).abs() <= 2.0);
+        assert!(sketch.value_at(7).is_nan());
+    }
+
+    #[test]
+    fn test_bounding_bins() {
+        let mut sketch = Sketch::new(0.01, 10);
+        for exponent in 0..100 {
+            sketch.add(2f64.powi(exponent));
+        }
+        assert_eq!(10, sketch.bins().count());
+        assert_eq!(100, sketch.bins().map(|(_, count)| count).sum::<u64>());
+        // The largest values are still accurate.
+        let largest = 2f64.powi(99);
+        assert!((sketch.quantile(1.0) - largest).abs() <= largest * 0.01);
+    }
+}
@@ -0,0 +1,296 @@
+//! Recording clients for unit tests.
+//!
+//! A `Recording` creates clients that keep every metric line in memory
+//! instead of sending it, so tests can check the metrics sent by the code
+//! under test:
+//!
+//! ```
+//! use statsd::testing::Recording;
+//!
+//! let recording = Recording::new();
+//! let client = recording.client("myapp");
+//! client.count("jobs.completed", 2.0);
+//! client.incr("jobs.completed");
+//! client.timer("jobs.duration", 12.5);
+//!
+//! recording.assert_counter("myapp.jobs.completed", 3.0);
+//! recording.assert_timer_recorded("myapp.jobs.duration");
+//! ```
+//!
+//! Lines are recorded as they would be sent, with the prefix and tags of
+//! the client. Pipelines sent with a recording client are recorded too.
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::client::Client;
+use crate::protocol::{self, Line, Value};
+
+pub use crate::protocol::MetricType;
+
+/// Lines recorded by recording clients.
+#[derive(Default)]
+pub(crate) struct Sink {
+    lines: Mutex<Vec<String>>,
+}
+
+impl Sink {
+    /// Record the lines of a packet.
+    pub fn push(&self, data: &[u8]) {
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        for line in data.split(|&byte| byte == b'\n') {
+            lines.push(String::from_utf8_lossy(line).into_owned());
+        }
+    }
+}
+
+/// A recorded metric line, parsed. Sets are not parsed, as their values
+/// are not numbers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// Name of the metric, including the prefix of the client.
+    pub name: String,
+    pub value: f64,
+    pub kind: MetricType,
+    /// Sample rate, for sampled counters.
+    pub rate: Option<f64>,
+    pub tags: Vec<(String, String)>,
+}
+
+impl Record {
+    /// Parse a metric line sent by a client.
+    fn parse(line: &str) -> Option<Record> {
+        let metric = match protocol::parse_line(line).ok()? {
+            Line::Metric(metric) => metric,
+            _ => return None,
+        };
+        let value = match metric.value() {
+            Value::Number(value) | Value::Delta(value) => value,
+            Value::Member(_) => return None,
+        };
+        Some(Record {
+            name: metric.name.to_string(),
+            value,
+            kind: metric.metric_type,
+            rate: metric.sample_rate,
+            tags: metric
+                .tags
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.unwrap_or("").to_string()))
+                .collect(),
+        })
+    }
+}
+
+/// In-memory destination of recording clients, with assertion helpers.
+///
+/// Cloning a recording is cheap, as clones share the recorded lines.
+#[derive(Clone, Default)]
+pub struct Recording {
+    sink: Arc<Sink>,
+}
+
+impl Recording {
+    pub fn new() -> Recording {
+        Recording::default()
+    }
+
+    /// Create a client with the given prefix, recording its metrics here.
+    ///
+    /// Clients created from it with `scoped()`, `with_tag()` or
+    /// `buffered()` record their metrics here too. Buffered clients
+    /// record metrics right away.
+    pub fn client(&self, prefix: &str) -> Client {
+        Client::recording(prefix, Arc::clone(&self.sink))
+    }
+
+    /// Get the recorded lines, in the order they were recorded.
+    pub fn lines(&self) -> Vec<String> {
+        self.sink
+            .lines
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Get the recorded metrics. Lines that can't be parsed are skipped.
+    pub fn records(&self) -> Vec<Record> {
+        self.lines()
+            .iter()
+            .filter_map(|line| Record::parse(line))
+            .collect()
+    }
+
+    /// Forget the recorded lines.
+    pub fn clear(&self) {
+        self.sink
+            .lines
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    fn values(&self, name: &str, kind: MetricType) -> Vec<f64> {
+        self.records()
+            .into_iter()
+            .filter(|record| record.name == name && record.kind == kind)
+            .map(|record| record.value)
+            .collect()
+    }
+
+    /// Get the sum of the values sent for a counter, whatever their tags.
+    ///
+    /// Sample rates are ignored: values are summed as they were sent.
+    pub fn counter(&self, name: &str) -> f64 {
+        self.values(name, MetricType::Counter).iter().sum()
+    }
+
+    /// Get the last value sent for a gauge, whatever its tags.
+    pub fn gauge(&self, name: &str) -> Option<f64> {
+        self.values(name, MetricType::Gauge).pop()
+    }
+
+    /// Get the values sent for a timer, whatever their tags.
+    pub fn timers(&self, name: &str) -> Vec<f64> {
+        self.values(name, MetricType::Timer)
+    }
+
+    /// Assert that the values sent for a counter add up to `expected`.
+    #[track_caller]
+    pub fn assert_counter(&self, name: &str, expected: f64) {
+        let actual = self.counter(name);
+        if actual != expected {
+            self.fail(format_args!(
+                "Expected counter `{}` to be {}, got {}",
+                name, expected, actual
+            ));
+        }
+    }
+
+    /// Assert that the last value sent for a gauge is `expected`.
+    #[track_caller]
+    pub fn assert_gauge(&self, name: &str, expected: f64) {
+        match self.gauge(name) {
+            Some(actual) if actual == expected => {}
+            Some(actual) => self.fail(format_args!(
+                "Expected gauge `{}` to be {}, got {}",
+                name, expected, actual
+            )),
+            None => self.fail(format_args!("Expected gauge `{}` to be recorded", name)),
+        }
+    }
+
+    /// Assert that at least one value was sent for a timer.
+    #[track_caller]
+    pub fn assert_timer_recorded(&self, name: &str) {
+        if self.timers(name).is_empty() {
+            self.fail(format_args!("Expected timer `{}` to be recorded", name));
+        }
+    }
+
+    /// Assert that a metric of any type was sent.
+    #[track_caller]
+    pub fn assert_recorded(&self, name: &str) {
+        if !self.records().iter().any(|record| record.name == name) {
+            self.fail(format_args!("Expected `{}` to be recorded", name));
+        }
+    }
+
+    /// Assert that no metric with the given name was sent.
+    #[track_caller]
+    pub fn assert_not_recorded(&self, name: &str) {
+        if self.records().iter().any(|record| record.name == name) {
+            self.fail(format_args!("Expected `{}` not to be recorded", name));
+        }
+    }
+
+    #[track_caller]
+    fn fail(&self, message: fmt::Arguments) -> ! {
+        panic!("{}. Recorded lines:\n{}", message, self.lines().join("\n"));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parsing_records() {
+        assert_eq!(
+            Some(Record {
+                name: "myapp.requests".to_string(),
+                value: 0.5,
+                kind: MetricType::Counter,
+                rate: Some(0.1),
+                tags: vec![
+                    ("method".to_string(), "GET".to_string()),
+                    ("flag".to_string(), "".to_string())
+                ],
+            }),
+            Record::parse("myapp.requests:0.5|c|@0.1|#method:GET,flag")
+        );
+        assert_eq!(
+            Some(MetricType::KeyValue),
+            Record::parse("key:1e20|kv").map(|record| record.kind)
+        );
+        assert_eq!(None, Record::parse("users:ab:c|s"));
+        assert_eq!(None, Record::parse("no value"));
+        assert_eq!(None, Record::parse("name:1|x"));
+        assert_eq!(None, Record::parse("name:1|c|oops"));
+    }
+
+    #[test]
+    fn test_recording_clients() {
+        let recording = Recording::new();
+        let client = recording.client("myapp");
+        client.incr("jobs");
+        client
+            .scoped("db")
+            .with_tag("shard", "2")
+            .count("queries", 3.0);
+        client
+            .buffered(512, std::time::Duration::from_secs(60))
+            .decr("jobs");
+
+        let mut pipeline = client.pipeline();
+        pipeline.gauge("load", 0.5);
+        pipeline.timer("time", 12.0);
+        pipeline.send(&client);
+
+        assert_eq!(
+            vec![
+                "myapp.jobs:1|c",
+                "myapp.db.queries:3|c|#shard:2",
+                "myapp.jobs:-1|c",
+                "myapp.load:0.5|g",
+                "myapp.time:12|ms",
+            ],
+            recording.lines()
+        );
+        recording.assert_counter("myapp.jobs", 0.0);
+        recording.assert_counter("myapp.db.queries", 3.0);
+        recording.assert_gauge("myapp.load", 0.5);
+        recording.assert_timer_recorded("myapp.time");
+        recording.assert_recorded("myapp.jobs");
+        recording.assert_not_recorded("myapp.missing");
+        assert_eq!(vec![12.0], recording.timers("myapp.time"));
+
+        recording.clear();
+        assert!(recording.lines().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected counter `myapp.jobs` to be 2, got 1")]
+    fn test_failing_counter_assertion() {
+        let recording = Recording::new();
+        recording.client("myapp").incr("jobs");
+        recording.assert_counter("myapp.jobs", 2.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected timer `myapp.time` to be recorded")]
+    fn test_failing_timer_assertion() {
+        let recording = Recording::new();
+        recording.client("myapp").gauge("time", 1.0);
+        recording.assert_timer_recorded("myapp.time");
+    }
+}
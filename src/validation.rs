@@ -0,0 +1,236 @@
+use std::borrow::Cow;
+
+use crate::client::StatsdError;
+
+/// Policy applied to prefixes, metric names and tags before sending.
+///
+/// Characters like `:`, `|`, `@`, `#` or newlines are part of the statsd
+/// line protocol, and would corrupt a packet or inject extra metrics
+/// into it when used in a name. Tag values are only checked for `|`, `,`,
+/// `#` and control characters, as servers split tags on their first `:`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NamePolicy {
+    /// Drop metrics with invalid names. Checking a name, or sending a
+    /// metric with the `try_*` methods of `Client`, returns
+    /// `StatsdError::InvalidMetric`.
+    Reject,
+    /// Replace invalid characters with `_`.
+    Sanitize,
+    /// Send names as they are.
+    #[default]
+    PassThrough,
+}
+
+impl NamePolicy {
+    /// Check a name according to the policy.
+    ///
+    /// ```
+    /// use statsd::validation::NamePolicy;
+    ///
+    /// assert_eq!(NamePolicy::Sanitize.apply("a b:c").unwrap(), "a_b_c");
+    /// assert!(NamePolicy::Reject.apply("a b:c").is_err());
+    /// assert_eq!(NamePolicy::PassThrough.apply("a b:c").unwrap(), "a b:c");
+    /// ```
+    pub fn apply<'a>(&self, name: &'a str) -> Result<Cow<'a, str>, StatsdError> {
+        self.apply_with(name, is_reserved)
+    }
+
+    /// Check a tag value according to the policy.
+    ///
+    /// ```
+    /// use statsd::validation::NamePolicy;
+    ///
+    /// assert_eq!(NamePolicy::Sanitize.apply_tag_value("/users/:id").unwrap(), "/users/:id");
+    /// assert_eq!(NamePolicy::Sanitize.apply_tag_value("a,b|c").unwrap(), "a_b_c");
+    /// ```
+    pub fn apply_tag_value<'a>(&self, value: &'a str) -> Result<Cow<'a, str>, StatsdError> {
+        self.apply_with(value, is_reserved_in_tag_value)
+    }
+
+    fn apply_with<'a>(
+        &self,
+        name: &'a str,
+        reserved: fn(char) -> bool,
+    ) -> Result<Cow<'a, str>, StatsdError> {
+        if *self == NamePolicy::PassThrough || !name.chars().any(reserved) {
+            return Ok(Cow::Borrowed(name));
+        }
+        match *self {
+            NamePolicy::Reject => Err(invalid_name(name)),
+            _ => Ok(Cow::Owned(
+                name.chars()
+                    .map(|c| if reserved(c) { '_' } else { c })
+                    .collect(),
+            )),
+        }
+    }
+
+    /// Write a name to `buf` according to the policy, without allocating.
+    pub(crate) fn write(&self, name: &str, buf: &mut Vec<u8>) -> Result<(), StatsdError> {
+        self.write_with(name, buf, is_reserved)
+    }
+
+    /// Write a tag value to `buf` according to the policy.
+    pub(crate) fn write_tag_value(
+        &self,
+        value: &str,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), StatsdError> {
+        self.write_with(value, buf, is_reserved_in_tag_value)
+    }
+
+    fn write_with(
+        &self,
+        name: &str,
+        buf: &mut Vec<u8>,
+        reserved: fn(char) -> bool,
+    ) -> Result<(), StatsdError> {
+        match *self {
+            NamePolicy::PassThrough => buf.extend_from_slice(name.as_bytes()),
+            NamePolicy::Reject if name.chars().any(reserved) => {
+                return Err(invalid_name(name));
+            }
+            NamePolicy::Reject => buf.extend_from_slice(name.as_bytes()),
+            NamePolicy::Sanitize => {
+                for part in name.split(reserved) {
+                    buf.extend_from_slice(part.as_bytes());
+                    buf.push(b'_');
+                }
+                // Splitting yields one more part than there are separators.
+                buf.pop();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid_name(name: &str) -> StatsdError {
+    StatsdError::InvalidMetric(format!("Invalid characters in {:?}", name))
+}
+
+/// Policy applied to `NaN` and infinite values before sending.
+///
+/// The line protocol has no representation for these values, and most
+/// servers reject or misparse them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonFinitePolicy {
+    /// Drop metrics with non-finite values. Checking a value returns
+    /// `StatsdError::InvalidValue`.
+    #[default]
+    Drop,
+    /// Replace infinities with the largest finite value of the same sign.
+    /// `NaN` is dropped.
+    Clamp,
+    /// Send values as they are.
+    PassThrough,
+}
+
+impl NonFinitePolicy {
+    /// Check a value according to the policy.
+    ///
+    /// ```
+    /// use statsd::validation::NonFinitePolicy;
+    ///
+    /// assert!(NonFinitePolicy::Drop.apply(f64::INFINITY).is_err());
+    /// assert_eq!(NonFinitePolicy::Clamp.apply(f64::INFINITY).unwrap(), f64::MAX);
+    /// assert!(NonFinitePolicy::Clamp.apply(f64::NAN).is_err());
+    /// ```
+    pub fn apply(&self, value: f64) -> Result<f64, StatsdError> {
+        if value.is_finite() || *self == NonFinitePolicy::PassThrough {
+            return Ok(value);
+        }
+        match *self {
+            NonFinitePolicy::Clamp if value.is_infinite() => Ok(value.signum() * f64::MAX),
+            _ => Err(StatsdError::InvalidValue(format!(
+                "Non-finite value {}",
+                value
+            ))),
+        }
+    }
+}
+
+/// Characters which have a meaning in the line protocol.
+fn is_reserved(c: char) -> bool {
+    matches!(c, ':' | '|' | '@' | '#' | ',') || c.is_whitespace() || c.is_control()
+}
+
+/// Characters which end a tag value in the line protocol.
+fn is_reserved_in_tag_value(c: char) -> bool {
+    matches!(c, '|' | ',' | '#') || c.is_control()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_valid_names_are_borrowed() {
+        for policy in &[
+            NamePolicy::Reject,
+            NamePolicy::Sanitize,
+            NamePolicy::PassThrough,
+        ] {
+            let name = policy.apply("my-app.some_metric").unwrap();
+            assert!(matches!(name, Cow::Borrowed("my-app.some_metric")));
+        }
+    }
+
+    #[test]
+    fn test_sanitize_replaces_reserved_characters() {
+        let name = NamePolicy::Sanitize.apply("a:b|c@d#e,f g\nh\ti").unwrap();
+        assert_eq!(name, "a_b_c_d_e_f_g_h_i");
+    }
+
+    #[test]
+    fn test_tag_values_may_contain_colons() {
+        let value = NamePolicy::Reject.apply_tag_value("/users/:id").unwrap();
+        assert!(matches!(value, Cow::Borrowed("/users/:id")));
+        let value = NamePolicy::Sanitize
+            .apply_tag_value("a b:c|d,e#f\ng")
+            .unwrap();
+        assert_eq!(value, "a b:c_d_e_f_g");
+        assert!(NamePolicy::Reject.apply_tag_value("a,b").is_err());
+    }
+
+    #[test]
+    fn test_non_finite_values() {
+        assert_eq!(NonFinitePolicy::Drop.apply(1.5).unwrap(), 1.5);
+        assert!(NonFinitePolicy::Drop.apply(f64::NAN).is_err());
+        assert!(NonFinitePolicy::Drop.apply(f64::NEG_INFINITY).is_err());
+        assert_eq!(
+            NonFinitePolicy::Clamp.apply(f64::NEG_INFINITY).unwrap(),
+            f64::MIN
+        );
+        assert!(NonFinitePolicy::PassThrough
+            .apply(f64::NAN)
+            .unwrap()
+            .is_nan());
+    }
+
+    #[test]
+    fn test_write_matches_apply() {
+        for policy in &[
+            NamePolicy::Reject,
+            NamePolicy::Sanitize,
+            NamePolicy::PassThrough,
+        ] {
+            for name in &["", "valid.name", ":", "a b:c", "é|ü\r\n"] {
+                let mut buf = Vec::new();
+                let written = policy.write(name, &mut buf).map(|_| buf);
+                let applied = policy.apply(name).map(|n| n.as_bytes().to_vec());
+                assert_eq!(written.ok(), applied.ok(), "{:?} {:?}", policy, name);
+
+                let mut buf = Vec::new();
+                let written = policy.write_tag_value(name, &mut buf).map(|_| buf);
+                let applied = policy.apply_tag_value(name).map(|n| n.as_bytes().to_vec());
+                assert_eq!(written.ok(), applied.ok(), "{:?} {:?}", policy, name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reject_returns_invalid_metric() {
+        let err = NamePolicy::Reject.apply("metric:1|c\nother").unwrap_err();
+        assert!(matches!(err, StatsdError::InvalidMetric(_)));
+    }
+}
@@ -0,0 +1,149 @@
+//! Checks that formatting and sending metrics doesn't allocate.
+//!
+//! This lives in its own test binary as it installs a counting global
+//! allocator.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::net::UdpSocket;
+
+use statsd::Client;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations<F: FnMut()>(mut func: F) -> usize {
+    // Warm up reusable buffers first.
+    func();
+    let before = ALLOCATIONS.with(Cell::get);
+    func();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn test_client_does_not_allocate() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+    let tagged = client.scoped("db").with_tag("shard", "3");
+    let count = allocations(|| {
+        client.count("some.counter", 12.5);
+        client.sampled_count("some.counter", 1.0, 1.0);
+        client.gauge_u64("some.gauge", 42);
+        tagged.timer("some.timer", 1e300);
+        tagged.incr("bad name");
+    });
+    assert_eq!(0, count);
+}
+
+#[test]
+fn test_pipeline_does_not_allocate() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+    let mut pipeline = client.pipeline();
+    pipeline.set_max_udp_size(64);
+    let count = allocations(|| {
+        for i in 0..100 {
+            pipeline.gauge("some.gauge", f64::from(i));
+        }
+        pipeline.send(&client);
+    });
+    assert_eq!(0, count);
+}
+
+#[test]
+fn test_batched_pipeline_does_not_allocate() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+    let mut pipeline = client.pipeline();
+    pipeline.set_max_udp_size(64);
+    pipeline.set_batch_send(true);
+    let count = allocations(|| {
+        for i in 0..100 {
+            pipeline.gauge("some.gauge", f64::from(i));
+        }
+        pipeline.send(&client);
+    });
+    assert_eq!(0, count);
+}
+
+#[test]
+fn test_tagged_macros_do_not_allocate() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+    statsd::set_global_client(client.with_tag("host", "a")).unwrap();
+    let count = allocations(|| {
+        statsd::statsd_incr!("some.counter", "region" => "eu");
+        statsd::statsd_gauge!("some.gauge", 1.5, "region" => "eu", "zone" => "b");
+        statsd::statsd_time!("some.timer", {}, "region" => "eu");
+    });
+    assert_eq!(0, count);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing_events_do_not_allocate() {
+    use tracing_subscriber::prelude::*;
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+    let layer = statsd::layer::StatsdLayer::new(&client);
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let count = allocations(|| {
+            tracing::info!(target: "app", "started");
+            tracing::error!(target: "app", "failed");
+        });
+        assert_eq!(0, count);
+    });
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn test_log_records_do_not_allocate() {
+    use log::{Level, Log, Metadata, Record};
+
+    struct Discard;
+
+    impl Log for Discard {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, _: &Record) {}
+
+        fn flush(&self) {}
+    }
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = Client::new(server.local_addr().unwrap(), "myapp").unwrap();
+    let mut logger = statsd::logger::StatsdLogger::new(Discard, &client);
+    logger.set_tag_target(true);
+    let count = allocations(|| {
+        for level in &[Level::Error, Level::Trace] {
+            logger.log(
+                &Record::builder()
+                    .level(*level)
+                    .target("app")
+                    .args(format_args!("message"))
+                    .build(),
+            );
+        }
+    });
+    assert_eq!(0, count);
+}